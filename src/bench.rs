@@ -0,0 +1,189 @@
+use core::time::Duration;
+use std::{collections::HashSet, path::PathBuf, time::Instant};
+
+use clap::ValueHint;
+use error_stack::{Result, ResultExt};
+use serde::{Serialize, Serializer};
+use tabled::{builder::Builder, settings::Style};
+use thiserror::Error;
+
+use crate::{
+    schema::{Context, ParseOptions, Scope},
+    validate::{self, Output},
+};
+
+// only counts if it is the global allocator, which the binary installs with `alloc-stats`
+#[cfg(feature = "alloc-stats")]
+static ALLOCATOR: &stats_alloc::StatsAlloc<std::alloc::System> = &stats_alloc::INSTRUMENTED_SYSTEM;
+
+#[derive(Debug, Error)]
+pub(crate) enum Error {
+    #[error("unable to read the identity schema")]
+    Schema,
+    #[error("unable to read the traits")]
+    Traits,
+    #[error("unable to write output")]
+    Io,
+}
+
+#[derive(Debug, Clone, clap::Args)]
+pub(crate) struct Options {
+    /// Identity schema to benchmark, `-` reads from stdin
+    #[clap(long, value_hint = ValueHint::FilePath)]
+    pub(crate) file: PathBuf,
+
+    /// Identity traits (JSON) the claims are resolved for
+    #[clap(long, value_hint = ValueHint::FilePath)]
+    pub(crate) traits: PathBuf,
+
+    /// Number of times the schema is parsed and the claims are resolved
+    #[clap(long, default_value_t = 10_000)]
+    pub(crate) iterations: u32,
+
+    /// Requested scopes, defaults to all configured scopes
+    #[clap(long, value_delimiter = ',')]
+    pub(crate) scopes: Vec<String>,
+
+    /// Output format
+    #[clap(long, value_enum, default_value = "table")]
+    pub(crate) output: Output,
+}
+
+/// Latency and allocations of one measured operation, durations are serialized in nanoseconds.
+/// Allocations are only counted by binaries built with the `alloc-stats` feature.
+#[derive(Serialize)]
+struct Measurement {
+    operation: &'static str,
+    #[serde(serialize_with = "nanoseconds")]
+    mean: Duration,
+    #[serde(serialize_with = "nanoseconds")]
+    p50: Duration,
+    #[serde(serialize_with = "nanoseconds")]
+    p99: Duration,
+    #[serde(serialize_with = "nanoseconds")]
+    max: Duration,
+    allocations: Option<usize>,
+    bytes: Option<usize>,
+}
+
+fn nanoseconds<S: Serializer>(
+    duration: &Duration,
+    serializer: S,
+) -> core::result::Result<S::Ok, S::Error> {
+    serializer.serialize_u128(duration.as_nanos())
+}
+
+/// Run the operation `iterations` times, recording the latency of every run and the allocations
+/// per run.
+fn measure<T>(operation: &'static str, iterations: u32, mut run: impl FnMut() -> T) -> Measurement {
+    let mut latencies = Vec::with_capacity(iterations as usize);
+
+    #[cfg(feature = "alloc-stats")]
+    let region = stats_alloc::Region::new(ALLOCATOR);
+
+    for _ in 0..iterations {
+        let start = Instant::now();
+        let output = run();
+        latencies.push(start.elapsed());
+
+        // dropping the output is part of the cost of every request
+        drop(output);
+    }
+
+    latencies.sort_unstable();
+
+    let percentile = |percentile: usize| {
+        let index = (latencies.len() * percentile / 100).min(latencies.len().saturating_sub(1));
+
+        latencies.get(index).copied().unwrap_or_default()
+    };
+
+    let total: Duration = latencies.iter().sum();
+
+    // the latencies are preallocated, so every allocation is caused by `run`
+    #[cfg(feature = "alloc-stats")]
+    let (allocations, bytes) = {
+        let stats = region.change();
+        let runs = latencies.len().max(1);
+
+        (
+            Some((stats.allocations + stats.reallocations) / runs),
+            Some(stats.bytes_allocated / runs),
+        )
+    };
+    #[cfg(not(feature = "alloc-stats"))]
+    let (allocations, bytes) = (None, None);
+
+    Measurement {
+        operation,
+        mean: total / iterations.max(1),
+        p50: percentile(50),
+        p99: percentile(99),
+        max: latencies.last().copied().unwrap_or_default(),
+        allocations,
+        bytes,
+    }
+}
+
+fn count(value: Option<usize>) -> String {
+    value.map_or_else(|| "-".to_owned(), |value| value.to_string())
+}
+
+/// Measure how long parsing the schema and resolving the claims takes, the two operations on the
+/// path of a consent request.
+pub(crate) fn run(options: &Options, parse: &ParseOptions) -> Result<(), Error> {
+    let schema = validate::read_traits(&options.file).change_context(Error::Schema)?;
+    let traits = validate::read_json(&options.traits, validate::Error::TraitsMalformed)
+        .change_context(Error::Traits)?;
+    let context = Context::from_traits(traits);
+
+    let parsing = measure("parse", options.iterations, || {
+        validate::parse(parse, None, &schema, &mut vec![])
+    });
+
+    let (cache, config) = validate::parse(parse, None, &schema, &mut vec![]);
+
+    let requested: HashSet<_> = if options.scopes.is_empty() {
+        config.scopes.keys().cloned().collect()
+    } else {
+        options.scopes.iter().cloned().map(Scope::new).collect()
+    };
+
+    let resolution = measure("resolve", options.iterations, || {
+        config.resolve_all(&context, &cache, &requested)
+    });
+
+    let measurements = [parsing, resolution];
+
+    let output = match options.output {
+        Output::Table => {
+            let mut builder = Builder::default();
+            builder.set_header([
+                "operation",
+                "mean",
+                "p50",
+                "p99",
+                "max",
+                "allocations",
+                "bytes",
+            ]);
+
+            for measurement in &measurements {
+                builder.push_record([
+                    measurement.operation.to_owned(),
+                    humantime::format_duration(measurement.mean).to_string(),
+                    humantime::format_duration(measurement.p50).to_string(),
+                    humantime::format_duration(measurement.p99).to_string(),
+                    humantime::format_duration(measurement.max).to_string(),
+                    count(measurement.allocations),
+                    count(measurement.bytes),
+                ]);
+            }
+
+            builder.build().with(Style::rounded()).to_string()
+        }
+        output => validate::render(&measurements, output).change_context(Error::Io)?,
+    };
+
+    validate::write(&output).change_context(Error::Io)
+}
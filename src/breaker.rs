@@ -0,0 +1,226 @@
+use core::time::Duration;
+use std::{sync::Mutex, time::Instant};
+
+use serde::Serialize;
+use thiserror::Error;
+
+#[derive(Debug, Copy, Clone, Error)]
+#[error("circuit breaker of {0} is open")]
+pub(crate) struct CircuitOpen(pub(crate) &'static str);
+
+#[derive(Debug, Clone, clap::Args)]
+pub(crate) struct BreakerConfig {
+    /// Number of consecutive failed requests after which an upstream is considered down and
+    /// requests fail fast, `0` disables the circuit breaker
+    #[clap(
+        long = "upstream-breaker-threshold",
+        env = "UPSTREAM_BREAKER_THRESHOLD",
+        default_value_t = 5
+    )]
+    pub(crate) threshold: u32,
+
+    /// How long requests fail fast before a single probe request is let through again
+    #[clap(
+        long = "upstream-breaker-cooldown",
+        env = "UPSTREAM_BREAKER_COOLDOWN",
+        default_value = "30s",
+        value_parser = humantime::parse_duration
+    )]
+    pub(crate) cooldown: Duration,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) enum BreakerState {
+    Closed,
+    HalfOpen,
+    Open,
+}
+
+impl BreakerState {
+    const fn gauge(self) -> f64 {
+        match self {
+            Self::Closed => 0.0,
+            Self::HalfOpen => 1.0,
+            Self::Open => 2.0,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+enum Inner {
+    Closed { failures: u32 },
+    Open { until: Instant },
+    // a probe is in flight, every other request is rejected until it completes
+    HalfOpen,
+}
+
+/// Per-upstream circuit breaker.
+///
+/// After `threshold` consecutive failures the breaker opens and every request is rejected until
+/// the cooldown has passed, then a single probe request decides whether the breaker closes again.
+#[derive(Debug)]
+pub(crate) struct CircuitBreaker {
+    name: &'static str,
+    /// Tenant the upstream belongs to, empty for the top-level options
+    tenant: String,
+    config: BreakerConfig,
+    inner: Mutex<Inner>,
+}
+
+impl CircuitBreaker {
+    pub(crate) fn new(name: &'static str, tenant: String, config: BreakerConfig) -> Self {
+        let this = Self {
+            name,
+            tenant,
+            config,
+            inner: Mutex::new(Inner::Closed { failures: 0 }),
+        };
+
+        this.record(BreakerState::Closed);
+        this
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        // the lock is never held across a panic, a poisoned lock still contains a valid state
+        self.inner
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+
+    fn record(&self, state: BreakerState) {
+        metrics::gauge!(
+            "upstream_circuit_state",
+            state.gauge(),
+            "upstream" => self.name,
+            "tenant" => self.tenant.clone()
+        );
+    }
+
+    pub(crate) fn state(&self) -> BreakerState {
+        let inner = *self.lock();
+
+        match inner {
+            Inner::Closed { .. } => BreakerState::Closed,
+            Inner::Open { until } if until <= Instant::now() => BreakerState::HalfOpen,
+            Inner::Open { .. } => BreakerState::Open,
+            Inner::HalfOpen => BreakerState::HalfOpen,
+        }
+    }
+
+    /// Permit to send a request, whose outcome is reported on the permit.
+    pub(crate) fn acquire(&self) -> Result<Permit<'_>, CircuitOpen> {
+        let permit = Permit {
+            breaker: self,
+            probe: false,
+        };
+
+        if self.config.threshold == 0 {
+            return Ok(permit);
+        }
+
+        let mut inner = self.lock();
+        let state = *inner;
+
+        match state {
+            Inner::Closed { .. } => Ok(permit),
+            Inner::Open { until } if until <= Instant::now() => {
+                tracing::info!(upstream = self.name, "circuit breaker half-open, probing");
+
+                *inner = Inner::HalfOpen;
+                self.record(BreakerState::HalfOpen);
+
+                Ok(Permit {
+                    probe: true,
+                    ..permit
+                })
+            }
+            Inner::Open { .. } | Inner::HalfOpen => {
+                metrics::increment_counter!(
+                    "upstream_circuit_rejected_total",
+                    "upstream" => self.name,
+                    "tenant" => self.tenant.clone()
+                );
+
+                Err(CircuitOpen(self.name))
+            }
+        }
+    }
+
+    fn success(&self) {
+        let mut inner = self.lock();
+
+        if !matches!(*inner, Inner::Closed { .. }) {
+            tracing::info!(upstream = self.name, "circuit breaker closed");
+
+            self.record(BreakerState::Closed);
+        }
+
+        *inner = Inner::Closed { failures: 0 };
+    }
+
+    fn failure(&self) {
+        if self.config.threshold == 0 {
+            return;
+        }
+
+        let mut inner = self.lock();
+
+        let current = *inner;
+        let failures = match current {
+            Inner::Closed { failures } => failures + 1,
+            // a failed probe immediately opens the breaker again
+            Inner::HalfOpen | Inner::Open { .. } => self.config.threshold,
+        };
+
+        if failures < self.config.threshold {
+            *inner = Inner::Closed { failures };
+            return;
+        }
+
+        tracing::warn!(upstream = self.name, "circuit breaker opened");
+
+        *inner = Inner::Open {
+            until: Instant::now() + self.config.cooldown,
+        };
+        self.record(BreakerState::Open);
+    }
+}
+
+/// Permit of [`CircuitBreaker::acquire`], a probe that is dropped without an outcome (e.g. because
+/// the request was cancelled) lets the next request probe instead.
+#[derive(Debug)]
+pub(crate) struct Permit<'a> {
+    breaker: &'a CircuitBreaker,
+    probe: bool,
+}
+
+impl Permit<'_> {
+    pub(crate) fn success(mut self) {
+        self.probe = false;
+        self.breaker.success();
+    }
+
+    pub(crate) fn failure(mut self) {
+        self.probe = false;
+        self.breaker.failure();
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        if !self.probe {
+            return;
+        }
+
+        let mut inner = self.breaker.lock();
+
+        if matches!(*inner, Inner::HalfOpen) {
+            tracing::debug!(upstream = self.breaker.name, "probe abandoned");
+
+            *inner = Inner::Open {
+                until: Instant::now(),
+            };
+        }
+    }
+}
@@ -1,14 +1,17 @@
-use std::{collections::HashSet, sync::Arc};
+use alloc::sync::Arc;
+use std::collections::HashSet;
 
 use error_stack::Result;
 use indexmap::IndexMap;
-use ory_kratos_client::apis::configuration::Configuration;
 use serde_json::Value;
 use tokio::sync::RwLock;
 
+pub use crate::validate::Error;
 use crate::{
-    schema::{Claims, Scope, ScopeConfig},
-    validate::{fetch, Error},
+    overlay::Overlays,
+    schema::{Claims, Context, ParseOptions, Pattern, Problem, Scope, ScopeConfig},
+    upstream::Kratos,
+    validate::{self, fetch},
 };
 
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
@@ -24,30 +27,120 @@ impl SchemaId {
     }
 }
 
+/// Title and description of an annotated trait in the identity schema.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct TraitMetadata {
+    pub(crate) title: Option<String>,
+    pub(crate) description: Option<String>,
+}
+
+/// Traits annotated with a scope, or tagged with the standard claim they fill.
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub(crate) struct ImplicitScopeCache(IndexMap<Scope, Vec<jsonptr::Pointer>>);
+pub(crate) struct ImplicitScopeCache {
+    scopes: IndexMap<Scope, Vec<jsonptr::Pointer>>,
+    claims: IndexMap<String, Vec<jsonptr::Pointer>>,
+    /// Traits that are personally identifiable information
+    pii: Vec<jsonptr::Pointer>,
+    metadata: Vec<(jsonptr::Pointer, TraitMetadata)>,
+    /// Patterns of `patternProperties`, matched by the wildcards of pointers at runtime
+    patterns: Vec<Pattern>,
+}
 
 impl ImplicitScopeCache {
     pub(crate) fn new() -> Self {
-        Self(IndexMap::new())
+        Self {
+            scopes: IndexMap::new(),
+            claims: IndexMap::new(),
+            pii: Vec::new(),
+            metadata: Vec::new(),
+            patterns: Vec::new(),
+        }
     }
 
     pub(crate) fn get(&self, scope: &Scope) -> Option<&Vec<jsonptr::Pointer>> {
-        self.0.get(scope)
+        self.scopes.get(scope)
     }
 
+    // a trait annotated in several branches of `allOf` is only listed once
     pub(crate) fn merge(&mut self, other: Self) {
-        for (scope, pointers) in other.0 {
-            self.0.entry(scope).or_default().extend(pointers);
+        for (scope, pointers) in other.scopes {
+            let existing = self.scopes.entry(scope).or_default();
+
+            for pointer in pointers {
+                if !existing.contains(&pointer) {
+                    existing.push(pointer);
+                }
+            }
+        }
+
+        for (claim, pointers) in other.claims {
+            let existing = self.claims.entry(claim).or_default();
+
+            for pointer in pointers {
+                if !existing.contains(&pointer) {
+                    existing.push(pointer);
+                }
+            }
+        }
+
+        self.pii.extend(other.pii);
+        self.metadata.extend(other.metadata);
+
+        for pattern in other.patterns {
+            self.insert_pattern(pattern);
         }
     }
 
     pub(crate) fn insert(&mut self, scope: Scope, pointer: jsonptr::Pointer) {
-        self.0.entry(scope).or_default().push(pointer);
+        self.scopes.entry(scope).or_default().push(pointer);
     }
 
     pub(crate) fn keys(&self) -> impl Iterator<Item = &Scope> {
-        self.0.keys()
+        self.scopes.keys()
+    }
+
+    pub(crate) fn insert_claim(&mut self, claim: String, pointer: jsonptr::Pointer) {
+        self.claims.entry(claim).or_default().push(pointer);
+    }
+
+    pub(crate) fn claim(&self, claim: &str) -> Option<&Vec<jsonptr::Pointer>> {
+        self.claims.get(claim)
+    }
+
+    pub(crate) fn insert_pii(&mut self, pointer: jsonptr::Pointer) {
+        self.pii.push(pointer);
+    }
+
+    pub(crate) fn is_pii(&self, pointer: &jsonptr::Pointer) -> bool {
+        self.pii.contains(pointer)
+    }
+
+    pub(crate) fn insert_metadata(&mut self, pointer: jsonptr::Pointer, metadata: TraitMetadata) {
+        if metadata.title.is_none() && metadata.description.is_none() {
+            return;
+        }
+
+        self.metadata.push((pointer, metadata));
+    }
+
+    pub(crate) fn metadata(&self, pointer: &jsonptr::Pointer) -> Option<&TraitMetadata> {
+        self.metadata
+            .iter()
+            .find_map(|(candidate, metadata)| (candidate == pointer).then_some(metadata))
+    }
+
+    pub(crate) fn claims(&self) -> impl Iterator<Item = (&String, &Vec<jsonptr::Pointer>)> {
+        self.claims.iter()
+    }
+
+    pub(crate) fn insert_pattern(&mut self, pattern: Pattern) {
+        if !self.patterns.contains(&pattern) {
+            self.patterns.push(pattern);
+        }
+    }
+
+    pub(crate) fn patterns(&self) -> &[Pattern] {
+        &self.patterns
     }
 }
 
@@ -62,32 +155,54 @@ impl ScopeCache {
     }
 }
 
+/// Scope configuration of an identity schema, ready to resolve claims.
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub(crate) struct Schema {
+pub struct Schema {
     cache: ScopeCache,
 
     config: ScopeConfig,
 }
 
 impl Schema {
-    pub(crate) fn resolve(&self, traits: &Value, requested: &HashSet<Scope>) -> Claims {
-        self.config.resolve_all(traits, &self.cache, requested)
+    /// Parse the scope configuration of an identity schema, e.g. `{"properties": {"traits":
+    /// {..}}}`. Problems are returned alongside what is usable, as `serve` does; `id` selects the
+    /// overlay of the parse options.
+    pub fn parse(
+        options: &ParseOptions,
+        id: Option<&str>,
+        identity_schema: &Value,
+    ) -> Result<(Self, Vec<Problem>), Error> {
+        let traits = validate::traits_of(identity_schema)?;
+
+        let mut problems = vec![];
+        let (cache, config) = validate::parse(options, id, &traits, &mut problems);
+
+        Ok((Self { cache, config }, problems))
+    }
+
+    /// Claims of the requested scopes for the identity, scopes that are not configured are
+    /// ignored.
+    #[must_use]
+    pub fn resolve(&self, context: &Context, requested: &HashSet<Scope>) -> Claims {
+        self.config.resolve_all(context, &self.cache, requested)
+    }
+
+    pub fn scopes(&self) -> impl Iterator<Item = &Scope> {
+        self.config.scopes.keys()
     }
 }
 
 #[derive(Debug)]
 pub(crate) struct SchemaCache {
-    direct_mapping: bool,
-    keyword: String,
+    options: RwLock<ParseOptions>,
     data: RwLock<IndexMap<SchemaId, Arc<Schema>>>,
 }
 
 impl SchemaCache {
-    pub(crate) fn new(keyword: String, direct_mapping: bool) -> Self {
+    pub(crate) fn new(options: ParseOptions) -> Self {
         Self {
-            keyword,
+            options: RwLock::new(options),
             data: RwLock::new(IndexMap::new()),
-            direct_mapping,
         }
     }
 
@@ -97,12 +212,6 @@ impl SchemaCache {
         lock.insert(id, Arc::new(schema));
     }
 
-    async fn contains_key(&self, id: &SchemaId) -> bool {
-        let lock = self.data.read().await;
-
-        lock.contains_key(id)
-    }
-
     async fn get(&self, id: &SchemaId) -> Option<Arc<Schema>> {
         let lock = self.data.read().await;
 
@@ -115,20 +224,47 @@ impl SchemaCache {
         Arc::clone(&lock[id])
     }
 
-    pub(crate) async fn fetch(
-        &self,
-        config: &Configuration,
-        id: &SchemaId,
-    ) -> Result<Arc<Schema>, Error> {
-        if self.contains_key(id).await {
-            return Ok(self.get_or_panic(id).await);
+    pub(crate) async fn fetch(&self, kratos: &Kratos, id: &SchemaId) -> Result<Arc<Schema>, Error> {
+        if let Some(schema) = self.get(id).await {
+            return Ok(schema);
         }
 
-        let (cache, config) =
-            fetch(config, &self.keyword, id.as_str(), self.direct_mapping).await?;
+        // held until the schema is cached, so that it is not parsed with outdated overlays
+        let options = self.options.read().await;
+
+        // problems are already logged while parsing, serving continues with what is usable
+        let (cache, config) = fetch(kratos, &options, id.as_str(), &mut vec![]).await?;
 
         self.insert(id.clone(), Schema { cache, config }).await;
 
         Ok(self.get_or_panic(id).await)
     }
+
+    /// Replace the scope configuration overlaying the identity schemas, the ones whose overlay
+    /// changed are parsed again on their next use.
+    pub(crate) async fn overlay(&self, overlays: Overlays) {
+        let mut options = self.options.write().await;
+
+        if options.overlays == overlays {
+            return;
+        }
+
+        // both locks are held, no schema is parsed or served with a mix of both versions
+        let mut data = self.data.write().await;
+        data.retain(|id, _| {
+            let id = Some(id.as_str());
+            let unchanged = options.overlays.get(id) == overlays.get(id);
+
+            if !unchanged {
+                tracing::info!(
+                    schema = id,
+                    "scope configuration changed, identity schema is parsed again"
+                );
+            }
+
+            unchanged
+        });
+
+        options.overlays = overlays;
+    }
 }
@@ -0,0 +1,533 @@
+use std::{ffi::OsString, io::Write, path::PathBuf, process::ExitCode};
+
+use clap::{
+    error::ErrorKind, Args as _, CommandFactory, FromArgMatches, Parser, Subcommand, ValueHint,
+};
+use clap_complete::Shell;
+use console::Term;
+use error_stack::{IntoReport, Report, Result, ResultExt};
+use thiserror::Error;
+use url::Url;
+
+use crate::{
+    bench, config,
+    config::ConfigFile,
+    diff, doctor, explain, hash,
+    listen::Address,
+    overlay::{Location, Overlays},
+    plugin, report, resolve, revoke,
+    schema::{ParseOptions, Scope},
+    script,
+    secret::Secret,
+    serve::{self, Config},
+    store,
+    upstream::{Credentials, UpstreamConfig},
+    validate,
+};
+
+#[derive(Debug, Error)]
+#[error("application error")]
+pub struct Error;
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// YAML or TOML file with default values for any of the options, keyed by their long name
+    #[clap(long, env, value_hint = ValueHint::FilePath)]
+    config: Option<PathBuf>,
+
+    #[command(flatten)]
+    global: GlobalArgs,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+// shared by every command and `Config::try_parse_from`
+#[derive(clap::Args, Debug)]
+struct GlobalArgs {
+    /// URL of the Kratos admin API, required by every command but `validate --file`
+    #[clap(long, env, value_hint = ValueHint::Url)]
+    kratos_admin_url: Option<Url>,
+
+    /// API key sent as bearer token on every call to the Kratos admin API
+    #[clap(long, env, hide_env_values = true)]
+    kratos_api_key: Option<Secret>,
+
+    /// File containing the Kratos API key
+    #[clap(
+        long,
+        env,
+        conflicts_with = "kratos_api_key",
+        value_hint = ValueHint::FilePath
+    )]
+    kratos_api_key_file: Option<PathBuf>,
+
+    /// URL of the Kratos public API, used to verify the session of the user (`serve
+    /// --verify-session`) and to start the settings flow if required scopes are missing
+    #[clap(long, env, value_hint = ValueHint::Url)]
+    kratos_public_url: Option<Url>,
+
+    /// URL of the Hydra admin API, required by every command but `validate --file`
+    #[clap(long, env, value_hint = ValueHint::Url)]
+    hydra_admin_url: Option<Url>,
+
+    /// API key sent as bearer token on every call to the Hydra admin API
+    #[clap(long, env, hide_env_values = true, conflicts_with = "hydra_username")]
+    hydra_api_key: Option<Secret>,
+
+    /// File containing the Hydra API key
+    #[clap(
+        long,
+        env,
+        conflicts_with_all = ["hydra_api_key", "hydra_username"],
+        value_hint = ValueHint::FilePath
+    )]
+    hydra_api_key_file: Option<PathBuf>,
+
+    /// Username used for basic authentication toward the Hydra admin API
+    #[clap(long, env)]
+    hydra_username: Option<String>,
+
+    /// Password used for basic authentication toward the Hydra admin API
+    #[clap(long, env, hide_env_values = true, requires = "hydra_username")]
+    hydra_password: Option<Secret>,
+
+    /// File containing the password used for basic authentication toward the Hydra admin API
+    #[clap(
+        long,
+        env,
+        requires = "hydra_username",
+        conflicts_with = "hydra_password",
+        value_hint = ValueHint::FilePath
+    )]
+    hydra_password_file: Option<PathBuf>,
+
+    #[clap(long, env)]
+    direct_mapping: bool,
+
+    /// Issue the standard claims of the `profile`, `email`, `address` and `phone` scopes from the
+    /// traits tagged with them (e.g. `{"claim": "given_name"}`)
+    #[clap(long, env)]
+    oidc_presets: bool,
+
+    /// Key the claims of the access token are nested under, e.g. `claims` places them in
+    /// `ext.claims` of the access token. Scopes opt out with `access_token_nested: false`
+    #[clap(long, env)]
+    access_token_key: Option<String>,
+
+    /// Scopes that are only granted and never issue claims, they are not resolved
+    #[clap(
+        long = "passthrough-scope",
+        env = "PASSTHROUGH_SCOPES",
+        value_delimiter = ',',
+        default_values = ["openid", "offline_access", "offline"]
+    )]
+    passthrough_scopes: Vec<String>,
+
+    /// Keep claims tagged as personally identifiable information out of the access token, which
+    /// resource servers can introspect. Scopes override it with `pii_in_access_token`
+    #[clap(long, env)]
+    exclude_pii: bool,
+
+    /// Keyword of the scope configuration and the annotations of the traits, repeated to accept
+    /// several (e.g. while renaming it), the first one present takes precedence
+    #[clap(
+        long = "keyword",
+        env = "KEYWORD",
+        value_delimiter = ',',
+        default_value = "indietyp/consent"
+    )]
+    keywords: Vec<String>,
+
+    /// File or URL of a JSON document with a scope configuration per identity schema ID (`*` for
+    /// any other), which takes precedence over the one embedded in the identity schema
+    #[clap(long, env, value_hint = ValueHint::AnyPath)]
+    scope_config: Option<Location>,
+
+    /// How deeply the schemas of the traits may be nested (properties, items, branches and
+    /// references) before the search for annotated traits gives up with an error
+    #[clap(long, env, default_value_t = 64)]
+    max_schema_depth: usize,
+
+    /// WebAssembly modules whose exported transforms can be used in explicit mappings
+    #[clap(
+        long = "plugin",
+        env = "PLUGINS",
+        value_delimiter = ',',
+        value_hint = ValueHint::FilePath
+    )]
+    plugins: Vec<PathBuf>,
+
+    /// Number of operations a script of an explicit mapping may execute before it is aborted
+    #[clap(long, env, default_value_t = script::MAX_OPERATIONS)]
+    script_max_operations: u64,
+
+    /// Key of values hashed with `hmac` in explicit mappings
+    #[clap(long, env, hide_env_values = true)]
+    hash_key: Option<Secret>,
+
+    /// File containing the hash key
+    #[clap(
+        long,
+        env,
+        conflicts_with = "hash_key",
+        value_hint = ValueHint::FilePath
+    )]
+    hash_key_file: Option<PathBuf>,
+
+    #[command(flatten)]
+    upstream: UpstreamConfig,
+}
+
+#[derive(clap::Args, Debug)]
+struct ServeArgs {
+    /// Addresses to listen on, either `<ip>:<port>`, `unix:<path>` or `systemd[:<index>]`
+    #[clap(required = true)]
+    addr: Vec<Address>,
+
+    #[command(flatten)]
+    options: Box<serve::Options>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    Serve(ServeArgs),
+    /// Print the scope configuration derived from an identity schema
+    Validate {
+        /// Identity schema in Kratos to validate
+        #[clap(required_unless_present_any = ["file", "all"])]
+        schema: Option<String>,
+
+        /// Validate a local identity schema instead (`-` reads from stdin), Kratos is not
+        /// contacted
+        #[clap(long, conflicts_with = "schema", value_hint = ValueHint::FilePath)]
+        file: Option<PathBuf>,
+
+        /// Validate every identity schema in Kratos and print a summary per schema
+        #[clap(long, conflicts_with_all = ["schema", "file", "traits"])]
+        all: bool,
+
+        #[command(flatten)]
+        options: validate::Options,
+    },
+    /// Explain how a scope is resolved and which claims receive its value
+    Explain {
+        /// Scope to explain
+        scope: String,
+
+        /// Identity schema in Kratos the scope is configured in
+        #[clap(long, default_value = "default")]
+        schema: String,
+
+        /// Read the identity schema from a local file instead (`-` reads from stdin), Kratos is
+        /// not contacted
+        #[clap(long, conflicts_with = "schema", value_hint = ValueHint::FilePath)]
+        file: Option<PathBuf>,
+    },
+    /// Compare the scope configuration of two identity schemas
+    Diff {
+        /// Identity schema before the change, `file:<path>` reads a local file instead of the
+        /// schema in Kratos
+        old: validate::Source,
+
+        /// Identity schema after the change, `file:<path>` reads a local file instead of the
+        /// schema in Kratos
+        new: validate::Source,
+    },
+    /// Print which claims every scope discloses, from which traits and in which token, e.g. for a
+    /// data protection officer
+    Report {
+        /// Report on a local identity schema instead of every identity schema in Kratos (`-`
+        /// reads from stdin), Kratos is not contacted
+        #[clap(long, value_hint = ValueHint::FilePath)]
+        file: Option<PathBuf>,
+
+        #[command(flatten)]
+        options: report::Options,
+    },
+    /// Print the claims that would be issued for an identity in Kratos
+    Resolve(resolve::Options),
+    /// Revoke the consent of a subject in Hydra and remove it from the grant store
+    Revoke(revoke::Options),
+    /// Print the grants of a subject recorded by `serve --grant-store` as NDJSON
+    Grants(store::ExportOptions),
+    /// Print the JSON Schema of the keyword, to validate and complete it in identity schemas
+    ExportConfigSchema,
+    /// Measure how long parsing a local identity schema and resolving claims takes
+    Bench(bench::Options),
+    /// Check that Hydra and Kratos are reachable and the identity schema is annotated
+    Doctor {
+        /// Identity schema to check
+        #[clap(long, default_value = "default")]
+        schema: String,
+    },
+    /// Print shell completions for the given shell
+    Completions {
+        #[clap(value_enum)]
+        shell: Shell,
+    },
+    /// Print the effective configuration of `serve` (with secrets masked) and exit
+    PrintConfig {
+        #[clap(long, value_enum, default_value_t)]
+        format: config::Format,
+
+        #[command(flatten)]
+        serve: ServeArgs,
+    },
+}
+
+fn require(value: Option<Url>, name: &str) -> Url {
+    value.unwrap_or_else(|| {
+        Args::command()
+            .error(
+                ErrorKind::MissingRequiredArgument,
+                format!("the following required argument was not provided: --{name}"),
+            )
+            .exit()
+    })
+}
+
+/// Exit code when `validate --strict` found problems, to distinguish them from the command
+/// failing to run at all.
+const EXIT_PROBLEMS: u8 = 3;
+
+fn exit_code(report: &Report<Error>) -> ExitCode {
+    match report.downcast_ref::<validate::Error>() {
+        Some(validate::Error::Strict(_)) => ExitCode::from(EXIT_PROBLEMS),
+        _ => ExitCode::FAILURE,
+    }
+}
+
+/// Run the command given on the command line, the binary is a wrapper around it.
+pub async fn run() -> ExitCode {
+    let Err(report) = execute().await else {
+        return ExitCode::SUCCESS;
+    };
+
+    // same output as returning the error from `main`
+    let _ = writeln!(Term::stderr(), "Error: {report:?}");
+
+    exit_code(&report)
+}
+
+/// How the scope configuration is read from the identity schema.
+fn parse_options(cli: &GlobalArgs) -> ParseOptions {
+    ParseOptions {
+        keywords: cli.keywords.clone(),
+        direct_mapping: cli.direct_mapping,
+        oidc_presets: cli.oidc_presets,
+        access_token_key: cli.access_token_key.clone(),
+        passthrough_scopes: cli
+            .passthrough_scopes
+            .iter()
+            .cloned()
+            .map(Scope::new)
+            .collect(),
+        exclude_pii: cli.exclude_pii,
+        max_schema_depth: cli.max_schema_depth,
+        overlays: Overlays::default(),
+    }
+}
+
+/// Set up what explicit mappings are resolved with, before any of them is resolved.
+fn configure_mappings(cli: &GlobalArgs) -> Result<(), Error> {
+    plugin::load(&cli.plugins).change_context(Error)?;
+    script::configure(cli.script_max_operations).change_context(Error)?;
+
+    let hash_key = Secret::resolve(cli.hash_key.clone(), cli.hash_key_file.as_deref())
+        .change_context(Error)?;
+    hash::configure(hash_key).change_context(Error)?;
+
+    Ok(())
+}
+
+/// Upstreams and parse options of the commands that talk to Hydra and Kratos, with their secrets
+/// resolved.
+fn config(cli: &GlobalArgs, parse: ParseOptions) -> Result<Config, Error> {
+    let kratos_api_key = Secret::resolve(
+        cli.kratos_api_key.clone(),
+        cli.kratos_api_key_file.as_deref(),
+    )
+    .change_context(Error)?;
+    let hydra_api_key =
+        Secret::resolve(cli.hydra_api_key.clone(), cli.hydra_api_key_file.as_deref())
+            .change_context(Error)?;
+    let hydra_password = Secret::resolve(
+        cli.hydra_password.clone(),
+        cli.hydra_password_file.as_deref(),
+    )
+    .change_context(Error)?;
+
+    Ok(Config {
+        kratos_url: require(cli.kratos_admin_url.clone(), "kratos-admin-url"),
+        kratos_credentials: kratos_api_key.map(Credentials::Bearer),
+        kratos_public_url: cli.kratos_public_url.clone(),
+        hydra_url: require(cli.hydra_admin_url.clone(), "hydra-admin-url"),
+        hydra_credentials: hydra_api_key.map(Credentials::Bearer).or_else(|| {
+            cli.hydra_username
+                .clone()
+                .map(|username| Credentials::Basic {
+                    username,
+                    password: hydra_password,
+                })
+        }),
+        parse,
+        scope_config: cli.scope_config.clone(),
+        sources: vec![],
+        upstream: cli.upstream.clone(),
+    })
+}
+
+/// Parse options with the scope configuration loaded and explicit mappings set up, shared by
+/// every command.
+async fn prepare(cli: &GlobalArgs) -> Result<ParseOptions, Error> {
+    let mut parse = parse_options(cli);
+    if let Some(location) = &cli.scope_config {
+        let client = cli.upstream.http().change_context(Error)?;
+        parse.overlays = Overlays::load(location, &client)
+            .await
+            .change_context(Error)?;
+    }
+
+    configure_mappings(cli)?;
+
+    Ok(parse)
+}
+
+impl Config {
+    /// Configuration read from the same options and environment variables as the command line,
+    /// without a command, e.g. `["--kratos-admin-url", "http://kratos:4434", "--hydra-admin-url",
+    /// "http://hydra:4445"]`. The plugins, script budget and hash key of explicit mappings are set
+    /// up as well, once per process: a later call with different ones fails. `--config` is not
+    /// supported.
+    pub async fn try_parse_from<I, T>(args: I) -> Result<Self, Error>
+    where
+        I: IntoIterator<Item = T> + Send,
+        T: Into<OsString> + Clone,
+    {
+        let command = GlobalArgs::augment_args(
+            clap::Command::new(env!("CARGO_PKG_NAME")).no_binary_name(true),
+        )
+        .mut_arg("kratos_admin_url", |arg| arg.required(true))
+        .mut_arg("hydra_admin_url", |arg| arg.required(true));
+
+        let matches = command
+            .try_get_matches_from(args)
+            .into_report()
+            .change_context(Error)?;
+        let cli = GlobalArgs::from_arg_matches(&matches)
+            .into_report()
+            .change_context(Error)?;
+
+        let parse = prepare(&cli).await?;
+
+        config(&cli, parse)
+    }
+}
+
+async fn execute() -> Result<(), Error> {
+    let args: Vec<OsString> = std::env::args_os().collect();
+
+    let mut command = Args::command();
+    if let Some(path) = ConfigFile::locate(&command, &args) {
+        command = ConfigFile::load(&path)
+            .and_then(|file| file.apply(command))
+            .change_context(Error)?;
+    }
+
+    let matches = command.clone().get_matches_from(args);
+    let cli = Args::from_arg_matches(&matches).unwrap_or_else(|error| error.exit());
+
+    // none of these parse identity schemas, so the scope configuration, plugins and hash key are
+    // not loaded, which would fail them if unavailable
+    match &cli.command {
+        Command::ExportConfigSchema => {
+            return validate::export_config_schema(&cli.global.keywords).change_context(Error);
+        }
+        Command::Grants(options) => {
+            return store::export(options).await.change_context(Error);
+        }
+        Command::Completions { shell } => {
+            let name = command.get_name().to_owned();
+            clap_complete::generate(*shell, &mut command, name, &mut std::io::stdout());
+
+            return Ok(());
+        }
+        Command::PrintConfig { format, .. } => {
+            // the pristine command keeps the declaration order of the options
+            return config::print(&Args::command(), &matches, *format).change_context(Error);
+        }
+        _ => {}
+    }
+
+    let parse = prepare(&cli.global).await?;
+
+    match &cli.command {
+        Command::Bench(options) => {
+            return bench::run(options, &parse).change_context(Error);
+        }
+        Command::Validate {
+            file: Some(file),
+            options,
+            ..
+        } => {
+            return validate::run_file(file, options, &parse).change_context(Error);
+        }
+        Command::Report {
+            file: Some(file),
+            options,
+        } => {
+            return report::run_file(file, options, &parse).change_context(Error);
+        }
+        Command::Diff { old, new } if !old.is_remote() && !new.is_remote() => {
+            return diff::run(old, new, None, &parse)
+                .await
+                .change_context(Error);
+        }
+        Command::Explain {
+            scope,
+            file: Some(file),
+            ..
+        } => {
+            return explain::run_file(scope, file, &parse).change_context(Error);
+        }
+        _ => {}
+    }
+
+    let config = config(&cli.global, parse)?;
+
+    match cli.command {
+        Command::Serve(ServeArgs { addr, options }) => serve::run(&addr, *options, config)
+            .await
+            .change_context(Error),
+        Command::Validate {
+            all: true, options, ..
+        } => validate::run_all(options, config)
+            .await
+            .change_context(Error),
+        Command::Validate {
+            schema, options, ..
+        } => validate::run(schema.unwrap_or_default(), options, config)
+            .await
+            .change_context(Error),
+        Command::Explain { scope, schema, .. } => explain::run(&scope, &schema, config)
+            .await
+            .change_context(Error),
+        Command::Diff { old, new } => diff::run_remote(&old, &new, config)
+            .await
+            .change_context(Error),
+        Command::Resolve(options) => resolve::run(options, config).await.change_context(Error),
+        Command::Doctor { schema } => doctor::run(schema, config).await.change_context(Error),
+        Command::Report { options, .. } => {
+            report::run(&options, config).await.change_context(Error)
+        }
+        Command::Revoke(options) => revoke::run(options, config).await.change_context(Error),
+        // handled before secrets are resolved, as none of them needs them
+        Command::PrintConfig { .. }
+        | Command::Completions { .. }
+        | Command::ExportConfigSchema
+        | Command::Bench(_)
+        | Command::Grants(_) => Ok(()),
+    }
+}
@@ -0,0 +1,252 @@
+use std::{
+    collections::HashSet,
+    ffi::OsString,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use console::Term;
+use error_stack::{IntoReport, Report, Result, ResultExt};
+use indexmap::IndexMap;
+use serde_json::Value;
+use thiserror::Error;
+use url::Url;
+
+#[derive(Debug, Error)]
+pub(crate) enum Error {
+    #[error("unable to read configuration file {}", .0.display())]
+    Io(PathBuf),
+    #[error("unable to parse configuration file {}", .0.display())]
+    Parse(PathBuf),
+    #[error("unknown option `{0}`")]
+    UnknownOption(String),
+    #[error("option `{0}` must be a scalar or a list of scalars")]
+    InvalidValue(String),
+    #[error("unable to write configuration")]
+    Output,
+}
+
+#[derive(Debug, Copy, Clone, Default, clap::ValueEnum)]
+pub(crate) enum Format {
+    #[default]
+    Yaml,
+    Json,
+}
+
+/// Options read from a YAML (or JSON) or TOML configuration file.
+///
+/// Keys are the long names of the command line flags (e.g. `upstream-timeout` or
+/// `upstream_timeout`), the values are used as defaults, so that environment variables and flags
+/// still take precedence.
+#[derive(Debug)]
+pub(crate) struct ConfigFile {
+    path: PathBuf,
+    options: IndexMap<String, Value>,
+}
+
+impl ConfigFile {
+    pub(crate) fn load(path: &Path) -> Result<Self, Error> {
+        let contents = std::fs::read_to_string(path)
+            .into_report()
+            .change_context_lazy(|| Error::Io(path.to_path_buf()))?;
+
+        let is_toml = path
+            .extension()
+            .map_or(false, |extension| extension == "toml");
+
+        let options: IndexMap<String, Value> = if is_toml {
+            toml::from_str(&contents)
+                .into_report()
+                .change_context_lazy(|| Error::Parse(path.to_path_buf()))?
+        } else {
+            serde_yaml::from_str(&contents)
+                .into_report()
+                .change_context_lazy(|| Error::Parse(path.to_path_buf()))?
+        };
+
+        let options = options
+            .into_iter()
+            .map(|(key, value)| (key.replace('_', "-"), value))
+            .collect();
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            options,
+        })
+    }
+
+    /// Find the configuration file given through `--config` or `CONFIG` without fully parsing the
+    /// command line, which might only become valid once the file has been applied.
+    pub(crate) fn locate(command: &Command, args: &[OsString]) -> Option<PathBuf> {
+        command
+            .clone()
+            .ignore_errors(true)
+            .try_get_matches_from(args)
+            .ok()?
+            .get_one::<PathBuf>("config")
+            .cloned()
+    }
+
+    fn values(key: &str, value: &Value) -> Result<Vec<String>, Error> {
+        let scalar = |value: &Value| match value {
+            Value::String(value) => Ok(value.clone()),
+            Value::Bool(value) => Ok(value.to_string()),
+            Value::Number(value) => Ok(value.to_string()),
+            Value::Null | Value::Array(_) | Value::Object(_) => {
+                Err(Report::new(Error::InvalidValue(key.to_owned())))
+            }
+        };
+
+        match value {
+            Value::Null => Ok(vec![]),
+            Value::Array(values) => values.iter().map(scalar).collect(),
+            value => scalar(value).map(|value| vec![value]),
+        }
+    }
+
+    fn key(arg: &Arg) -> String {
+        arg.get_long().map_or_else(
+            || arg.get_id().as_str().replace('_', "-"),
+            ToOwned::to_owned,
+        )
+    }
+
+    fn apply_to(&self, mut command: Command, used: &mut HashSet<String>) -> Result<Command, Error> {
+        let keys: Vec<_> = command
+            .get_arguments()
+            .map(|arg| (arg.get_id().as_str().to_owned(), Self::key(arg)))
+            .collect();
+
+        for (id, key) in keys {
+            let Some(value) = self.options.get(&key) else {
+                continue;
+            };
+
+            let values = Self::values(&key, value)?;
+            used.insert(key);
+
+            if values.is_empty() {
+                continue;
+            }
+
+            command = command.mut_arg(id, |arg| arg.default_values(values).required(false));
+        }
+
+        let subcommands: Vec<_> = command.get_subcommands().cloned().collect();
+
+        for subcommand in subcommands {
+            let name = subcommand.get_name().to_owned();
+            let subcommand = self.apply_to(subcommand, used)?;
+
+            command = command.mut_subcommand(name, |_| subcommand);
+        }
+
+        Ok(command)
+    }
+
+    /// Use the values of the file as defaults of the respective arguments.
+    pub(crate) fn apply(&self, command: Command) -> Result<Command, Error> {
+        let mut used = HashSet::new();
+        let command = self
+            .apply_to(command, &mut used)
+            .change_context_lazy(|| Error::Parse(self.path.clone()))?;
+
+        let unknown = self
+            .options
+            .keys()
+            .filter(|key| !used.contains(*key))
+            .map(|key| Report::new(Error::UnknownOption(key.clone())))
+            .reduce(|mut report, error| {
+                report.extend_one(error);
+                report
+            });
+
+        if let Some(report) = unknown {
+            return Err(report.change_context(Error::Parse(self.path.clone())));
+        }
+
+        Ok(command)
+    }
+}
+
+/// Mask the credentials of URLs, e.g. of the upstream proxy, which may embed them.
+fn redact_userinfo(value: String) -> String {
+    let Ok(mut url) = Url::parse(&value) else {
+        return value;
+    };
+
+    if url.username().is_empty() && url.password().is_none() {
+        return value;
+    }
+
+    // only fails for URLs that cannot have credentials in the first place
+    let _ = url.set_username("***");
+    let _ = url.set_password(url.password().map(|_| "***"));
+
+    url.into()
+}
+
+fn effective(command: &Command, matches: &ArgMatches, options: &mut IndexMap<String, Value>) {
+    for arg in command.get_arguments() {
+        let id = arg.get_id().as_str();
+
+        // options that only steer the invocation itself
+        if matches!(id, "config" | "format" | "help" | "version") {
+            continue;
+        }
+
+        let Ok(Some(values)) = matches.try_get_raw(id) else {
+            continue;
+        };
+
+        // secrets are the options whose environment variable is hidden from the help
+        let values: Vec<_> = values
+            .map(|value| {
+                if arg.is_hide_env_values_set() {
+                    Value::String("***".to_owned())
+                } else {
+                    Value::String(redact_userinfo(value.to_string_lossy().into_owned()))
+                }
+            })
+            .collect();
+
+        let value = match arg.get_action() {
+            ArgAction::Append => Value::Array(values),
+            ArgAction::SetTrue | ArgAction::SetFalse => {
+                Value::Bool(values.first().and_then(Value::as_str) == Some("true"))
+            }
+            _ => values.into_iter().next().unwrap_or(Value::Null),
+        };
+
+        options.insert(ConfigFile::key(arg), value);
+    }
+
+    if let Some((name, matches)) = matches.subcommand() {
+        if let Some(command) = command.find_subcommand(name) {
+            effective(command, matches, options);
+        }
+    }
+}
+
+/// Print the options after merging the configuration file, environment and command line, in the
+/// same shape the configuration file is read in.
+pub(crate) fn print(command: &Command, matches: &ArgMatches, format: Format) -> Result<(), Error> {
+    let mut options = IndexMap::new();
+    effective(command, matches, &mut options);
+
+    let output = match format {
+        Format::Yaml => serde_yaml::to_string(&options)
+            .into_report()
+            .change_context(Error::Output)?,
+        Format::Json => serde_json::to_string_pretty(&options)
+            .map(|output| format!("{output}\n"))
+            .into_report()
+            .change_context(Error::Output)?,
+    };
+
+    Term::stdout()
+        .write_all(output.as_bytes())
+        .into_report()
+        .change_context(Error::Output)
+}
@@ -0,0 +1,129 @@
+use error_stack::{Result, ResultExt};
+use indexmap::IndexMap;
+use ory_hydra_client::models::OAuth2ConsentSession;
+use serde::Serialize;
+use thiserror::Error;
+use time::{format_description::well_known::Rfc3339, Duration, OffsetDateTime};
+
+use crate::{store::Store, upstream::Hydra};
+
+#[derive(Debug, Copy, Clone, Error)]
+pub(crate) enum Error {
+    #[error("unable to list the consent sessions in Hydra")]
+    Hydra,
+    #[error("unable to list the grants in the grant store")]
+    Store,
+}
+
+// Hydra paginates with a `Link` header, which the generated client does not expose
+const PAGE_SIZE: i64 = 500;
+
+/// Consent of a subject to a client, as shown to the user in an account dashboard.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub(crate) struct Consent {
+    client_id: Option<String>,
+    client_name: Option<String>,
+    scopes: Vec<String>,
+    granted_at: Option<String>,
+    /// `null` if Hydra remembers the consent indefinitely or does not remember it at all
+    expires_at: Option<String>,
+    /// Whether Hydra remembers the consent, so that the client gets tokens without asking again
+    remembered: bool,
+}
+
+impl Consent {
+    fn from_session(session: OAuth2ConsentSession) -> Self {
+        let client = session
+            .consent_request
+            .and_then(|request| request.client)
+            .map(|client| *client);
+
+        // Hydra remembers a consent with `remember_for` of `0` indefinitely
+        let expires_at = session
+            .handled_at
+            .as_deref()
+            .and_then(|handled_at| OffsetDateTime::parse(handled_at, &Rfc3339).ok())
+            .zip(session.remember_for.filter(|seconds| *seconds > 0))
+            .and_then(|(handled_at, seconds)| {
+                (handled_at + Duration::seconds(seconds))
+                    .format(&Rfc3339)
+                    .ok()
+            });
+
+        Self {
+            client_id: client.as_ref().and_then(|client| client.client_id.clone()),
+            client_name: client
+                .and_then(|client| client.client_name)
+                .filter(|name| !name.is_empty()),
+            scopes: session.grant_scope.unwrap_or_default(),
+            granted_at: session.handled_at,
+            expires_at,
+            remembered: session.remember.unwrap_or(false),
+        }
+    }
+}
+
+/// Consents of the subject, one per client: those Hydra remembers, followed by the most recent
+/// grant recorded in the grant store of every other client.
+pub(crate) async fn list(
+    hydra: &Hydra,
+    store: Option<&Store>,
+    tenant: &str,
+    subject: &str,
+) -> Result<Vec<Consent>, Error> {
+    let sessions = hydra
+        .scoped()
+        .call(Error::Hydra, |hydra| {
+            ory_hydra_client::apis::o_auth2_api::list_o_auth2_consent_sessions(
+                hydra,
+                subject,
+                Some(PAGE_SIZE),
+                None,
+                None,
+            )
+        })
+        .await?;
+
+    let mut consents = IndexMap::new();
+
+    for consent in sessions.into_iter().map(Consent::from_session) {
+        // a client has a consent session per login session, the most recent one is shown
+        let newer = consents
+            .get(&consent.client_id)
+            .map_or(true, |existing: &Consent| {
+                existing.granted_at < consent.granted_at
+            });
+
+        if newer {
+            consents.insert(consent.client_id.clone(), consent);
+        }
+    }
+
+    if let Some(store) = store {
+        let grants = store
+            .grants(tenant, subject, None)
+            .await
+            .change_context(Error::Store)?;
+
+        // oldest first, so that the most recent grant of a client remains
+        let mut recorded = IndexMap::new();
+        for grant in grants {
+            recorded.insert(grant.client_id.clone(), grant);
+        }
+
+        for (client_id, grant) in recorded {
+            consents.entry(client_id).or_insert_with(|| Consent {
+                client_id: grant.client_id,
+                client_name: None,
+                scopes: grant.scopes,
+                granted_at: OffsetDateTime::from_unix_timestamp(grant.granted_at)
+                    .ok()
+                    .and_then(|granted_at| granted_at.format(&Rfc3339).ok()),
+                expires_at: None,
+                remembered: false,
+            });
+        }
+    }
+
+    Ok(consents.into_values().collect())
+}
@@ -0,0 +1,184 @@
+use std::io::Write;
+
+use console::{style, Term};
+use error_stack::{IntoReport, Result, ResultExt};
+use indexmap::IndexMap;
+use thiserror::Error;
+
+use crate::{
+    explain::{claim, Trace},
+    schema::{ParseOptions, Scope},
+    serve::Config,
+    upstream::Kratos,
+    validate::{self, Source},
+};
+
+#[derive(Debug, Error)]
+pub(crate) enum Error {
+    #[error("unable to load the scope configuration of `{0}`")]
+    Schema(Source),
+    #[error("unable to configure upstream clients")]
+    Upstream,
+    #[error("unable to write to stdout")]
+    Io,
+}
+
+async fn load(
+    source: &Source,
+    kratos: Option<&Kratos>,
+    options: &ParseOptions,
+) -> Result<IndexMap<Scope, Trace>, Error> {
+    let schema = source
+        .load(kratos)
+        .await
+        .change_context_lazy(|| Error::Schema(source.clone()))?;
+
+    let (cache, config) = validate::parse(options, source.id(), &schema, &mut vec![]);
+
+    let traces = config
+        .scopes
+        .iter()
+        .map(|(scope, configuration)| (scope.clone(), Trace::new(scope, configuration, &cache)))
+        .collect();
+
+    Ok(traces)
+}
+
+fn sources(trace: &Trace) -> String {
+    if trace.sources.is_empty() {
+        return "none".to_owned();
+    }
+
+    trace.sources.join(", ")
+}
+
+fn describe(text: &Option<String>) -> String {
+    text.clone().unwrap_or_else(|| "none".to_owned())
+}
+
+fn remember_for(seconds: Option<u64>) -> String {
+    seconds.map_or_else(|| "none".to_owned(), |seconds| format!("{seconds}s"))
+}
+
+fn list(items: &[String]) -> String {
+    if items.is_empty() {
+        return "none".to_owned();
+    }
+
+    items.join(", ")
+}
+
+fn changes(old: &Trace, new: &Trace) -> Vec<String> {
+    let fields = [
+        ("kind", old.kind.to_owned(), new.kind.to_owned()),
+        (
+            "required",
+            old.required.to_string(),
+            new.required.to_string(),
+        ),
+        ("pii", old.pii.to_string(), new.pii.to_string()),
+        (
+            "remember_for",
+            remember_for(old.remember_for),
+            remember_for(new.remember_for),
+        ),
+        ("title", describe(&old.title), describe(&new.title)),
+        (
+            "description",
+            describe(&old.description),
+            describe(&new.description),
+        ),
+        ("aliases", list(&old.aliases), list(&new.aliases)),
+        ("includes", list(&old.includes), list(&new.includes)),
+        ("sources", sources(old), sources(new)),
+        ("value", old.value.clone(), new.value.clone()),
+        ("id_token", claim(&old.id_token), claim(&new.id_token)),
+        (
+            "access_token",
+            claim(&old.access_token),
+            claim(&new.access_token),
+        ),
+        ("userinfo", claim(&old.userinfo), claim(&new.userinfo)),
+    ];
+
+    fields
+        .into_iter()
+        .filter(|(_, old, new)| old != new)
+        .map(|(field, old, new)| format!("{field}: {old} → {new}"))
+        .collect()
+}
+
+fn print(old: &IndexMap<Scope, Trace>, new: &IndexMap<Scope, Trace>) -> std::io::Result<()> {
+    let mut term = Term::stdout();
+    let mut unchanged = true;
+
+    for (scope, trace) in old {
+        match new.get(scope) {
+            None => {
+                writeln!(
+                    term,
+                    "{} {}: {}",
+                    style("-").red(),
+                    scope.as_str(),
+                    trace.claims().join(", ")
+                )?;
+            }
+            Some(other) => {
+                let changes = changes(trace, other);
+
+                if changes.is_empty() {
+                    continue;
+                }
+
+                writeln!(term, "{} {}", style("~").yellow(), scope.as_str())?;
+
+                for change in changes {
+                    writeln!(term, "    {change}")?;
+                }
+            }
+        }
+
+        unchanged = false;
+    }
+
+    for (scope, trace) in new {
+        if old.contains_key(scope) {
+            continue;
+        }
+
+        writeln!(
+            term,
+            "{} {}: {}",
+            style("+").green(),
+            scope.as_str(),
+            trace.claims().join(", ")
+        )?;
+
+        unchanged = false;
+    }
+
+    if unchanged {
+        writeln!(term, "no changes to the scope configuration")?;
+    }
+
+    Ok(())
+}
+
+/// Print the scopes that were added, removed or changed between two identity schemas.
+pub(crate) async fn run(
+    old: &Source,
+    new: &Source,
+    kratos: Option<&Kratos>,
+    options: &ParseOptions,
+) -> Result<(), Error> {
+    let old = load(old, kratos, options).await?;
+    let new = load(new, kratos, options).await?;
+
+    print(&old, &new).into_report().change_context(Error::Io)
+}
+
+pub(crate) async fn run_remote(old: &Source, new: &Source, config: Config) -> Result<(), Error> {
+    let kratos = validate::connect(&config).change_context(Error::Upstream)?;
+
+    run(old, new, Some(&kratos), &config.parse).await
+}
@@ -0,0 +1,280 @@
+use std::io::Write;
+
+use console::{style, Term};
+use error_stack::{IntoReport, Report, Result, ResultExt};
+use serde_json::Value;
+use thiserror::Error;
+
+use crate::{
+    serve::Config,
+    upstream::{Hydra, Kratos},
+    validate,
+};
+
+#[derive(Debug, Error)]
+pub(crate) enum Error {
+    #[error("API error to Hydra")]
+    Hydra,
+    #[error("API error to Kratos")]
+    Kratos,
+    #[error("keyword not found")]
+    KeywordMissing,
+    #[error("unable to write to stdout")]
+    Io,
+    #[error("{0} checks failed")]
+    Failed(usize),
+}
+
+struct Check {
+    name: &'static str,
+    hint: &'static str,
+    outcome: core::result::Result<String, String>,
+}
+
+impl Check {
+    const fn pass(name: &'static str, detail: String) -> Self {
+        Self {
+            name,
+            hint: "",
+            outcome: Ok(detail),
+        }
+    }
+
+    fn fail<C>(name: &'static str, hint: &'static str, report: &Report<C>) -> Self {
+        Self {
+            name,
+            hint,
+            outcome: Err(format!("{report:#}")),
+        }
+    }
+
+    fn new<T, C>(
+        name: &'static str,
+        hint: &'static str,
+        result: Result<T, C>,
+        detail: &'static str,
+    ) -> Self {
+        match result {
+            Ok(_) => Self::pass(name, detail.to_owned()),
+            Err(report) => Self::fail(name, hint, &report),
+        }
+    }
+
+    fn write(&self, term: &mut Term) -> std::io::Result<()> {
+        match &self.outcome {
+            Ok(detail) => writeln!(term, "{} {}: {detail}", style("✔").green(), self.name),
+            Err(error) => {
+                writeln!(term, "{} {}: {error}", style("✘").red(), self.name)?;
+                writeln!(term, "  {} {}", style("hint:").yellow(), self.hint)
+            }
+        }
+    }
+}
+
+/// Key the value of a keyword is stored under, the last token of a keyword that is a JSON pointer.
+fn keyword_key(keyword: &str) -> String {
+    keyword
+        .starts_with('/')
+        .then(|| jsonptr::Pointer::try_from(keyword).ok())
+        .flatten()
+        .and_then(|pointer| pointer.tokens().last())
+        .map_or_else(|| keyword.to_owned(), |token| token.as_str().to_owned())
+}
+
+fn contains_key(value: &Value, key: &str) -> bool {
+    match value {
+        Value::Object(object) => object
+            .iter()
+            .any(|(name, value)| name == key || contains_key(value, key)),
+        Value::Array(array) => array.iter().any(|value| contains_key(value, key)),
+        _ => false,
+    }
+}
+
+async fn check_kratos(kratos: &Kratos, checks: &mut Vec<Check>) {
+    let ready = kratos
+        .scoped()
+        .call(Error::Kratos, |config| {
+            ory_kratos_client::apis::metadata_api::is_ready(config)
+        })
+        .await;
+
+    checks.push(Check::new(
+        "Kratos is reachable",
+        "verify that `KRATOS_ADMIN_URL` points to the admin API and the upstream TLS options match",
+        ready,
+        "ready",
+    ));
+
+    let identities = kratos
+        .scoped()
+        .call(Error::Kratos, |config| {
+            ory_kratos_client::apis::identity_api::list_identities(config, Some(1), None, None)
+        })
+        .await;
+
+    checks.push(Check::new(
+        "Kratos admin API is authorized",
+        "verify `KRATOS_API_KEY`, the admin API must be reachable with it",
+        identities,
+        "listed identities",
+    ));
+}
+
+async fn check_hydra(hydra: &Hydra, checks: &mut Vec<Check>) {
+    let ready = hydra
+        .scoped()
+        .call(Error::Hydra, |config| {
+            ory_hydra_client::apis::metadata_api::is_ready(config)
+        })
+        .await;
+
+    checks.push(Check::new(
+        "Hydra is reachable",
+        "verify that `HYDRA_ADMIN_URL` points to the admin API and the upstream TLS options match",
+        ready,
+        "ready",
+    ));
+
+    let clients = hydra
+        .scoped()
+        .call(Error::Hydra, |config| {
+            ory_hydra_client::apis::o_auth2_api::list_o_auth2_clients(
+                config,
+                Some(1),
+                None,
+                None,
+                None,
+            )
+        })
+        .await;
+
+    checks.push(Check::new(
+        "Hydra admin API is authorized",
+        "verify `HYDRA_API_KEY` or `HYDRA_USERNAME` and `HYDRA_PASSWORD`",
+        clients,
+        "listed clients",
+    ));
+}
+
+async fn check_schema(kratos: &Kratos, schema: &str, config: &Config, checks: &mut Vec<Check>) {
+    let traits = match validate::fetch_traits(kratos, schema).await {
+        Ok(traits) => traits,
+        Err(report) => {
+            checks.push(Check::fail(
+                "identity schema parses",
+                "verify that the schema exists in Kratos and has a `properties.traits` object",
+                &report,
+            ));
+
+            return;
+        }
+    };
+
+    checks.push(Check::pass(
+        "identity schema parses",
+        format!("schema `{schema}`"),
+    ));
+
+    let present = serde_json::to_value(&traits).map_or(false, |value| {
+        config
+            .parse
+            .keywords
+            .iter()
+            .any(|keyword| contains_key(&value, &keyword_key(keyword)))
+    });
+
+    if !present {
+        checks.push(Check::fail(
+            "keyword is present",
+            "annotate the traits in the identity schema with the keyword or change `KEYWORD`",
+            &Report::new(Error::KeywordMissing)
+                .attach_printable(format!("keywords: {}", config.parse.keywords.join(", "))),
+        ));
+
+        return;
+    }
+
+    let mut problems = vec![];
+    let (_, scopes) = validate::parse(&config.parse, Some(schema), &traits, &mut problems);
+
+    checks.push(Check::pass(
+        "keyword is present",
+        format!("{} scopes configured", scopes.scopes.len()),
+    ));
+
+    let errors = problems.iter().filter(|problem| problem.is_error()).count();
+
+    if errors == 0 {
+        checks.push(Check::pass(
+            "scope configuration is well-formed",
+            "no problems".to_owned(),
+        ));
+    } else {
+        checks.push(Check::fail(
+            "scope configuration is well-formed",
+            "run `validate --strict` to list the malformed parts, which are skipped",
+            &Report::new(validate::Error::Strict(errors)),
+        ));
+    }
+}
+
+pub(crate) async fn run(schema: String, config: Config) -> Result<(), Error> {
+    let mut checks = vec![];
+
+    let upstreams = config.upstream.connect();
+    let upstreams = match upstreams {
+        Ok(upstreams) => upstreams,
+        Err(report) => {
+            checks.push(Check::fail(
+                "upstream clients are configured",
+                "verify the certificate, key and CA files as well as the proxy",
+                &report,
+            ));
+
+            return report_checks(&checks);
+        }
+    };
+
+    match upstreams.kratos(&config.kratos_url, config.kratos_credentials.as_ref()) {
+        Ok(kratos) => {
+            check_kratos(&kratos, &mut checks).await;
+            check_schema(&kratos, &schema, &config, &mut checks).await;
+        }
+        Err(report) => checks.push(Check::fail(
+            "Kratos client is configured",
+            "verify the Kratos credentials",
+            &report,
+        )),
+    }
+
+    match upstreams.hydra(&config.hydra_url, config.hydra_credentials.as_ref()) {
+        Ok(hydra) => check_hydra(&hydra, &mut checks).await,
+        Err(report) => checks.push(Check::fail(
+            "Hydra client is configured",
+            "verify the Hydra credentials",
+            &report,
+        )),
+    }
+
+    report_checks(&checks)
+}
+
+fn report_checks(checks: &[Check]) -> Result<(), Error> {
+    let mut term = Term::stdout();
+
+    for check in checks {
+        check
+            .write(&mut term)
+            .into_report()
+            .change_context(Error::Io)?;
+    }
+
+    let failed = checks.iter().filter(|check| check.outcome.is_err()).count();
+
+    if failed > 0 {
+        return Err(Report::new(Error::Failed(failed)));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,487 @@
+use core::{fmt::Display, time::Duration};
+use std::{io::Write, path::Path};
+
+use console::{style, Term};
+use error_stack::{IntoReport, Report, Result, ResultExt};
+use thiserror::Error;
+
+use crate::{
+    cache::ScopeCache,
+    schema::{
+        Collect, ParseOptions, Precedence, Scope, ScopeConfig, ScopeConfiguration,
+        ScopeExplicitMapping, ScopeExplicitMappingKind, SessionData, Source, Target,
+    },
+    serve::Config,
+    validate,
+};
+
+#[derive(Debug, Error)]
+pub(crate) enum Error {
+    #[error("unable to load the scope configuration")]
+    Schema,
+    #[error("scope `{0}` is not configured")]
+    UnknownScope(String),
+    #[error("unable to write to stdout")]
+    Io,
+}
+
+/// Where a value is looked up, e.g. a pointer or a query, together with the document it is looked
+/// up in.
+fn origin(location: &impl Display, source: Source) -> String {
+    match source {
+        Source::Traits => location.to_string(),
+        Source::Identity => format!("{location} of the identity"),
+        Source::Credentials => format!("{location} of the credentials"),
+        Source::Login => format!("{location} of the login"),
+        Source::Keto => format!("{location} of the relationships in Keto"),
+        Source::Sessions => format!("{location} of the sessions in Kratos"),
+        Source::Webhook => format!("{location} of the response of the webhook"),
+        Source::Custom => format!("{location} of the claim sources of the application"),
+        Source::Scope => format!("{location} of the requested scope"),
+    }
+}
+
+/// Where the values of an explicit mapping end up, e.g. `value.name[0] ← /name/first`.
+fn targets(mapping: &ScopeExplicitMapping, target: &str, lines: &mut Vec<String>) {
+    let start = lines.len();
+
+    match &mapping.kind {
+        ScopeExplicitMappingKind::Object { properties } => {
+            for (key, mapping) in properties {
+                targets(mapping, &format!("{target}.{key}"), lines);
+            }
+        }
+        ScopeExplicitMappingKind::Tuple { items } => {
+            for (index, mapping) in items.iter().enumerate() {
+                targets(mapping, &format!("{target}[{index}]"), lines);
+            }
+        }
+        ScopeExplicitMappingKind::Path { ref_, source } => {
+            lines.push(format!("{target} ← {}", origin(ref_, *source)));
+        }
+        ScopeExplicitMappingKind::Verified { ref_, source } => {
+            lines.push(format!(
+                "{target} ← verification of {}",
+                origin(ref_, *source)
+            ));
+        }
+        ScopeExplicitMappingKind::Transform { transform, value } => {
+            let start = lines.len();
+            targets(value, target, lines);
+
+            for line in lines.iter_mut().skip(start) {
+                line.push_str(&format!(" through `{transform}`"));
+            }
+        }
+        ScopeExplicitMappingKind::Expr { script } => {
+            lines.push(format!("{target} ← script `{script}`"));
+        }
+        ScopeExplicitMappingKind::Query { jmespath, source } => {
+            lines.push(format!(
+                "{target} ← {}",
+                origin(&format!("query `{jmespath}`"), *source)
+            ));
+        }
+        ScopeExplicitMappingKind::Template { template, source } => {
+            lines.push(format!(
+                "{target} ← {}",
+                origin(&format!("template `{template}`"), *source)
+            ));
+        }
+        ScopeExplicitMappingKind::Const { value } => {
+            lines.push(format!("{target} ← constant `{value}`"));
+        }
+        ScopeExplicitMappingKind::Coalesce { items } => {
+            let mut items = items.iter();
+
+            if let Some(first) = items.next() {
+                targets(first, target, lines);
+            }
+
+            for mapping in items {
+                let start = lines.len();
+                targets(mapping, target, lines);
+
+                for line in lines.iter_mut().skip(start) {
+                    line.push_str(" if the previous items are `null`");
+                }
+            }
+        }
+        ScopeExplicitMappingKind::If { when, then, else_ } => {
+            let condition = origin(&when.ref_, when.source);
+            let condition = when.equals.as_ref().map_or_else(
+                || format!("{condition} is set"),
+                |expected| format!("{condition} equals `{expected}`"),
+            );
+
+            let start = lines.len();
+            targets(then, target, lines);
+
+            for line in lines.iter_mut().skip(start) {
+                line.push_str(&format!(" if {condition}"));
+            }
+
+            if let Some(else_) = else_ {
+                let start = lines.len();
+                targets(else_, target, lines);
+
+                for line in lines.iter_mut().skip(start) {
+                    line.push_str(&format!(" unless {condition}"));
+                }
+            }
+        }
+        ScopeExplicitMappingKind::Map { over, source, item } => {
+            let start = lines.len();
+            targets(item, &format!("{target}[]"), lines);
+
+            let over = origin(over, *source);
+            for line in lines.iter_mut().skip(start) {
+                line.push_str(&format!(" for every item of {over}"));
+            }
+        }
+    }
+
+    modifiers(mapping, &mut lines[start..]);
+}
+
+/// How the value of a mapping is changed after it is resolved, e.g. ` as number`.
+fn modifiers(mapping: &ScopeExplicitMapping, lines: &mut [String]) {
+    if !mapping.normalize.is_empty() {
+        let normalizations = mapping
+            .normalize
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        for line in &mut *lines {
+            line.push_str(&format!(" normalized with {normalizations}"));
+        }
+    }
+
+    if let Some(date) = &mapping.date {
+        for line in &mut *lines {
+            line.push_str(&format!(" as date `{}` at {}", date.format, date.offset));
+        }
+    }
+
+    if let Some(coercion) = mapping.coerce {
+        for line in &mut *lines {
+            line.push_str(&format!(" as {coercion}"));
+        }
+    }
+
+    if let Some(hash) = mapping.hash {
+        for line in &mut *lines {
+            line.push_str(&format!(" hashed with {}", hash.as_str()));
+        }
+    }
+
+    if let Some(default) = &mapping.default {
+        for line in lines {
+            line.push_str(&format!(", `{default}` if `null`"));
+        }
+    }
+}
+
+const fn collect(collect: &Collect) -> &'static str {
+    match collect {
+        Collect::First => "the value of the first source that exists",
+        Collect::Last => "the value of the last source that exists",
+        Collect::Any => "the value of any source that exists",
+        Collect::All => "a list of the values of all sources that exist",
+        Collect::Merge => "the objects of all sources that exist, merged recursively",
+        Collect::Concat => "a list of the items of all sources that exist, arrays are flattened",
+        Collect::Count => {
+            "the number of values of all sources that exist, arrays count their items"
+        }
+        Collect::Unique => {
+            "a list of the distinct items of all sources that exist, arrays are flattened"
+        }
+        Collect::Verified => {
+            "the value of the first source that is a verified address, otherwise of the first \
+             source that exists"
+        }
+    }
+}
+
+pub(crate) fn claim(claims: &[String]) -> String {
+    if claims.is_empty() {
+        return "not issued".to_owned();
+    }
+
+    let claims: Vec<_> = claims.iter().map(|claim| format!("`{claim}`")).collect();
+
+    claims.join(", ")
+}
+
+/// Names of the claims of one token that the scope writes to.
+fn targets_of<'a>(
+    session_data: &[&'a SessionData],
+    target: impl Fn(&'a SessionData) -> Option<&'a Target>,
+) -> Vec<String> {
+    session_data
+        .iter()
+        .filter_map(|session_data| target(session_data))
+        .flat_map(Target::claims)
+        .map(ToOwned::to_owned)
+        .collect()
+}
+
+/// Human-readable summary of how a scope is resolved.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Trace {
+    pub(crate) kind: &'static str,
+    /// Whether consent is refused if the value is `null`
+    pub(crate) required: bool,
+    /// Other names the scope is requested under
+    pub(crate) aliases: Vec<String>,
+    /// Scopes resolved together with this one
+    pub(crate) includes: Vec<String>,
+    /// Whether the value is personally identifiable information
+    pub(crate) pii: bool,
+    /// Longest time in seconds the consent is remembered for
+    pub(crate) remember_for: Option<u64>,
+    pub(crate) title: Option<String>,
+    pub(crate) description: Option<String>,
+    pub(crate) sources: Vec<String>,
+    pub(crate) value: String,
+    pub(crate) id_token: Vec<String>,
+    pub(crate) access_token: Vec<String>,
+    pub(crate) userinfo: Vec<String>,
+}
+
+impl Trace {
+    pub(crate) fn new(
+        scope: &Scope,
+        configuration: &ScopeConfiguration,
+        cache: &ScopeCache,
+    ) -> Self {
+        let (kind, sources, value) = match configuration {
+            ScopeConfiguration::Implicit(implicit) => {
+                // the order is the one of the schema, which decides what `first` and `last` pick
+                let sources = cache
+                    .implicit_scopes
+                    .get(scope)
+                    .into_iter()
+                    .flatten()
+                    .map(ToString::to_string)
+                    .collect();
+
+                let mut value = collect(&implicit.collect).to_owned();
+
+                if implicit.collect == Collect::Merge {
+                    value.push_str(match implicit.precedence {
+                        Precedence::First => ", the first source wins on conflicts",
+                        Precedence::Last => ", the last source wins on conflicts",
+                    });
+                }
+
+                if let (Collect::Concat, Some(separator)) = (&implicit.collect, &implicit.separator)
+                {
+                    value.push_str(&format!(", joined with `{separator}`"));
+                }
+
+                if let Some(default) = &implicit.default {
+                    value.push_str(&format!(", `{default}` if none exists"));
+                }
+
+                ("implicit", sources, value)
+            }
+            ScopeConfiguration::Explicit(explicit) => {
+                let mut sources = vec![];
+                targets(&explicit.mapping, "value", &mut sources);
+
+                (
+                    "explicit",
+                    sources,
+                    "the mapping, missing sources are `null`".to_owned(),
+                )
+            }
+            ScopeConfiguration::Preset(preset) => {
+                let sources = preset
+                    .claims
+                    .iter()
+                    .flat_map(|preset| {
+                        let (claim, prefix) = preset
+                            .verifies
+                            .as_ref()
+                            .map_or((&preset.claim, ""), |claim| (claim, "verification of "));
+
+                        cache
+                            .implicit_scopes
+                            .claim(claim)
+                            .into_iter()
+                            .flatten()
+                            .map(move |pointer| format!("{} ← {prefix}{pointer}", preset.claim))
+                    })
+                    .collect();
+
+                (
+                    "preset",
+                    sources,
+                    "a claim per standard claim, the value of the first tagged trait that exists"
+                        .to_owned(),
+                )
+            }
+        };
+
+        let session_data = configuration.session_data();
+
+        let names = |scopes: &[Scope]| {
+            scopes
+                .iter()
+                .map(|scope| scope.as_str().to_owned())
+                .collect()
+        };
+
+        let (title, description) = configuration.describe();
+
+        Self {
+            kind,
+            title: title.map(ToOwned::to_owned),
+            description: description.map(ToOwned::to_owned),
+            required: configuration.is_required(),
+            pii: session_data.iter().any(|session_data| session_data.pii),
+            remember_for: configuration.remember_for(),
+            aliases: names(configuration.aliases()),
+            includes: names(configuration.includes()),
+            sources,
+            value,
+            id_token: targets_of(&session_data, |session_data| session_data.id_token.as_ref()),
+            access_token: targets_of(&session_data, |session_data| {
+                session_data.access_token.as_ref()
+            }),
+            userinfo: targets_of(&session_data, |session_data| session_data.userinfo.as_ref()),
+        }
+    }
+
+    pub(crate) fn claims(&self) -> Vec<String> {
+        let mut claims = vec![
+            format!("id_token: {}", claim(&self.id_token)),
+            format!("access_token: {}", claim(&self.access_token)),
+            format!("userinfo: {}", claim(&self.userinfo)),
+        ];
+
+        if !self.includes.is_empty() {
+            let includes: Vec<_> = self
+                .includes
+                .iter()
+                .map(|child| format!("`{child}`"))
+                .collect();
+
+            claims.push(format!("and the claims of {}", includes.join(", ")));
+        }
+
+        claims
+    }
+}
+
+fn section(term: &mut Term, title: &str, lines: &[String]) -> std::io::Result<()> {
+    writeln!(term, "{}", style(title).bold())?;
+
+    for line in lines {
+        writeln!(term, "  {line}")?;
+    }
+
+    Ok(())
+}
+
+/// Print how the value of a scope is resolved and which claims receive it.
+fn explain(scope: &str, cache: &ScopeCache, config: &ScopeConfig) -> Result<(), Error> {
+    let scope = Scope::new(scope.to_owned());
+
+    let Some(configuration) = config.find_scope(&scope) else {
+        let configured: Vec<_> = config.scopes.keys().map(Scope::as_str).collect();
+
+        return Err(Report::new(Error::UnknownScope(scope.as_str().to_owned()))
+            .attach_printable(format!("configured scopes: {}", configured.join(", "))));
+    };
+
+    let trace = Trace::new(&scope, configuration, cache);
+
+    let described: Vec<_> = [&trace.title, &trace.description]
+        .into_iter()
+        .flatten()
+        .cloned()
+        .collect();
+
+    let none = ["none, the value is always `null`".to_owned()];
+    let sources: &[String] = if trace.sources.is_empty() {
+        &none
+    } else {
+        &trace.sources
+    };
+
+    let mut term = Term::stdout();
+
+    let required = if trace.required {
+        ", consent is refused if its value is `null`"
+    } else {
+        ""
+    };
+
+    let pii = if trace.pii {
+        ", its value is personally identifiable information"
+    } else {
+        ""
+    };
+
+    let remember_for = match trace.remember_for {
+        None => String::new(),
+        Some(0) => ", consent is never remembered".to_owned(),
+        Some(seconds) => format!(
+            ", consent is remembered for at most {}",
+            humantime::format_duration(Duration::from_secs(seconds))
+        ),
+    };
+
+    let aliases = if trace.aliases.is_empty() {
+        String::new()
+    } else {
+        let aliases: Vec<_> = trace
+            .aliases
+            .iter()
+            .map(|alias| format!("`{alias}`"))
+            .collect();
+
+        format!(", also requested as {}", aliases.join(", "))
+    };
+
+    writeln!(
+        term,
+        "scope `{}` is {}{aliases}{required}{pii}{remember_for}",
+        style(scope.as_str()).cyan(),
+        trace.kind
+    )
+    .and_then(|_| {
+        if described.is_empty() {
+            return Ok(());
+        }
+
+        section(&mut term, "description", &described)
+    })
+    .and_then(|_| section(&mut term, "sources", sources))
+    .and_then(|_| section(&mut term, "value", &[trace.value.clone()]))
+    .and_then(|_| section(&mut term, "claims", &trace.claims()))
+    .into_report()
+    .change_context(Error::Io)
+}
+
+pub(crate) async fn run(scope: &str, schema: &str, config: Config) -> Result<(), Error> {
+    let kratos = validate::connect(&config).change_context(Error::Schema)?;
+
+    let (cache, scopes) = validate::fetch(&kratos, &config.parse, schema, &mut vec![])
+        .await
+        .change_context(Error::Schema)?;
+
+    explain(scope, &cache, &scopes)
+}
+
+pub(crate) fn run_file(scope: &str, path: &Path, options: &ParseOptions) -> Result<(), Error> {
+    let schema = validate::read_traits(path).change_context(Error::Schema)?;
+
+    let (cache, config) = validate::parse(options, None, &schema, &mut vec![]);
+
+    explain(scope, &cache, &config)
+}
@@ -0,0 +1,105 @@
+#![allow(clippy::std_instead_of_alloc)] // Reason: expansion of the `JsonSchema` derive
+
+use core::fmt::Write;
+use std::sync::OnceLock;
+
+use error_stack::{Report, Result};
+use hmac::{Hmac, Mac};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::secret::Secret;
+
+/// Hash a resolved value is replaced by, to release a stable pseudonym instead of the value
+/// itself, e.g. a hashed email address for analytics clients.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub(crate) enum Hash {
+    /// SHA-256 of the value
+    Sha256,
+    /// HMAC-SHA-256 of the value with the key of `--hash-key`, which, unlike a plain hash, cannot
+    /// be reversed by hashing every candidate value
+    Hmac,
+}
+
+impl Hash {
+    pub(crate) const fn as_str(self) -> &'static str {
+        match self {
+            Self::Sha256 => "sha256",
+            Self::Hmac => "hmac",
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Error)]
+#[error("a different hash key is configured already")]
+pub(crate) struct Error;
+
+// configured once at startup, like the engine of scripts
+static KEY: OnceLock<Secret> = OnceLock::new();
+
+/// Set the key of `hmac` hashes, must be called before any value is hashed. The key is shared by
+/// the whole process, setting a different one (or none) afterwards fails.
+pub(crate) fn configure(key: Option<Secret>) -> Result<(), Error> {
+    let configured = key.map_or_else(
+        || KEY.get().is_none(),
+        |key| match KEY.set(key) {
+            Ok(()) => true,
+            Err(key) => KEY.get() == Some(&key),
+        },
+    );
+
+    if configured {
+        Ok(())
+    } else {
+        Err(Report::new(Error))
+    }
+}
+
+/// Whether `hmac` hashes can be computed.
+pub(crate) fn is_configured() -> bool {
+    KEY.get().is_some()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .fold(String::with_capacity(bytes.len() * 2), |mut hex, byte| {
+            // writing to a string cannot fail
+            let _ = write!(hex, "{byte:02x}");
+
+            hex
+        })
+}
+
+fn digest(hash: Hash, input: &[u8]) -> Option<String> {
+    match hash {
+        Hash::Sha256 => Some(hex(&Sha256::digest(input))),
+        Hash::Hmac => {
+            let Some(key) = KEY.get() else {
+                tracing::warn!("unable to compute hmac, no hash key is configured");
+
+                return None;
+            };
+
+            let mut mac = Hmac::<Sha256>::new_from_slice(key.expose().as_bytes()).ok()?;
+            mac.update(input);
+
+            Some(hex(&mac.finalize().into_bytes()))
+        }
+    }
+}
+
+/// Replace the value by the lowercase hex encoded hash of it, strings are hashed as is, any other
+/// value as JSON. Items of arrays are hashed individually, `null` stays `null`.
+pub(crate) fn apply(hash: Hash, value: Value) -> Value {
+    match value {
+        Value::Null => Value::Null,
+        Value::Array(items) => items.into_iter().map(|item| apply(hash, item)).collect(),
+        Value::String(string) => digest(hash, string.as_bytes()).map_or(Value::Null, Value::String),
+        value => digest(hash, value.to_string().as_bytes()).map_or(Value::Null, Value::String),
+    }
+}
@@ -0,0 +1,124 @@
+use error_stack::{Report, Result};
+use ory_kratos_client::models::JsonPatch;
+use reqwest::StatusCode;
+use serde_json::{json, Map, Value};
+use thiserror::Error;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+use crate::upstream::Kratos;
+
+#[derive(Debug, Copy, Clone, Error)]
+#[error("unable to record the consent in the metadata of the identity")]
+pub(crate) struct Error;
+
+/// Key of `metadata_admin` the consents are recorded under.
+const KEY: &str = "consents";
+
+// keeps the metadata of identities that consent often from growing without bound
+const LIMIT: usize = 100;
+
+/// How often the consent is recorded again if the metadata changed concurrently.
+const ATTEMPTS: usize = 3;
+
+/// Append the consent given to the client to the consents in the `metadata_admin` of the identity,
+/// e.g. `{"client_id": "..", "scopes": ["openid"], "granted_at": "2023-06-01T12:00:00Z"}`. Only
+/// the most recent ones are kept, anything else in `metadata_admin` is left as is, and it is never
+/// replaced if it is not an object.
+///
+/// The patch only applies if `metadata_admin` is still the one read, otherwise the consent is
+/// recorded again on top of the concurrent change, e.g. of another consent of the same identity.
+pub(crate) async fn record(
+    kratos: &Kratos,
+    subject: &str,
+    client_id: Option<&str>,
+    scopes: &[String],
+) -> Result<(), Error> {
+    for _ in 0..ATTEMPTS {
+        if attempt(kratos, subject, client_id, scopes).await? {
+            return Ok(());
+        }
+    }
+
+    Err(Report::new(Error).attach_printable("`metadata_admin` kept changing concurrently"))
+}
+
+/// Record the consent, `false` if `metadata_admin` changed since it was read.
+async fn attempt(
+    kratos: &Kratos,
+    subject: &str,
+    client_id: Option<&str>,
+    scopes: &[String],
+) -> Result<bool, Error> {
+    let identity = kratos
+        .scoped()
+        .call(Error, |kratos| {
+            ory_kratos_client::apis::identity_api::get_identity(kratos, subject, None)
+        })
+        .await?;
+
+    let current = identity.metadata_admin.unwrap_or(Value::Null);
+    let mut metadata = match &current {
+        Value::Object(metadata) => metadata.clone(),
+        Value::Null => Map::new(),
+        _ => {
+            return Err(Report::new(Error)
+                .attach_printable("`metadata_admin` of the identity is not an object"));
+        }
+    };
+
+    let mut consents = match metadata.remove(KEY) {
+        Some(Value::Array(consents)) => consents,
+        _ => vec![],
+    };
+
+    let granted_at = OffsetDateTime::now_utc().format(&Rfc3339).ok();
+
+    consents.push(json!({
+        "client_id": client_id,
+        "scopes": scopes,
+        "granted_at": granted_at,
+    }));
+    consents.drain(..consents.len().saturating_sub(LIMIT));
+
+    metadata.insert(KEY.to_owned(), Value::Array(consents));
+
+    // a missing member tests equal to `null`
+    let test = JsonPatch {
+        value: Some(current),
+        ..JsonPatch::new("test".to_owned(), "/metadata_admin".to_owned())
+    };
+    // `add` replaces the member if it exists, which `replace` requires
+    let add = JsonPatch {
+        value: Some(Value::Object(metadata)),
+        ..JsonPatch::new("add".to_owned(), "/metadata_admin".to_owned())
+    };
+
+    kratos
+        .scoped()
+        .call(Error, |kratos| {
+            let patch = vec![test.clone(), add.clone()];
+
+            async move {
+                // Kratos rejects a patch with a failed `test` with `400`
+                match ory_kratos_client::apis::identity_api::patch_identity(
+                    kratos,
+                    subject,
+                    Some(patch),
+                )
+                .await
+                {
+                    Ok(_) => Ok(true),
+                    Err(ory_kratos_client::apis::Error::ResponseError(response))
+                        if matches!(
+                            response.status,
+                            StatusCode::BAD_REQUEST | StatusCode::CONFLICT
+                        ) =>
+                    {
+                        Ok(false)
+                    }
+                    Err(error) => Err(error),
+                }
+            }
+        })
+        .await
+}
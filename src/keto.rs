@@ -0,0 +1,147 @@
+use alloc::sync::Arc;
+use core::str::FromStr;
+
+use clap::ValueHint;
+use error_stack::{Result, ResultExt};
+use serde_json::{Map, Value};
+use thiserror::Error;
+use url::Url;
+
+use crate::{
+    source::{self, ClaimSource, Request},
+    upstream::Keto,
+};
+
+#[derive(Debug, Copy, Clone, Error)]
+#[error("unable to list the relationships of the subject in Keto")]
+pub(crate) struct Error;
+
+#[derive(Debug, Error)]
+#[error("invalid relationship `{0}`, expected `<name>=<namespace>#<relation>`")]
+pub(crate) struct ParseRelationshipError(String);
+
+/// Relationships of the subject exposed to mappings under `name`, e.g. `roles=Group#members`
+/// lists the objects of the `Group` namespace the subject is a member of.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Relationship {
+    name: String,
+    namespace: String,
+    relation: String,
+}
+
+impl FromStr for Relationship {
+    type Err = ParseRelationshipError;
+
+    fn from_str(value: &str) -> core::result::Result<Self, Self::Err> {
+        let relationship = value.split_once('=').and_then(|(name, tuple)| {
+            let (namespace, relation) = tuple.split_once('#')?;
+
+            [name, namespace, relation]
+                .iter()
+                .all(|part| !part.is_empty())
+                .then(|| Self {
+                    name: name.to_owned(),
+                    namespace: namespace.to_owned(),
+                    relation: relation.to_owned(),
+                })
+        });
+
+        relationship.ok_or_else(|| ParseRelationshipError(value.to_owned()))
+    }
+}
+
+#[derive(Debug, Clone, clap::Args)]
+pub(crate) struct KetoConfig {
+    /// URL of the Keto read API, the relationships of the subject are only listed if set
+    #[clap(
+        long,
+        env,
+        requires = "keto_relationships",
+        value_hint = ValueHint::Url
+    )]
+    pub(crate) keto_read_url: Option<Url>,
+
+    /// Relationships of the subject exposed to mappings with the `keto` source, as
+    /// `<name>=<namespace>#<relation>`, e.g. `roles=Group#members`
+    #[clap(
+        long = "keto-relationship",
+        env = "KETO_RELATIONSHIPS",
+        value_delimiter = ',',
+        requires = "keto_read_url"
+    )]
+    pub(crate) keto_relationships: Vec<Relationship>,
+}
+
+// most subjects are related to few enough objects to fit into a single page
+const PAGE_SIZE: i64 = 1000;
+
+/// List the objects the subject is related to for every relationship, e.g.
+/// `{"roles": ["admin", "editor"]}`.
+async fn relationships(
+    keto: &Keto,
+    relationships: &[Relationship],
+    subject: &str,
+) -> Result<Value, Error> {
+    let mut document = Map::new();
+
+    for relationship in relationships {
+        let mut objects = vec![];
+        let mut page_token = None::<String>;
+
+        loop {
+            let page = keto
+                .scoped()
+                .call(Error, |keto| {
+                    ory_keto_client::apis::relationship_api::get_relationships(
+                        keto,
+                        page_token.as_deref(),
+                        Some(PAGE_SIZE),
+                        Some(&relationship.namespace),
+                        None,
+                        Some(&relationship.relation),
+                        Some(subject),
+                        None,
+                        None,
+                        None,
+                    )
+                })
+                .await?;
+
+            objects.extend(
+                page.relation_tuples
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|relationship| Value::String(relationship.object)),
+            );
+
+            match page.next_page_token {
+                Some(token) if !token.is_empty() => page_token = Some(token),
+                _ => break,
+            }
+        }
+
+        document.insert(relationship.name.clone(), Value::Array(objects));
+    }
+
+    Ok(Value::Object(document))
+}
+
+/// Relationships of the subject, the document of the `keto` source.
+#[derive(Debug)]
+pub(crate) struct KetoSource {
+    pub(crate) keto: Arc<Keto>,
+    pub(crate) relationships: Vec<Relationship>,
+}
+
+#[axum::async_trait]
+impl ClaimSource for KetoSource {
+    fn name(&self) -> &str {
+        source::KETO
+    }
+
+    async fn fetch(&self, request: &Request<'_>) -> Result<Value, source::Error> {
+        relationships(&self.keto, &self.relationships, request.subject)
+            .await
+            .change_context(source::Error)
+    }
+}
@@ -0,0 +1,50 @@
+//! Consent and logout provider of Ory Hydra, which issues the traits of Ory Kratos identities as
+//! claims according to the scopes configured in their identity schema.
+//!
+//! [`router`] returns the handlers of `serve`, to mount them in an existing axum application, and
+//! [`Schema`] resolves the claims of identities without serving them. Implementations of
+//! [`ClaimSource`] contribute further documents explicit mappings resolve pointers in.
+
+extern crate alloc;
+
+mod bench;
+mod breaker;
+pub mod cache;
+pub mod cli;
+mod config;
+mod consents;
+mod diff;
+mod doctor;
+mod explain;
+mod hash;
+mod history;
+mod keto;
+mod lint;
+mod listen;
+mod middleware;
+mod namespace;
+mod overlay;
+mod plugin;
+mod report;
+mod resolve;
+mod revoke;
+pub mod schema;
+mod script;
+mod secret;
+pub mod serve;
+mod sessions;
+mod size;
+pub mod source;
+mod store;
+mod telemetry;
+mod tenant;
+mod upstream;
+mod validate;
+mod webhook;
+
+pub use crate::{
+    cache::Schema,
+    schema::{Claims, Context, ParseOptions, Problem, Scope},
+    serve::{router, Config, Options},
+    source::ClaimSource,
+};
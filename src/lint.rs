@@ -0,0 +1,529 @@
+use indexmap::IndexMap;
+use schemars::schema::{ArrayValidation, Schema, SchemaObject, SingleOrVec};
+
+use crate::{
+    cache::ScopeCache,
+    hash::{self, Hash},
+    plugin,
+    schema::{
+        Problem, Query, Scope, ScopeConfig, ScopeConfiguration, ScopeExplicitMapping,
+        ScopeExplicitMappingKind, Source, Target,
+    },
+    script::Script,
+};
+
+/// Claims of the ID token set by Hydra itself, which a scope must not overwrite.
+pub(crate) const RESERVED_CLAIMS: &[&str] = &[
+    "iss",
+    "sub",
+    "aud",
+    "exp",
+    "iat",
+    "nbf",
+    "jti",
+    "auth_time",
+    "nonce",
+    "acr",
+    "amr",
+    "azp",
+    "at_hash",
+    "c_hash",
+    "sid",
+    "rat",
+];
+
+/// Whether the pointer may resolve to a value of traits that are valid against the schema.
+///
+/// Wherever the schema cannot be followed statically (e.g. `additionalProperties`, `$ref` or
+/// combinators), the pointer is assumed to exist.
+fn exists(schema: &Schema, tokens: &[jsonptr::Token]) -> bool {
+    let Some((token, rest)) = tokens.split_first() else {
+        return true;
+    };
+
+    let schema = match schema {
+        Schema::Bool(allowed) => return *allowed,
+        Schema::Object(schema) => schema,
+    };
+
+    if schema.reference.is_some() || schema.subschemas.is_some() {
+        return true;
+    }
+
+    if let Some(object) = &schema.object {
+        if let Some(property) = object.properties.get(token.as_str()) {
+            return exists(property, rest);
+        }
+
+        if object.additional_properties.is_some() || !object.pattern_properties.is_empty() {
+            return true;
+        }
+    }
+
+    if let Some(array) = &schema.array {
+        if let Ok(index) = token.as_str().parse::<usize>() {
+            return exists_item(array, index, rest);
+        }
+    }
+
+    false
+}
+
+fn exists_item(array: &ArrayValidation, index: usize, tokens: &[jsonptr::Token]) -> bool {
+    match &array.items {
+        None => true,
+        Some(SingleOrVec::Single(item)) => exists(item, tokens),
+        Some(SingleOrVec::Vec(items)) => items.get(index).map_or_else(
+            || {
+                array
+                    .additional_items
+                    .as_ref()
+                    .map_or(true, |item| exists(item, tokens))
+            },
+            |item| exists(item, tokens),
+        ),
+    }
+}
+
+fn pointers(mapping: &ScopeExplicitMapping) -> Vec<jsonptr::Pointer> {
+    // only pointers into the traits can be checked against the schema
+    let mut found = match &mapping.kind {
+        ScopeExplicitMappingKind::Path {
+            ref_,
+            source: Source::Traits,
+        }
+        | ScopeExplicitMappingKind::Verified {
+            ref_,
+            source: Source::Traits,
+        } => vec![ref_.0.clone()],
+        ScopeExplicitMappingKind::Template {
+            template,
+            source: Source::Traits,
+        } => template.pointers().cloned().collect(),
+        ScopeExplicitMappingKind::If { when, .. } if when.source == Source::Traits => {
+            vec![when.ref_.0.clone()]
+        }
+        // pointers of the item are relative to an item of the array
+        ScopeExplicitMappingKind::Map {
+            over,
+            source: Source::Traits,
+            item,
+        } => {
+            let mut found = vec![over.0.clone()];
+
+            for pointer in pointers(item) {
+                let mut absolute = over.0.clone();
+                absolute.push_back("0".into());
+                absolute.append(&pointer);
+
+                found.push(absolute);
+            }
+
+            return found;
+        }
+        ScopeExplicitMappingKind::Map { .. } => return vec![],
+        _ => vec![],
+    };
+
+    found.extend(mapping.kind.children().into_iter().flat_map(pointers));
+
+    found
+}
+
+fn transforms(mapping: &ScopeExplicitMapping) -> Vec<&str> {
+    mapping
+        .nodes()
+        .into_iter()
+        .filter_map(|node| match &node.kind {
+            ScopeExplicitMappingKind::Transform { transform, .. } => Some(transform.as_str()),
+            _ => None,
+        })
+        .collect()
+}
+
+fn scripts(mapping: &ScopeExplicitMapping) -> Vec<&Script> {
+    mapping
+        .nodes()
+        .into_iter()
+        .filter_map(|node| match &node.kind {
+            ScopeExplicitMappingKind::Expr { script } => Some(script),
+            _ => None,
+        })
+        .collect()
+}
+
+fn queries(mapping: &ScopeExplicitMapping) -> Vec<&Query> {
+    mapping
+        .nodes()
+        .into_iter()
+        .filter_map(|node| match &node.kind {
+            ScopeExplicitMappingKind::Query { jmespath, .. } => Some(jmespath),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Queries that are not valid `JMESPath`, which always resolve to `null`.
+fn invalid_queries(config: &ScopeConfig, problems: &mut Vec<Problem>) {
+    for (scope, configuration) in &config.scopes {
+        let ScopeConfiguration::Explicit(explicit) = configuration else {
+            continue;
+        };
+
+        for query in queries(&explicit.mapping) {
+            if let Err(error) = query.compiled() {
+                problems.push(Problem::error(
+                    &jsonptr::Pointer::root(),
+                    format!(
+                        "query `{query}` of scope `{}` is not valid JMESPath at column {}: {}",
+                        scope.as_str(),
+                        error.column,
+                        error.reason
+                    ),
+                ));
+            }
+        }
+    }
+}
+
+/// Scripts that do not compile, which always resolve to `null`.
+fn invalid_scripts(config: &ScopeConfig, problems: &mut Vec<Problem>) {
+    for (scope, configuration) in &config.scopes {
+        let ScopeConfiguration::Explicit(explicit) = configuration else {
+            continue;
+        };
+
+        for script in scripts(&explicit.mapping) {
+            if let Err(error) = script.compiled() {
+                problems.push(Problem::error(
+                    &jsonptr::Pointer::root(),
+                    format!(
+                        "script `{script}` of scope `{}` does not compile: {error}",
+                        scope.as_str()
+                    ),
+                ));
+            }
+        }
+    }
+}
+
+/// Constants that cannot be converted to the type they are coerced to, which always resolve to
+/// `null`.
+fn impossible_coercions(config: &ScopeConfig, problems: &mut Vec<Problem>) {
+    for (scope, configuration) in &config.scopes {
+        let ScopeConfiguration::Explicit(explicit) = configuration else {
+            continue;
+        };
+
+        for node in explicit.mapping.nodes() {
+            let (ScopeExplicitMappingKind::Const { value }, Some(coercion)) =
+                (&node.kind, node.coerce)
+            else {
+                continue;
+            };
+
+            if !coercion.is_convertible(value) {
+                problems.push(Problem::error(
+                    &jsonptr::Pointer::root(),
+                    format!(
+                        "constant `{value}` of scope `{}` cannot be coerced to {coercion}",
+                        scope.as_str()
+                    ),
+                ));
+            }
+        }
+    }
+}
+
+/// `hmac` hashes without a key, which always resolve to `null`.
+fn unkeyed_hashes(config: &ScopeConfig, problems: &mut Vec<Problem>) {
+    if hash::is_configured() {
+        return;
+    }
+
+    for (scope, configuration) in &config.scopes {
+        let ScopeConfiguration::Explicit(explicit) = configuration else {
+            continue;
+        };
+
+        if explicit
+            .mapping
+            .nodes()
+            .iter()
+            .any(|node| node.hash == Some(Hash::Hmac))
+        {
+            problems.push(Problem::warning(
+                &jsonptr::Pointer::root(),
+                format!(
+                    "scope `{}` hashes values with `hmac`, but no key is configured (see \
+                     `--hash-key`)",
+                    scope.as_str()
+                ),
+            ));
+        }
+    }
+}
+
+/// Transforms no loaded plugin exports, which always resolve to `null`.
+fn unknown_transforms(config: &ScopeConfig, problems: &mut Vec<Problem>) {
+    for (scope, configuration) in &config.scopes {
+        let ScopeConfiguration::Explicit(explicit) = configuration else {
+            continue;
+        };
+
+        for transform in transforms(&explicit.mapping) {
+            if plugin::is_registered(transform) {
+                continue;
+            }
+
+            problems.push(Problem::warning(
+                &jsonptr::Pointer::root(),
+                format!(
+                    "scope `{}` uses the transform `{transform}`, which is not exported by any \
+                     plugin (see `--plugin`)",
+                    scope.as_str()
+                ),
+            ));
+        }
+    }
+}
+
+/// Explicit mappings that point to properties not present in the schema, which always resolve to
+/// `null`.
+fn missing_properties(config: &ScopeConfig, schema: &SchemaObject, problems: &mut Vec<Problem>) {
+    let schema = Schema::Object(schema.clone());
+
+    for (scope, configuration) in &config.scopes {
+        let ScopeConfiguration::Explicit(explicit) = configuration else {
+            continue;
+        };
+
+        for pointer in pointers(&explicit.mapping) {
+            let tokens: Vec<_> = pointer.tokens().collect();
+
+            if !exists(&schema, &tokens) {
+                problems.push(Problem::warning(
+                    &pointer,
+                    format!(
+                        "scope `{}` references a property that is not present in the schema",
+                        scope.as_str()
+                    ),
+                ));
+            }
+        }
+    }
+
+    for pointer in config.subject.iter().flat_map(pointers) {
+        let tokens: Vec<_> = pointer.tokens().collect();
+
+        if !exists(&schema, &tokens) {
+            problems.push(Problem::warning(
+                &pointer,
+                "the subject references a property that is not present in the schema".to_owned(),
+            ));
+        }
+    }
+}
+
+/// Scopes configured explicitly or filled by a preset, while traits are also annotated with them,
+/// the annotations are ignored.
+fn implicit_and_explicit(config: &ScopeConfig, cache: &ScopeCache, problems: &mut Vec<Problem>) {
+    for scope in cache.implicit_scopes.keys() {
+        let reason = match config.find_scope(scope) {
+            Some(ScopeConfiguration::Explicit(_)) => "is configured explicitly",
+            Some(ScopeConfiguration::Preset(_)) => "is filled by the tagged claims",
+            Some(ScopeConfiguration::Implicit(_)) | None => continue,
+        };
+
+        for pointer in cache.implicit_scopes.get(scope).into_iter().flatten() {
+            problems.push(Problem::warning(
+                pointer,
+                format!(
+                    "scope `{}` {reason}, this annotation is ignored",
+                    scope.as_str()
+                ),
+            ));
+        }
+    }
+}
+
+/// Aliases that are scopes themselves or belong to more than one scope, the alias resolves all of
+/// them.
+fn conflicting_aliases(config: &ScopeConfig, problems: &mut Vec<Problem>) {
+    let mut owners: IndexMap<&Scope, Vec<&Scope>> = IndexMap::new();
+
+    for (scope, configuration) in &config.scopes {
+        for alias in configuration.aliases() {
+            owners.entry(alias).or_default().push(scope);
+        }
+    }
+
+    for (alias, scopes) in owners {
+        let scopes: Vec<_> = scopes
+            .iter()
+            .map(|scope| format!("`{}`", scope.as_str()))
+            .collect();
+
+        let message = if config.scopes.contains_key(alias) {
+            format!(
+                "alias `{}` of {} is also a scope",
+                alias.as_str(),
+                scopes.join(", ")
+            )
+        } else if scopes.len() > 1 {
+            format!(
+                "alias `{}` belongs to the scopes {}",
+                alias.as_str(),
+                scopes.join(", ")
+            )
+        } else {
+            continue;
+        };
+
+        problems.push(Problem::warning(&jsonptr::Pointer::root(), message));
+    }
+}
+
+/// Scopes that are configured, but pass through without claims, e.g. `openid`.
+fn passthrough_scopes(config: &ScopeConfig, problems: &mut Vec<Problem>) {
+    for scope in config.scopes.keys() {
+        if !config.passthrough_scopes.contains(scope) {
+            continue;
+        }
+
+        problems.push(Problem::warning(
+            &jsonptr::Pointer::root(),
+            format!(
+                "scope `{}` passes through without claims, its configuration is ignored",
+                scope.as_str()
+            ),
+        ));
+    }
+}
+
+/// Included scopes that are not configured, they never issue any claims.
+fn unknown_includes(config: &ScopeConfig, problems: &mut Vec<Problem>) {
+    for (scope, configuration) in &config.scopes {
+        for child in configuration.includes() {
+            if config.scopes.contains_key(child) {
+                continue;
+            }
+
+            problems.push(Problem::warning(
+                &jsonptr::Pointer::root(),
+                format!(
+                    "scope `{}` includes the scope `{}`, which is not configured",
+                    scope.as_str(),
+                    child.as_str()
+                ),
+            ));
+        }
+    }
+}
+
+/// Claims written by more than one scope, only one of the values ends up in the token.
+fn duplicate_claims(config: &ScopeConfig, problems: &mut Vec<Problem>) {
+    let mut id_token: IndexMap<&str, Vec<&Scope>> = IndexMap::new();
+    let mut access_token: IndexMap<&str, Vec<&Scope>> = IndexMap::new();
+    let mut userinfo: IndexMap<&str, Vec<&Scope>> = IndexMap::new();
+
+    for (scope, configuration) in &config.scopes {
+        for session_data in configuration.session_data() {
+            if let Some(target) = &session_data.id_token {
+                for claim in target.claims() {
+                    id_token.entry(claim).or_default().push(scope);
+                }
+            }
+
+            if let Some(target) = &session_data.access_token {
+                for claim in target.claims() {
+                    access_token.entry(claim).or_default().push(scope);
+                }
+            }
+
+            if let Some(target) = &session_data.userinfo {
+                for claim in target.claims() {
+                    userinfo.entry(claim).or_default().push(scope);
+                }
+            }
+        }
+    }
+
+    for (token, claims) in [
+        ("id_token", id_token),
+        ("access_token", access_token),
+        ("userinfo", userinfo),
+    ] {
+        for (claim, scopes) in claims {
+            if scopes.len() < 2 {
+                continue;
+            }
+
+            let scopes: Vec<_> = scopes
+                .iter()
+                .map(|scope| format!("`{}`", scope.as_str()))
+                .collect();
+
+            problems.push(Problem::warning(
+                &jsonptr::Pointer::root(),
+                format!(
+                    "claim `{claim}` of the {token} is written by the scopes {}",
+                    scopes.join(", ")
+                ),
+            ));
+        }
+    }
+}
+
+/// Claims that collide with the ones set by Hydra, e.g. a `sub` trait picked up by the direct
+/// mapping. Claims of the access token are nested in `ext` and cannot collide.
+fn reserved_claims(config: &ScopeConfig, problems: &mut Vec<Problem>) {
+    for (scope, configuration) in &config.scopes {
+        for claim in configuration
+            .session_data()
+            .into_iter()
+            .filter_map(|session_data| session_data.id_token.as_ref())
+            .flat_map(Target::claims)
+        {
+            // claims placed in nested objects are reserved if the outermost one is
+            let outermost = claim
+                .strip_prefix('/')
+                .and_then(|pointer| pointer.split('/').next())
+                .unwrap_or(claim);
+
+            if !RESERVED_CLAIMS.contains(&outermost) {
+                continue;
+            }
+
+            problems.push(Problem::warning(
+                &jsonptr::Pointer::root(),
+                format!(
+                    "scope `{}` writes the claim `{claim}` of the id_token, which is reserved by \
+                     OpenID Connect",
+                    scope.as_str()
+                ),
+            ));
+        }
+    }
+}
+
+/// Flag configurations that are well-formed, but most likely not what was intended.
+pub(crate) fn lint(
+    config: &ScopeConfig,
+    cache: &ScopeCache,
+    schema: &SchemaObject,
+    problems: &mut Vec<Problem>,
+) {
+    duplicate_claims(config, problems);
+    conflicting_aliases(config, problems);
+    unknown_includes(config, problems);
+    passthrough_scopes(config, problems);
+    implicit_and_explicit(config, cache, problems);
+    missing_properties(config, schema, problems);
+    reserved_claims(config, problems);
+    unknown_transforms(config, problems);
+    impossible_coercions(config, problems);
+    unkeyed_hashes(config, problems);
+    invalid_scripts(config, problems);
+    invalid_queries(config, problems);
+}
@@ -0,0 +1,212 @@
+use core::{
+    fmt::{self, Display, Formatter},
+    pin::Pin,
+    str::FromStr,
+    task::{Context, Poll},
+};
+use std::{
+    io,
+    net::SocketAddr,
+    os::unix::fs::FileTypeExt,
+    path::{Path, PathBuf},
+};
+
+use axum::{extract::connect_info::Connected, Router};
+use error_stack::{IntoReport, Result, ResultExt};
+use hyper::server::{accept::Accept, conn::AddrStream};
+use listenfd::ListenFd;
+use thiserror::Error;
+use tokio::{
+    net::{UnixListener, UnixStream},
+    task::JoinSet,
+};
+
+#[derive(Debug, Error)]
+pub(crate) enum Error {
+    #[error("unable to bind to {0}")]
+    Bind(Address),
+    #[error("error while serving on {0}")]
+    Serve(Address),
+    #[error("no listening socket at index {0} was passed by systemd")]
+    NotInherited(usize),
+}
+
+#[derive(Debug, Error)]
+#[error("invalid address `{0}`, expected `<ip>:<port>`, `unix:<path>` or `systemd[:<index>]`")]
+pub(crate) struct ParseAddressError(String);
+
+/// Address the server listens on, either `<ip>:<port>`, `unix:<path>` or `systemd[:<index>]` for
+/// a socket passed through systemd socket activation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Address {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+    Systemd(usize),
+}
+
+impl FromStr for Address {
+    type Err = ParseAddressError;
+
+    fn from_str(value: &str) -> core::result::Result<Self, Self::Err> {
+        if let Some(path) = value.strip_prefix("unix:") {
+            return Ok(Self::Unix(PathBuf::from(path)));
+        }
+
+        if value == "systemd" {
+            return Ok(Self::Systemd(0));
+        }
+
+        let address = value.strip_prefix("systemd:").map_or_else(
+            || value.parse().ok().map(Self::Tcp),
+            |index| index.parse().ok().map(Self::Systemd),
+        );
+
+        address.ok_or_else(|| ParseAddressError(value.to_owned()))
+    }
+}
+
+impl Display for Address {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(address) => Display::fmt(address, f),
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+            Self::Systemd(index) => write!(f, "systemd:{index}"),
+        }
+    }
+}
+
+/// Connection info of a request, the remote address is unknown for Unix domain sockets.
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct Peer(pub(crate) Option<SocketAddr>);
+
+impl Connected<&AddrStream> for Peer {
+    fn connect_info(target: &AddrStream) -> Self {
+        Self(Some(target.remote_addr()))
+    }
+}
+
+impl Connected<&UnixStream> for Peer {
+    fn connect_info(_: &UnixStream) -> Self {
+        Self(None)
+    }
+}
+
+struct UnixAcceptor(UnixListener);
+
+impl Accept for UnixAcceptor {
+    type Conn = UnixStream;
+    type Error = io::Error;
+
+    fn poll_accept(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<core::result::Result<Self::Conn, Self::Error>>> {
+        self.0
+            .poll_accept(cx)
+            .map(|result| Some(result.map(|(stream, _)| stream)))
+    }
+}
+
+fn bind_unix(path: &Path) -> io::Result<UnixListener> {
+    // a socket left behind by a previous run would make the bind fail, anything else is kept
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path)?,
+        _ => {}
+    }
+
+    UnixListener::bind(path)
+}
+
+enum Listener {
+    Tcp(std::net::TcpListener),
+    Unix(UnixListener),
+}
+
+/// Take the socket passed by systemd (see `sd_listen_fds(3)`), which may either be a TCP or Unix
+/// domain socket. The sockets are read from the environment once for all addresses, as doing so
+/// removes the variables describing them.
+fn inherit(fds: &mut ListenFd, index: usize) -> Result<Listener, Error> {
+    let listener = match fds.take_tcp_listener(index) {
+        Ok(listener) => listener.map(Listener::Tcp),
+        Err(_) => fds
+            .take_unix_listener(index)
+            .and_then(|listener| {
+                listener
+                    .map(|listener| {
+                        listener.set_nonblocking(true)?;
+                        UnixListener::from_std(listener).map(Listener::Unix)
+                    })
+                    .transpose()
+            })
+            .into_report()
+            .change_context(Error::Bind(Address::Systemd(index)))?,
+    };
+
+    listener.ok_or(Error::NotInherited(index)).into_report()
+}
+
+fn bind(address: &Address, fds: &mut ListenFd) -> Result<Listener, Error> {
+    match address {
+        Address::Tcp(socket) => std::net::TcpListener::bind(socket)
+            .map(Listener::Tcp)
+            .into_report()
+            .change_context_lazy(|| Error::Bind(address.clone())),
+        Address::Unix(path) => bind_unix(path)
+            .map(Listener::Unix)
+            .into_report()
+            .change_context_lazy(|| Error::Bind(address.clone())),
+        Address::Systemd(index) => inherit(fds, *index),
+    }
+}
+
+async fn serve(address: &Address, listener: Listener, router: Router) -> Result<(), Error> {
+    let service = router.into_make_service_with_connect_info::<Peer>();
+
+    tracing::info!(%address, "listening");
+
+    let result = match listener {
+        Listener::Tcp(listener) => {
+            axum::Server::from_tcp(listener)
+                .into_report()
+                .change_context_lazy(|| Error::Bind(address.clone()))?
+                .serve(service)
+                .await
+        }
+        Listener::Unix(listener) => {
+            axum::Server::builder(UnixAcceptor(listener))
+                .serve(service)
+                .await
+        }
+    };
+
+    result
+        .into_report()
+        .change_context_lazy(|| Error::Serve(address.clone()))
+}
+
+/// Serve the router on all addresses concurrently.
+///
+/// Every address is bound before any connection is accepted, so that a single misconfigured
+/// address does not leave the server partially reachable. Returns once any of the servers fails.
+pub(crate) async fn serve_all(addresses: &[Address], router: Router) -> Result<(), Error> {
+    let mut fds = ListenFd::from_env();
+
+    let listeners = addresses
+        .iter()
+        .map(|address| bind(address, &mut fds).map(|listener| (address.clone(), listener)))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut servers = JoinSet::new();
+
+    for (address, listener) in listeners {
+        let router = router.clone();
+
+        servers.spawn(async move { serve(&address, listener, router).await });
+    }
+
+    match servers.join_next().await {
+        Some(Ok(result)) => result,
+        Some(Err(error)) => std::panic::resume_unwind(error.into_panic()),
+        None => Ok(()),
+    }
+}
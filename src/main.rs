@@ -1,65 +1,21 @@
-use std::net::SocketAddr;
+use std::process::ExitCode;
 
-use clap::{Parser, Subcommand};
-use error_stack::{Result, ResultExt};
-use thiserror::Error;
 use tracing_subscriber::EnvFilter;
-use url::Url;
 
-use crate::serve::Config;
-
-mod cache;
-mod schema;
-mod serve;
-mod validate;
-
-#[derive(Debug, Error)]
-#[error("application error")]
-pub struct Error;
-
-#[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
-struct Args {
-    #[clap(long, env)]
-    kratos_admin_url: Url,
-
-    #[clap(long, env)]
-    hydra_admin_url: Url,
-
-    #[clap(long, env)]
-    direct_mapping: bool,
-
-    #[clap(long, env, default_value = "indietyp/consent")]
-    keyword: String,
-
-    #[command(subcommand)]
-    command: Command,
-}
-
-#[derive(Subcommand, Debug)]
-enum Command {
-    Serve { addr: SocketAddr },
-    Validate { schema: String },
-}
+// every thread contends on the same counters, which is why only binaries built for `bench`
+// count allocations
+#[cfg(feature = "alloc-stats")]
+#[global_allocator]
+static ALLOCATOR: &stats_alloc::StatsAlloc<std::alloc::System> = &stats_alloc::INSTRUMENTED_SYSTEM;
 
 #[tokio::main]
-async fn main() -> Result<(), Error> {
+async fn main() -> ExitCode {
+    // stdout is the output of the commands, e.g. the CSV of `report`
     tracing_subscriber::fmt()
         .pretty()
         .with_env_filter(EnvFilter::from_default_env())
+        .with_writer(std::io::stderr)
         .init();
 
-    let cli = Args::parse();
-
-    let config = Config {
-        kratos_url: cli.kratos_admin_url,
-        hydra_url: cli.hydra_admin_url,
-        direct_mapping: cli.direct_mapping,
-        keyword: cli.keyword,
-    };
-
-    match cli.command {
-        Command::Serve { addr } => serve::run(addr, config).await.change_context(Error),
-        Command::Validate { schema } => validate::run(schema, config).await.change_context(Error),
-    }
+    hydra_kratos_consent::cli::run().await
 }
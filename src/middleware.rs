@@ -0,0 +1,744 @@
+use alloc::{borrow::Cow, sync::Arc};
+use core::time::Duration;
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    sync::Mutex,
+    time::Instant,
+};
+
+use axum::{
+    error_handling::HandleErrorLayer,
+    extract::{ConnectInfo, State},
+    http::{
+        header::{
+            HeaderName, AUTHORIZATION, CACHE_CONTROL, CONTENT_SECURITY_POLICY, REFERRER_POLICY,
+            RETRY_AFTER, WWW_AUTHENTICATE, X_CONTENT_TYPE_OPTIONS, X_FRAME_OPTIONS,
+        },
+        HeaderValue, Request, StatusCode,
+    },
+    middleware::Next,
+    response::{IntoResponse, Response},
+    BoxError, Extension, Router,
+};
+use clap::ValueHint;
+use error_stack::Result;
+use ipnet::IpNet;
+use tokio::{sync::Semaphore, time::MissedTickBehavior};
+use tower::{limit::GlobalConcurrencyLimitLayer, load_shed::error::Overloaded, ServiceBuilder};
+use tower_http::{request_id::RequestId, set_header::SetResponseHeaderLayer};
+
+use crate::{
+    listen::Peer,
+    secret::{self, Secret},
+};
+
+#[derive(Debug, Clone, clap::Args)]
+pub(crate) struct LimitConfig {
+    /// Maximum number of consent and logout requests processed concurrently, additional requests
+    /// are rejected with `429 Too Many Requests`
+    #[clap(long, env)]
+    pub(crate) max_concurrent_requests: Option<usize>,
+
+    /// Value of the `Retry-After` header sent with rejected requests
+    #[clap(
+        long,
+        env,
+        default_value = "1s",
+        value_parser = humantime::parse_duration
+    )]
+    pub(crate) overload_retry_after: Duration,
+}
+
+fn overloaded(error: &BoxError, retry_after: Duration) -> Response {
+    if error.is::<Overloaded>() {
+        metrics::increment_counter!("http_requests_shed_total", "tenant" => tenant());
+
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            [(RETRY_AFTER, retry_after.as_secs().max(1).to_string())],
+            "too many concurrent requests",
+        )
+            .into_response();
+    }
+
+    tracing::error!(%error, "unhandled middleware error");
+    StatusCode::INTERNAL_SERVER_ERROR.into_response()
+}
+
+impl LimitConfig {
+    pub(crate) fn limit(&self) -> Limit {
+        Limit {
+            semaphore: self
+                .max_concurrent_requests
+                .map(|max| Arc::new(Semaphore::new(max))),
+            retry_after: self.overload_retry_after,
+        }
+    }
+}
+
+/// Limit of [`LimitConfig`], every router it is applied to shares the same permits, as
+/// `Router::layer` wraps each route on its own.
+#[derive(Debug, Clone)]
+pub(crate) struct Limit {
+    semaphore: Option<Arc<Semaphore>>,
+    retry_after: Duration,
+}
+
+impl Limit {
+    /// Shed load once the configured number of requests is in flight, instead of queueing them.
+    pub(crate) fn apply<S>(&self, router: Router<S>) -> Router<S>
+    where
+        S: Clone + Send + Sync + 'static,
+    {
+        let Some(semaphore) = &self.semaphore else {
+            return router;
+        };
+
+        let retry_after = self.retry_after;
+
+        router.layer(
+            ServiceBuilder::new()
+                .layer(HandleErrorLayer::new(move |error: BoxError| async move {
+                    overloaded(&error, retry_after)
+                }))
+                .load_shed()
+                .layer(GlobalConcurrencyLimitLayer::with_semaphore(Arc::clone(
+                    semaphore,
+                ))),
+        )
+    }
+}
+
+/// Address of the client that sent the request, inserted into the request extensions by
+/// [`client_ip`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct ClientIp(pub(crate) IpAddr);
+
+#[derive(Debug, Clone, clap::Args)]
+pub(crate) struct ClientIpConfig {
+    /// Use the `X-Forwarded-For` entry appended by the trusted proxies as the client address, only
+    /// enable this if the service is exclusively reachable through a proxy that sets the header
+    #[clap(long, env)]
+    pub(crate) trust_forwarded_for: bool,
+
+    /// Number of trusted proxies in front of the service, the client address is the entry this
+    /// many positions from the right of `X-Forwarded-For`, as the entries before it are written
+    /// by the client itself
+    #[clap(long, env, default_value_t = 1, requires = "trust_forwarded_for")]
+    pub(crate) trusted_proxy_hops: usize,
+}
+
+/// Unwrap IPv4-mapped IPv6 addresses (as seen when listening on `[::]`), so that they are treated
+/// the same as their IPv4 counterpart.
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        IpAddr::V4(_) => ip,
+    }
+}
+
+/// Address of the peer, as recorded by `serve` or by the application the router is mounted in,
+/// `None` if the application records none.
+fn peer<B>(request: &Request<B>) -> Option<IpAddr> {
+    let extensions = request.extensions();
+
+    match extensions.get::<ConnectInfo<Peer>>() {
+        // Unix domain sockets have no peer address, but are only reachable from the local machine
+        Some(ConnectInfo(Peer(address))) => {
+            Some(address.map_or(IpAddr::V4(Ipv4Addr::LOCALHOST), |address| address.ip()))
+        }
+        None => extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|info| info.0.ip()),
+    }
+}
+
+/// Entry of `X-Forwarded-For` the outermost of the trusted proxies appended, `None` if the header
+/// has fewer entries than there are trusted proxies.
+fn forwarded_for<B>(request: &Request<B>, hops: usize) -> Option<IpAddr> {
+    request
+        .headers()
+        .get("x-forwarded-for")?
+        .to_str()
+        .ok()?
+        .rsplit(',')
+        .nth(hops.max(1) - 1)?
+        .trim()
+        .parse()
+        .ok()
+}
+
+pub(crate) async fn client_ip<B: Send>(
+    State(config): State<ClientIpConfig>,
+    mut request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let ip = config
+        .trust_forwarded_for
+        .then(|| forwarded_for(&request, config.trusted_proxy_hops))
+        .flatten()
+        .or_else(|| peer(&request))
+        .map(canonical);
+
+    if let Some(ip) = ip {
+        request.extensions_mut().insert(ClientIp(ip));
+    }
+
+    next.run(request).await
+}
+
+/// Rejection of the middleware that depends on the address of the client, if there is none, as
+/// letting the request through would make every client the same one.
+fn unknown_client() -> Response {
+    tracing::error!(
+        "address of the client is unknown, serve the router with \
+         `into_make_service_with_connect_info::<SocketAddr>()`"
+    );
+
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "address of the client is unknown",
+    )
+        .into_response()
+}
+
+#[derive(Debug, Clone, clap::Args)]
+pub(crate) struct RateLimitConfig {
+    /// Sustained number of consent, logout and userinfo requests a single client may send per
+    /// minute, across all of them
+    #[clap(long, env)]
+    pub(crate) rate_limit: Option<u32>,
+
+    /// Number of requests a single client may send in a burst before the rate limit applies
+    #[clap(long, env, default_value_t = 10, requires = "rate_limit")]
+    pub(crate) rate_limit_burst: u32,
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token bucket per client address.
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    /// tokens per second
+    rate: f64,
+    burst: f64,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+impl RateLimiter {
+    // clients beyond this many are rejected until the next sweep made room for them
+    const CAPACITY: usize = 65_536;
+    const SWEEP_PERIOD: Duration = Duration::from_secs(10);
+
+    fn new(config: &RateLimitConfig, per_minute: u32) -> Self {
+        Self {
+            rate: f64::from(per_minute) / 60.0,
+            burst: f64::from(config.rate_limit_burst.max(1)),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Take a token for the client, returns how long to wait if none is available.
+    fn acquire(&self, ip: IpAddr) -> core::result::Result<(), Duration> {
+        let now = Instant::now();
+        let mut buckets = self
+            .buckets
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+
+        if buckets.len() >= Self::CAPACITY && !buckets.contains_key(&ip) {
+            return Err(Self::SWEEP_PERIOD);
+        }
+
+        let bucket = buckets.entry(ip).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = elapsed.mul_add(self.rate, bucket.tokens).min(self.burst);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate))
+        }
+    }
+
+    /// Evict the clients whose bucket is full again, as they are treated the same as untracked
+    /// ones.
+    fn sweep(&self) {
+        let now = Instant::now();
+        let (rate, burst) = (self.rate, self.burst);
+
+        self.buckets
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .retain(|_, bucket| {
+                let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+
+                elapsed.mul_add(rate, bucket.tokens) < burst
+            });
+    }
+
+    /// Sweep in the background, instead of on the request path, for as long as the limiter is
+    /// in use.
+    fn spawn_sweeper(limiter: &Arc<Self>) {
+        let limiter = Arc::downgrade(limiter);
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Self::SWEEP_PERIOD);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+            loop {
+                interval.tick().await;
+
+                let Some(limiter) = limiter.upgrade() else {
+                    break;
+                };
+
+                limiter.sweep();
+            }
+        });
+    }
+}
+
+async fn rate_limit<B: Send>(
+    State(limiter): State<Arc<RateLimiter>>,
+    client: Option<Extension<ClientIp>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let Some(Extension(ClientIp(ip))) = client else {
+        return unknown_client();
+    };
+
+    match limiter.acquire(ip) {
+        Ok(()) => next.run(request).await,
+        Err(wait) => {
+            metrics::increment_counter!("http_requests_rate_limited_total", "tenant" => tenant());
+            tracing::debug!(%ip, ?wait, "client exceeded rate limit");
+
+            (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(RETRY_AFTER, wait.as_secs().max(1).to_string())],
+                "rate limit exceeded",
+            )
+                .into_response()
+        }
+    }
+}
+
+impl RateLimitConfig {
+    pub(crate) fn limit(&self) -> RateLimit {
+        let limiter = self
+            .rate_limit
+            .filter(|limit| *limit > 0)
+            .map(|per_minute| Arc::new(RateLimiter::new(self, per_minute)));
+
+        if let Some(limiter) = &limiter {
+            RateLimiter::spawn_sweeper(limiter);
+        }
+
+        RateLimit { limiter }
+    }
+}
+
+/// Rate limit of [`RateLimitConfig`], every router it is applied to shares the same buckets, so
+/// that a client is limited across all of them.
+#[derive(Debug, Clone)]
+pub(crate) struct RateLimit {
+    limiter: Option<Arc<RateLimiter>>,
+}
+
+impl RateLimit {
+    pub(crate) fn apply<S>(&self, router: Router<S>) -> Router<S>
+    where
+        S: Clone + Send + Sync + 'static,
+    {
+        let Some(limiter) = &self.limiter else {
+            return router;
+        };
+
+        router.layer(axum::middleware::from_fn_with_state(
+            Arc::clone(limiter),
+            rate_limit,
+        ))
+    }
+}
+
+#[derive(Debug, Clone, clap::Args)]
+pub(crate) struct AllowlistConfig {
+    /// Networks (in CIDR notation) clients must be part of, requests from any other address are
+    /// rejected with `403 Forbidden`, if empty all clients are allowed
+    #[clap(long, env, value_delimiter = ',')]
+    pub(crate) allowed_networks: Vec<IpNet>,
+}
+
+async fn allowlist<B: Send>(
+    State(networks): State<Arc<[IpNet]>>,
+    client: Option<Extension<ClientIp>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let Some(Extension(ClientIp(ip))) = client else {
+        return unknown_client();
+    };
+
+    if networks.iter().any(|network| network.contains(&ip)) {
+        return next.run(request).await;
+    }
+
+    metrics::increment_counter!("http_requests_forbidden_total");
+    tracing::debug!(%ip, "client is not part of an allowed network");
+
+    (StatusCode::FORBIDDEN, "client address is not allowed").into_response()
+}
+
+impl AllowlistConfig {
+    pub(crate) fn apply<S>(&self, router: Router<S>) -> Router<S>
+    where
+        S: Clone + Send + Sync + 'static,
+    {
+        if self.allowed_networks.is_empty() {
+            return router;
+        }
+
+        let networks: Arc<[IpNet]> = self.allowed_networks.clone().into();
+
+        router.layer(axum::middleware::from_fn_with_state(networks, allowlist))
+    }
+}
+
+#[derive(Debug, Clone, clap::Args)]
+pub(crate) struct EndpointTokenConfig {
+    /// Token that must be presented with consent and logout requests, either as
+    /// `X-Endpoint-Token` header or `endpoint_token` query parameter
+    #[clap(long, env, hide_env_values = true)]
+    pub(crate) endpoint_token: Option<Secret>,
+
+    /// File containing the endpoint token
+    #[clap(
+        long,
+        env,
+        conflicts_with = "endpoint_token",
+        value_hint = ValueHint::FilePath
+    )]
+    pub(crate) endpoint_token_file: Option<PathBuf>,
+}
+
+fn presented_token<B>(request: &Request<B>) -> Option<Cow<'_, str>> {
+    if let Some(header) = request.headers().get("x-endpoint-token") {
+        return header.to_str().ok().map(Cow::Borrowed);
+    }
+
+    url::form_urlencoded::parse(request.uri().query()?.as_bytes())
+        .find(|(key, _)| key == "endpoint_token")
+        .map(|(_, value)| value)
+}
+
+async fn endpoint_token<B: Send>(
+    State(token): State<Arc<Secret>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    if presented_token(&request).map_or(false, |presented| token.matches(&presented)) {
+        return next.run(request).await;
+    }
+
+    metrics::increment_counter!("http_requests_unauthorized_total", "tenant" => tenant());
+
+    (
+        StatusCode::UNAUTHORIZED,
+        "missing or invalid endpoint token",
+    )
+        .into_response()
+}
+
+impl EndpointTokenConfig {
+    pub(crate) fn apply<S>(&self, router: Router<S>) -> Result<Router<S>, secret::Error>
+    where
+        S: Clone + Send + Sync + 'static,
+    {
+        let token = Secret::resolve(
+            self.endpoint_token.clone(),
+            self.endpoint_token_file.as_deref(),
+        )?;
+
+        let Some(token) = token else {
+            return Ok(router);
+        };
+
+        Ok(router.layer(axum::middleware::from_fn_with_state(
+            Arc::new(token),
+            endpoint_token,
+        )))
+    }
+}
+
+#[derive(Debug, Clone, clap::Args)]
+pub(crate) struct AdminTokenConfig {
+    /// Token that must be presented as bearer token to the admin API (`/admin/consents`), which is
+    /// only served if it is set
+    #[clap(long, env, hide_env_values = true)]
+    pub(crate) admin_token: Option<Secret>,
+
+    /// File containing the admin token
+    #[clap(
+        long,
+        env,
+        conflicts_with = "admin_token",
+        value_hint = ValueHint::FilePath
+    )]
+    pub(crate) admin_token_file: Option<PathBuf>,
+}
+
+/// Token that must be presented as bearer token, named in the response if it is not.
+#[derive(Debug)]
+struct Bearer {
+    token: Secret,
+    name: &'static str,
+}
+
+async fn bearer_token<B: Send>(
+    State(bearer): State<Arc<Bearer>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let presented = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    if presented.map_or(false, |presented| bearer.token.matches(presented)) {
+        return next.run(request).await;
+    }
+
+    metrics::increment_counter!("http_requests_unauthorized_total", "tenant" => tenant());
+
+    (
+        StatusCode::UNAUTHORIZED,
+        [(WWW_AUTHENTICATE, "Bearer")],
+        format!("missing or invalid {} token", bearer.name),
+    )
+        .into_response()
+}
+
+/// Require the bearer token on every route of the router, `None` without a token.
+fn require_bearer<S>(
+    router: Router<S>,
+    token: Option<Secret>,
+    name: &'static str,
+) -> Option<Router<S>>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    token.map(|token| {
+        router.layer(axum::middleware::from_fn_with_state(
+            Arc::new(Bearer { token, name }),
+            bearer_token,
+        ))
+    })
+}
+
+impl AdminTokenConfig {
+    /// Require the admin token on every route of the router, `None` without a token, as the admin
+    /// API is not served then.
+    pub(crate) fn apply<S>(&self, router: Router<S>) -> Result<Option<Router<S>>, secret::Error>
+    where
+        S: Clone + Send + Sync + 'static,
+    {
+        let token = Secret::resolve(self.admin_token.clone(), self.admin_token_file.as_deref())?;
+
+        Ok(require_bearer(router, token, "admin"))
+    }
+}
+
+#[derive(Debug, Clone, clap::Args)]
+pub(crate) struct TokenHookTokenConfig {
+    /// Token Hydra must present as bearer token to the token hook (`/token-hook`), which is only
+    /// served if it is set, as it returns the claims of any subject it is asked for
+    #[clap(long, env, hide_env_values = true)]
+    pub(crate) token_hook_token: Option<Secret>,
+
+    /// File containing the token hook token
+    #[clap(
+        long,
+        env,
+        conflicts_with = "token_hook_token",
+        value_hint = ValueHint::FilePath
+    )]
+    pub(crate) token_hook_token_file: Option<PathBuf>,
+}
+
+impl TokenHookTokenConfig {
+    /// Require the token hook token on every route of the router, `None` without a token, as the
+    /// token hook is not served then.
+    pub(crate) fn apply<S>(&self, router: Router<S>) -> Result<Option<Router<S>>, secret::Error>
+    where
+        S: Clone + Send + Sync + 'static,
+    {
+        let token = Secret::resolve(
+            self.token_hook_token.clone(),
+            self.token_hook_token_file.as_deref(),
+        )?;
+
+        Ok(require_bearer(router, token, "token hook"))
+    }
+}
+
+#[allow(clippy::std_instead_of_core)] // Reason: expansion of `task_local!`
+mod local {
+    tokio::task_local! {
+        pub(super) static REQUEST_ID: String;
+        pub(super) static TENANT: String;
+    }
+}
+
+/// Identifier of the request currently being handled, if any.
+pub(crate) fn request_id() -> Option<String> {
+    local::REQUEST_ID.try_with(Clone::clone).ok()
+}
+
+/// Tenant the request is handled for, empty for the top-level options.
+pub(crate) fn tenant() -> String {
+    local::TENANT.try_with(Clone::clone).unwrap_or_default()
+}
+
+/// Handle the request for the tenant, which labels the metrics and log events recorded meanwhile.
+pub(crate) async fn scope_tenant<B: Send>(
+    tenant: String,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    tracing::Span::current().record("tenant", tenant.as_str());
+
+    local::TENANT.scope(tenant, next.run(request)).await
+}
+
+fn request_id_of<B>(request: &Request<B>) -> Option<String> {
+    request
+        .extensions()
+        .get::<RequestId>()
+        .and_then(|id| id.header_value().to_str().ok())
+        .map(ToOwned::to_owned)
+}
+
+/// URI of the request as logged, with the value of an `endpoint_token` query parameter replaced,
+/// as the query string of the consent URL is the only place Hydra can put the token in.
+fn logged_uri<B>(request: &Request<B>) -> String {
+    let uri = request.uri();
+
+    let Some(query) = uri.query() else {
+        return uri.to_string();
+    };
+
+    let pairs = url::form_urlencoded::parse(query.as_bytes());
+
+    if !pairs.clone().any(|(key, _)| key == "endpoint_token") {
+        return uri.to_string();
+    }
+
+    let query = url::form_urlencoded::Serializer::new(String::new())
+        .extend_pairs(pairs.map(|(key, value)| {
+            let value = if key == "endpoint_token" {
+                Cow::Borrowed("redacted")
+            } else {
+                value
+            };
+
+            (key, value)
+        }))
+        .finish();
+
+    format!("{}?{query}", uri.path())
+}
+
+pub(crate) fn make_span<B>(request: &Request<B>) -> tracing::Span {
+    tracing::info_span!(
+        "request",
+        method = %request.method(),
+        uri = %logged_uri(request),
+        request_id = request_id_of(request).as_deref().unwrap_or_default(),
+        tenant = tracing::field::Empty,
+    )
+}
+
+/// Make the request id (set by `SetRequestIdLayer`) available to everything that runs while
+/// handling the request, e.g. calls to upstreams and error responses.
+pub(crate) async fn scope_request_id<B: Send>(request: Request<B>, next: Next<B>) -> Response {
+    let id = request_id_of(&request);
+
+    match id {
+        Some(id) => local::REQUEST_ID.scope(id, next.run(request)).await,
+        None => next.run(request).await,
+    }
+}
+
+/// Headers added to every response unless the handler already set them, an empty value disables
+/// the respective header.
+#[derive(Debug, Clone, clap::Args)]
+pub(crate) struct SecurityHeadersConfig {
+    /// Value of the `Content-Security-Policy` header
+    #[clap(
+        long,
+        env,
+        default_value = "default-src 'none'; frame-ancestors 'none'"
+    )]
+    pub(crate) content_security_policy: String,
+
+    /// Value of the `X-Frame-Options` header
+    #[clap(long, env, default_value = "DENY")]
+    pub(crate) frame_options: String,
+
+    /// Value of the `Referrer-Policy` header
+    #[clap(long, env, default_value = "no-referrer")]
+    pub(crate) referrer_policy: String,
+
+    /// Value of the `Cache-Control` header
+    #[clap(long, env, default_value = "no-store")]
+    pub(crate) cache_control: String,
+}
+
+impl SecurityHeadersConfig {
+    fn headers(&self) -> impl Iterator<Item = (HeaderName, HeaderValue)> + '_ {
+        [
+            (CONTENT_SECURITY_POLICY, &self.content_security_policy),
+            (X_FRAME_OPTIONS, &self.frame_options),
+            (REFERRER_POLICY, &self.referrer_policy),
+            (CACHE_CONTROL, &self.cache_control),
+        ]
+        .into_iter()
+        .map(|(name, value)| (name, value.as_str()))
+        .chain([(X_CONTENT_TYPE_OPTIONS, "nosniff")])
+        .filter(|(_, value)| !value.is_empty())
+        .filter_map(|(name, value)| match HeaderValue::from_str(value) {
+            Ok(value) => Some((name, value)),
+            Err(error) => {
+                tracing::warn!(%name, %error, "ignoring invalid security header");
+
+                None
+            }
+        })
+    }
+
+    pub(crate) fn apply<S>(&self, mut router: Router<S>) -> Router<S>
+    where
+        S: Clone + Send + Sync + 'static,
+    {
+        for (name, value) in self.headers() {
+            router = router.layer(SetResponseHeaderLayer::if_not_present(name, value));
+        }
+
+        router
+    }
+}
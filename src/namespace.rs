@@ -0,0 +1,63 @@
+use ory_hydra_client::models::OAuth2Client;
+use serde_json::{Map, Value};
+
+use crate::schema;
+
+/// Key in the metadata of a client that overrides `--claim-namespace` for its tokens.
+const METADATA_KEY: &str = "claim_namespace";
+
+#[derive(Debug, Clone, clap::Args)]
+pub(crate) struct NamespaceConfig {
+    /// Prefix of every claim that is not a standard claim of OpenID Connect, e.g.
+    /// `https://example.com/` turns `roles` into `https://example.com/roles`. Clients override it
+    /// with `claim_namespace` in their metadata, an empty one disables it
+    #[clap(long, env)]
+    pub(crate) claim_namespace: Option<String>,
+}
+
+impl NamespaceConfig {
+    fn namespace<'a>(&'a self, client: Option<&'a OAuth2Client>) -> Option<&'a str> {
+        let overridden = client
+            .and_then(|client| client.metadata.as_ref())
+            .and_then(|metadata| metadata.get(METADATA_KEY))
+            .and_then(Value::as_str);
+
+        overridden
+            .or(self.claim_namespace.as_deref())
+            .filter(|namespace| !namespace.is_empty())
+    }
+
+    /// Prefix the custom claims of a token with the namespace of the client, the claims of the
+    /// object under `nested` (the `--access-token-key`) are prefixed instead of its key.
+    pub(crate) fn apply(
+        &self,
+        client: Option<&OAuth2Client>,
+        claims: Value,
+        nested: Option<&str>,
+    ) -> Value {
+        let Some(namespace) = self.namespace(client) else {
+            return claims;
+        };
+
+        let Value::Object(claims) = claims else {
+            return claims;
+        };
+
+        Value::Object(prefix(namespace, claims, nested))
+    }
+}
+
+fn prefix(namespace: &str, claims: Map<String, Value>, nested: Option<&str>) -> Map<String, Value> {
+    claims
+        .into_iter()
+        .map(|(claim, value)| match value {
+            Value::Object(inner) if nested == Some(claim.as_str()) => {
+                (claim, Value::Object(prefix(namespace, inner, None)))
+            }
+            value if schema::is_standard_claim(&claim) || claim.starts_with(namespace) => {
+                (claim, value)
+            }
+            value => (format!("{namespace}{claim}"), value),
+        })
+        .collect()
+}
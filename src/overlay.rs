@@ -0,0 +1,278 @@
+use core::{
+    convert::Infallible,
+    fmt::{self, Display, Formatter},
+    str::FromStr,
+    time::Duration,
+};
+use std::path::{Path, PathBuf};
+
+use error_stack::{IntoReport, Result, ResultExt};
+use indexmap::IndexMap;
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use reqwest::{
+    header::{HeaderValue, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED},
+    StatusCode,
+};
+use serde_json::Value;
+use thiserror::Error;
+use tokio::sync::mpsc;
+use url::Url;
+
+use crate::schema::ScopeConfig;
+
+#[derive(Debug, Clone, Error)]
+pub(crate) enum Error {
+    #[error("unable to read the scope configuration from {0}")]
+    Read(Location),
+    #[error("scope configuration in {0} is malformed")]
+    Malformed(Location),
+    #[error("unable to watch the scope configuration in {0}")]
+    Watch(Location),
+}
+
+/// Where the scope configuration overlaying the one of the identity schemas is read from, a URL
+/// (`http://` or `https://`) or a local file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Location {
+    Url(Url),
+    File(PathBuf),
+}
+
+impl FromStr for Location {
+    type Err = Infallible;
+
+    fn from_str(value: &str) -> core::result::Result<Self, Self::Err> {
+        Ok(Url::parse(value)
+            .ok()
+            .filter(|url| matches!(url.scheme(), "http" | "https"))
+            .map_or_else(|| Self::File(PathBuf::from(value)), Self::Url))
+    }
+}
+
+impl Display for Location {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Url(url) => Display::fmt(url, f),
+            Self::File(path) => Display::fmt(&path.display(), f),
+        }
+    }
+}
+
+impl Location {
+    async fn read(&self, client: &reqwest::Client) -> Result<Value, Error> {
+        match self {
+            Self::Url(url) => client
+                .get(url.clone())
+                .send()
+                .await
+                .and_then(reqwest::Response::error_for_status)
+                .into_report()
+                .change_context_lazy(|| Error::Read(self.clone()))?
+                .json()
+                .await
+                .into_report()
+                .change_context_lazy(|| Error::Malformed(self.clone())),
+            Self::File(path) => read_file(self, path).await,
+        }
+    }
+}
+
+async fn read_file(location: &Location, path: &Path) -> Result<Value, Error> {
+    let contents = tokio::fs::read(path)
+        .await
+        .into_report()
+        .change_context_lazy(|| Error::Read(location.clone()))?;
+
+    serde_json::from_slice(&contents)
+        .into_report()
+        .change_context_lazy(|| Error::Malformed(location.clone()))
+}
+
+/// Scope configurations by the ID of the identity schema they overlay, the one of `*` overlays
+/// every identity schema without one of its own (and local files).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct Overlays(IndexMap<String, ScopeConfig>);
+
+impl Overlays {
+    const ANY: &'static str = "*";
+
+    /// Load the overlays, a malformed document is an error, as it would silently change claims.
+    pub(crate) async fn load(location: &Location, client: &reqwest::Client) -> Result<Self, Error> {
+        let document = location.read(client).await?;
+
+        Self::parse(location, document)
+    }
+
+    fn parse(location: &Location, document: Value) -> Result<Self, Error> {
+        let overlays = serde_json::from_value(document)
+            .into_report()
+            .change_context_lazy(|| Error::Malformed(location.clone()))?;
+
+        Ok(Self(overlays))
+    }
+
+    /// Overlay of the identity schema with the ID, local files only get the one of `*`.
+    pub(crate) fn get(&self, id: Option<&str>) -> Option<&ScopeConfig> {
+        id.and_then(|id| self.0.get(id))
+            .or_else(|| self.0.get(Self::ANY))
+    }
+}
+
+/// Scope configuration at a URL, fetched again only if it changed since the last good version.
+pub(crate) struct Remote {
+    location: Location,
+    client: reqwest::Client,
+    etag: Option<HeaderValue>,
+    last_modified: Option<HeaderValue>,
+}
+
+impl Remote {
+    pub(crate) const fn new(url: Url, client: reqwest::Client) -> Self {
+        Self {
+            location: Location::Url(url),
+            client,
+            etag: None,
+            last_modified: None,
+        }
+    }
+
+    async fn fetch(&mut self) -> Result<Option<Overlays>, Error> {
+        let Location::Url(url) = &self.location else {
+            return Ok(None);
+        };
+
+        let mut request = self.client.get(url.clone());
+
+        if let Some(etag) = &self.etag {
+            request = request.header(IF_NONE_MATCH, etag.clone());
+        }
+
+        if let Some(last_modified) = &self.last_modified {
+            request = request.header(IF_MODIFIED_SINCE, last_modified.clone());
+        }
+
+        let response = request
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .into_report()
+            .change_context_lazy(|| Error::Read(self.location.clone()))?;
+
+        if response.status() == StatusCode::NOT_MODIFIED {
+            return Ok(None);
+        }
+
+        let etag = response.headers().get(ETAG).cloned();
+        let last_modified = response.headers().get(LAST_MODIFIED).cloned();
+
+        let document = response
+            .json()
+            .await
+            .into_report()
+            .change_context_lazy(|| Error::Malformed(self.location.clone()))?;
+        let overlays = Overlays::parse(&self.location, document)?;
+
+        // only a good version is revalidated, a malformed one is fetched again
+        self.etag = etag;
+        self.last_modified = last_modified;
+
+        Ok(Some(overlays))
+    }
+
+    /// Fetch the configuration if it changed, `None` if it did not or cannot be fetched, in which
+    /// case the last good version stays in use.
+    pub(crate) async fn poll(&mut self) -> Option<Overlays> {
+        match self.fetch().await {
+            Ok(overlays) => overlays,
+            Err(report) => {
+                tracing::warn!(
+                    ?report,
+                    "unable to refresh the scope configuration, the last good version stays in use"
+                );
+
+                None
+            }
+        }
+    }
+}
+
+/// Editors save a file in several steps, events within this window are handled as one change.
+const SETTLE: Duration = Duration::from_millis(100);
+
+/// Scope configuration in a local file, loaded again whenever it is written.
+pub(crate) struct Watch {
+    location: Location,
+    path: PathBuf,
+    // dropping the watcher stops the events
+    _watcher: RecommendedWatcher,
+    events: mpsc::UnboundedReceiver<()>,
+}
+
+impl Watch {
+    pub(crate) fn new(path: &Path) -> Result<Self, Error> {
+        let location = Location::File(path.to_owned());
+        let name = path.file_name().map(ToOwned::to_owned);
+
+        let (sender, events) = mpsc::unbounded_channel();
+        let mut watcher =
+            notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+                let Ok(event) = event else {
+                return;
+            };
+
+                let affected = event
+                    .paths
+                    .iter()
+                    .any(|changed| changed.file_name() == name.as_deref());
+
+                if affected && (event.kind.is_create() || event.kind.is_modify()) {
+                    // the receiver is only gone once serving stopped
+                    let _ = sender.send(());
+                }
+            })
+            .into_report()
+            .change_context_lazy(|| Error::Watch(location.clone()))?;
+
+        // editors commonly replace the file instead of writing to it, which is only seen by
+        // watching its directory
+        let directory = path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+            .unwrap_or_else(|| Path::new("."));
+
+        watcher
+            .watch(directory, RecursiveMode::NonRecursive)
+            .into_report()
+            .change_context_lazy(|| Error::Watch(location.clone()))?;
+
+        Ok(Self {
+            location,
+            path: path.to_owned(),
+            _watcher: watcher,
+            events,
+        })
+    }
+
+    /// Wait until the file changed and load it, a version that cannot be loaded is skipped and the
+    /// last good version stays in use. `None` once the file is no longer watched.
+    pub(crate) async fn changed(&mut self) -> Option<Overlays> {
+        loop {
+            self.events.recv().await?;
+
+            tokio::time::sleep(SETTLE).await;
+            while self.events.try_recv().is_ok() {}
+
+            let overlays = read_file(&self.location, &self.path)
+                .await
+                .and_then(|document| Overlays::parse(&self.location, document));
+
+            match overlays {
+                Ok(overlays) => return Some(overlays),
+                Err(report) => tracing::warn!(
+                    ?report,
+                    "unable to reload the scope configuration, the last good version stays in use"
+                ),
+            }
+        }
+    }
+}
@@ -0,0 +1,202 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+use error_stack::{Report, Result};
+use serde_json::Value;
+use thiserror::Error;
+use tokio::runtime::{Handle, RuntimeFlavor};
+use wasmtime::{
+    Config, Engine, ExternType, InstancePre, Linker, Module, Store, StoreLimits,
+    StoreLimitsBuilder, ValType,
+};
+
+#[derive(Debug, Error)]
+pub(crate) enum Error {
+    #[error("unable to set up the WebAssembly engine")]
+    Engine,
+    #[error("unable to load plugin {}", .0.display())]
+    Load(PathBuf),
+    #[error("transform `{0}` is exported by more than one plugin")]
+    Duplicate(String),
+    #[error("different plugins are loaded already")]
+    Reloaded,
+}
+
+/// Instructions a single invocation of a transform may execute before it is aborted.
+const FUEL: u64 = 10_000_000;
+
+/// Size the linear memory of a single invocation may grow to.
+const MEMORY: usize = 16 * 1024 * 1024;
+
+/// Compiled plugins and the transforms they export.
+struct Plugins {
+    paths: Vec<PathBuf>,
+    engine: Engine,
+    transforms: HashMap<String, InstancePre<StoreLimits>>,
+}
+
+// loaded once at startup, resolution happens in too many places to thread the plugins through
+static PLUGINS: OnceLock<Plugins> = OnceLock::new();
+
+fn engine_error(error: &wasmtime::Error) -> Report<Error> {
+    Report::new(Error::Engine).attach_printable(format!("{error:#}"))
+}
+
+fn load_error(path: &Path, error: &wasmtime::Error) -> Report<Error> {
+    Report::new(Error::Load(path.to_path_buf())).attach_printable(format!("{error:#}"))
+}
+
+/// Whether the export has the signature of a transform, `(ptr: i32, len: i32) -> i64`.
+fn is_transform(ty: &ExternType) -> bool {
+    let ExternType::Func(func) = ty else {
+        return false;
+    };
+
+    func.params().eq([ValType::I32, ValType::I32]) && func.results().eq([ValType::I64])
+}
+
+/// Compile the plugins and register every function they export with the signature of a
+/// transform under its export name.
+///
+/// Plugins do not get any imports, they can only compute on the value they are given. They are
+/// loaded once per process, loading different ones afterwards fails.
+pub(crate) fn load(paths: &[PathBuf]) -> Result<(), Error> {
+    if let Some(plugins) = PLUGINS.get() {
+        return reloaded(plugins, paths);
+    }
+
+    if paths.is_empty() {
+        return Ok(());
+    }
+
+    let mut config = Config::new();
+    config.consume_fuel(true);
+
+    let engine = Engine::new(&config).map_err(|error| engine_error(&error))?;
+    let linker = Linker::new(&engine);
+
+    let mut transforms = HashMap::new();
+
+    for path in paths {
+        let module = Module::from_file(&engine, path).map_err(|error| load_error(path, &error))?;
+        let instance = linker
+            .instantiate_pre(&module)
+            .map_err(|error| load_error(path, &error))?;
+
+        for export in module.exports() {
+            if !is_transform(&export.ty()) {
+                continue;
+            }
+
+            if transforms
+                .insert(export.name().to_owned(), instance.clone())
+                .is_some()
+            {
+                return Err(Report::new(Error::Duplicate(export.name().to_owned())));
+            }
+
+            tracing::debug!(transform = export.name(), ?path, "registered transform");
+        }
+    }
+
+    let plugins = PLUGINS.get_or_init(|| Plugins {
+        paths: paths.to_vec(),
+        engine,
+        transforms,
+    });
+
+    reloaded(plugins, paths)
+}
+
+fn reloaded(plugins: &Plugins, paths: &[PathBuf]) -> Result<(), Error> {
+    if plugins.paths != paths {
+        return Err(
+            Report::new(Error::Reloaded).attach_printable(format!("loaded {:?}", plugins.paths))
+        );
+    }
+
+    Ok(())
+}
+
+/// Whether a loaded plugin exports the transform.
+pub(crate) fn is_registered(name: &str) -> bool {
+    PLUGINS
+        .get()
+        .map_or(false, |plugins| plugins.transforms.contains_key(name))
+}
+
+/// Copy the JSON encoded value into a fresh instance of the plugin, call the transform and read
+/// the JSON encoded result, whose pointer and length are packed into the upper and lower half of
+/// the return value.
+fn invoke(
+    engine: &Engine,
+    instance: &InstancePre<StoreLimits>,
+    name: &str,
+    value: &Value,
+) -> wasmtime::Result<Value> {
+    let input = serde_json::to_vec(value)?;
+
+    let mut store = Store::new(
+        engine,
+        StoreLimitsBuilder::new().memory_size(MEMORY).build(),
+    );
+    store.limiter(|limits| limits);
+    store.add_fuel(FUEL)?;
+
+    let instance = instance.instantiate(&mut store)?;
+    let memory = instance
+        .get_memory(&mut store, "memory")
+        .ok_or_else(|| wasmtime::Error::msg("plugin does not export `memory`"))?;
+    let alloc = instance.get_typed_func::<i32, i32>(&mut store, "alloc")?;
+    let transform = instance.get_typed_func::<(i32, i32), i64>(&mut store, name)?;
+
+    let length = i32::try_from(input.len())?;
+    let pointer = alloc.call(&mut store, length)?;
+    memory.write(&mut store, usize::try_from(pointer)?, &input)?;
+
+    let packed = transform.call(&mut store, (pointer, length))?;
+
+    // the length is up to the plugin, it is read in place instead of being allocated up front
+    let start = usize::try_from(packed >> 32)?;
+    let end = start.saturating_add(usize::try_from(packed & 0xFFFF_FFFF)?);
+    let output = memory
+        .data(&store)
+        .get(start..end)
+        .ok_or_else(|| wasmtime::Error::msg("result is out of the bounds of the memory"))?;
+
+    Ok(serde_json::from_slice(output)?)
+}
+
+/// Run a transform, which may take up to [`FUEL`] instructions, without stalling the other tasks
+/// of the worker thread of the runtime it is called on.
+fn blocking<T>(run: impl FnOnce() -> T) -> T {
+    match Handle::try_current() {
+        Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => {
+            tokio::task::block_in_place(run)
+        }
+        _ => run(),
+    }
+}
+
+/// Run the transform on the value, `null` if no plugin exports it or it fails.
+pub(crate) fn transform(name: &str, value: &Value) -> Value {
+    let Some((plugins, instance)) = PLUGINS.get().and_then(|plugins| {
+        plugins
+            .transforms
+            .get(name)
+            .map(|instance| (plugins, instance))
+    }) else {
+        tracing::warn!(name, "transform is not exported by any plugin");
+
+        return Value::Null;
+    };
+
+    blocking(|| invoke(&plugins.engine, instance, name, value)).unwrap_or_else(|error| {
+        tracing::warn!(name, error = format!("{error:#}"), "transform failed");
+
+        Value::Null
+    })
+}
@@ -0,0 +1,263 @@
+use std::{io::Write, path::Path};
+
+use console::Term;
+use error_stack::{IntoReport, Report, Result, ResultExt};
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::{
+    cache::ScopeCache,
+    explain::Trace,
+    schema::{ParseOptions, ScopeConfig, ScopeConfiguration, SessionData, Target},
+    serve::Config,
+    validate,
+};
+
+#[derive(Debug, Error)]
+pub(crate) enum Error {
+    #[error("unable to load the identity schemas")]
+    Schema,
+    #[error("unable to write to stdout")]
+    Io,
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, clap::ValueEnum)]
+pub(crate) enum Format {
+    #[default]
+    Csv,
+    Json,
+}
+
+#[derive(Debug, Clone, clap::Args)]
+pub(crate) struct Options {
+    #[clap(long, value_enum, default_value_t)]
+    format: Format,
+}
+
+/// Claim a scope discloses personal data in, with the traits its value is taken from.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+struct Row {
+    schema: String,
+    scope: String,
+    kind: &'static str,
+    /// `id_token`, `access_token` or `userinfo`, `null` if the scope issues no claims
+    token: Option<&'static str>,
+    claim: Option<String>,
+    sources: Vec<String>,
+    pii: bool,
+    required: bool,
+}
+
+const HEADER: [&str; 8] = [
+    "schema", "scope", "kind", "token", "claim", "sources", "pii", "required",
+];
+
+impl Row {
+    /// Row of an identity schema whose traits cannot be read, which must not go missing from the
+    /// report silently.
+    fn malformed(schema: &str) -> Self {
+        Self {
+            schema: schema.to_owned(),
+            scope: String::new(),
+            kind: "malformed",
+            token: None,
+            claim: None,
+            sources: vec![],
+            pii: false,
+            required: false,
+        }
+    }
+
+    fn fields(&self) -> [String; 8] {
+        [
+            self.schema.clone(),
+            self.scope.clone(),
+            self.kind.to_owned(),
+            self.token.unwrap_or_default().to_owned(),
+            self.claim.clone().unwrap_or_default(),
+            self.sources.join("; "),
+            self.pii.to_string(),
+            self.required.to_string(),
+        ]
+    }
+}
+
+/// Quote the field if it contains a separator, a quote or a line break, as in RFC 4180.
+fn escape(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+/// Session data of the scope together with the sources of its value, a preset releases each
+/// standard claim from the traits tagged with it.
+fn placements<'a>(
+    configuration: &'a ScopeConfiguration,
+    trace: &Trace,
+) -> Vec<(&'a SessionData, Vec<String>)> {
+    match configuration {
+        ScopeConfiguration::Preset(preset) => preset
+            .claims
+            .iter()
+            .map(|claim| {
+                let prefix = format!("{} ← ", claim.claim);
+                let sources = trace
+                    .sources
+                    .iter()
+                    .filter(|source| source.starts_with(&prefix))
+                    .cloned()
+                    .collect();
+
+                (&claim.session_data, sources)
+            })
+            .collect(),
+        _ => configuration
+            .session_data()
+            .into_iter()
+            .map(|session_data| (session_data, trace.sources.clone()))
+            .collect(),
+    }
+}
+
+/// A row per claim every scope of the schema is issued in, scopes without claims have a single
+/// row without a token.
+fn rows(schema: &str, cache: &ScopeCache, config: &ScopeConfig, rows: &mut Vec<Row>) {
+    for (scope, configuration) in &config.scopes {
+        let trace = Trace::new(scope, configuration, cache);
+        let start = rows.len();
+
+        let row = |token, claim, sources, pii| Row {
+            schema: schema.to_owned(),
+            scope: scope.as_str().to_owned(),
+            kind: trace.kind,
+            token,
+            claim,
+            sources,
+            pii,
+            required: trace.required,
+        };
+
+        for (session_data, sources) in placements(configuration, &trace) {
+            // same as when the claims are resolved
+            let excluded =
+                config.exclude_pii && session_data.pii && !session_data.pii_in_access_token;
+            let nested = config
+                .access_token_key
+                .as_deref()
+                .filter(|_| session_data.access_token_nested != Some(false));
+
+            let tokens = [
+                ("id_token", session_data.id_token.as_ref()),
+                (
+                    "access_token",
+                    session_data.access_token.as_ref().filter(|_| !excluded),
+                ),
+                ("userinfo", session_data.userinfo.as_ref()),
+            ];
+
+            for (token, target) in tokens {
+                for claim in target.into_iter().flat_map(Target::claims) {
+                    let claim = match nested {
+                        Some(key) if token == "access_token" => format!("{key}.{claim}"),
+                        _ => claim.to_owned(),
+                    };
+
+                    rows.push(row(
+                        Some(token),
+                        Some(claim),
+                        sources.clone(),
+                        session_data.pii,
+                    ));
+                }
+            }
+        }
+
+        if rows.len() == start {
+            rows.push(row(None, None, trace.sources.clone(), trace.pii));
+        }
+    }
+}
+
+fn print(rows: &[Row], format: Format) -> Result<(), Error> {
+    let output = match format {
+        Format::Csv => {
+            let mut output = HEADER.join(",");
+            output.push('\n');
+
+            for row in rows {
+                let fields: Vec<_> = row.fields().iter().map(|field| escape(field)).collect();
+
+                output.push_str(&fields.join(","));
+                output.push('\n');
+            }
+
+            output
+        }
+        Format::Json => {
+            let mut output = serde_json::to_string_pretty(rows)
+                .into_report()
+                .change_context(Error::Io)?;
+            output.push('\n');
+
+            output
+        }
+    };
+
+    Term::stdout()
+        .write_all(output.as_bytes())
+        .into_report()
+        .change_context(Error::Io)
+}
+
+/// Print which claims every scope of every identity schema in Kratos discloses and where their
+/// values come from. Malformed parts of the configuration are skipped, as `validate` reports
+/// them, schemas whose traits cannot be read get a single `malformed` row.
+pub(crate) async fn run(options: &Options, config: Config) -> Result<(), Error> {
+    let kratos = validate::connect(&config).change_context(Error::Schema)?;
+
+    let mut report = vec![];
+
+    for container in validate::list_schemas(&kratos)
+        .await
+        .change_context(Error::Schema)?
+    {
+        let schema = container.id.unwrap_or_default();
+
+        let traits = container
+            .schema
+            .ok_or_else(|| Report::new(validate::Error::IdentitySchemaMalformed))
+            .and_then(|identity_schema| validate::traits_of(&identity_schema));
+
+        let traits = match traits {
+            Ok(traits) => traits,
+            Err(error) => {
+                tracing::warn!(
+                    schema,
+                    error = format!("{error:#}"),
+                    "unable to read the traits of the identity schema"
+                );
+                report.push(Row::malformed(&schema));
+
+                continue;
+            }
+        };
+
+        let (cache, scopes) = validate::parse(&config.parse, Some(&schema), &traits, &mut vec![]);
+
+        rows(&schema, &cache, &scopes, &mut report);
+    }
+
+    print(&report, options.format)
+}
+
+pub(crate) fn run_file(path: &Path, options: &Options, parse: &ParseOptions) -> Result<(), Error> {
+    let traits = validate::read_traits(path).change_context(Error::Schema)?;
+    let (cache, scopes) = validate::parse(parse, None, &traits, &mut vec![]);
+
+    let mut report = vec![];
+    rows(&path.display().to_string(), &cache, &scopes, &mut report);
+
+    print(&report, options.format)
+}
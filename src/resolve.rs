@@ -0,0 +1,339 @@
+use alloc::sync::Arc;
+use std::{
+    collections::HashSet,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use clap::ValueHint;
+use console::Term;
+use error_stack::{IntoReport, Report, Result, ResultExt};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use thiserror::Error;
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, BufReader},
+    sync::{mpsc, Semaphore},
+    task::JoinSet,
+};
+
+use crate::{
+    cache::{SchemaCache, SchemaId},
+    schema::{Context, Scope},
+    serve::Config,
+    upstream::Kratos,
+    validate::{self, Output, Preview},
+};
+
+#[derive(Debug, Error)]
+pub(crate) enum Error {
+    #[error("unable to configure upstream clients")]
+    Upstream,
+    #[error("API error to Kratos")]
+    Kratos,
+    #[error("unable to fetch schema from Kratos")]
+    IdentitySchema,
+    #[error("unable to read {}", .0.display())]
+    Batch(PathBuf),
+    #[error("line is neither an identity id nor an object with `id` or `traits`")]
+    Entry,
+    #[error("unable to write output")]
+    Output,
+}
+
+#[derive(Debug, Clone, clap::Args)]
+pub(crate) struct Options {
+    /// Identity in Kratos to resolve the claims for
+    #[clap(long, required_unless_present = "batch", conflicts_with = "batch")]
+    pub(crate) identity: Option<String>,
+
+    /// NDJSON file (CSV if it ends in `.csv`) of identities to resolve, `-` reads NDJSON from
+    /// stdin. Every line is an identity id, an object with an `id` or an object with `traits`
+    /// (and optionally `schema_id`), the results are printed as NDJSON
+    #[clap(long, value_hint = ValueHint::FilePath)]
+    pub(crate) batch: Option<PathBuf>,
+
+    /// Identity schema of the trait documents in the batch without a `schema_id`
+    #[clap(long, default_value = "default")]
+    pub(crate) schema: String,
+
+    /// Number of identities resolved in parallel
+    #[clap(long, default_value_t = 8)]
+    pub(crate) concurrency: usize,
+
+    /// Requested scopes, defaults to all configured scopes
+    #[clap(long, value_delimiter = ',')]
+    pub(crate) scopes: Vec<String>,
+
+    /// Output format of a single identity
+    #[clap(long, value_enum, default_value = "json")]
+    pub(crate) output: Output,
+}
+
+/// Line of a batch.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Entry {
+    Identity(String),
+    // checked before `Reference`, so that exported identities are resolved from their traits
+    Traits {
+        id: Option<String>,
+        traits: Value,
+        schema_id: Option<String>,
+        // e.g. `metadata_public` or `verifiable_addresses` of an export
+        #[serde(flatten)]
+        identity: serde_json::Map<String, Value>,
+    },
+    Reference {
+        id: String,
+    },
+}
+
+/// Result of a single line of a batch.
+#[derive(Serialize)]
+struct Outcome {
+    line: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    identity: Option<String>,
+    #[serde(flatten)]
+    result: Resolved,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum Resolved {
+    Claims(Preview),
+    Error { error: String },
+}
+
+struct Resolver {
+    kratos: Kratos,
+    cache: SchemaCache,
+    scopes: Vec<String>,
+}
+
+impl Resolver {
+    async fn identity(&self, id: &str) -> Result<(String, Option<Context>), Error> {
+        let identity = self
+            .kratos
+            .scoped()
+            .call(Error::Kratos, |kratos| {
+                ory_kratos_client::apis::identity_api::get_identity(kratos, id, None)
+            })
+            .await?;
+
+        Ok((
+            identity.schema_id.clone(),
+            Context::from_identity(&identity),
+        ))
+    }
+
+    async fn claims(&self, schema_id: String, context: Option<Context>) -> Result<Preview, Error> {
+        let schema = self
+            .cache
+            .fetch(&self.kratos, &SchemaId::new(schema_id))
+            .await
+            .change_context(Error::IdentitySchema)?;
+
+        let requested: HashSet<_> = if self.scopes.is_empty() {
+            schema.scopes().cloned().collect()
+        } else {
+            self.scopes.iter().cloned().map(Scope::new).collect()
+        };
+
+        // identities without traits are accepted without any session data
+        let preview = context.map_or(
+            Preview {
+                subject: None,
+                id_token: Value::Null,
+                access_token: Value::Null,
+                userinfo: Value::Null,
+            },
+            |context| {
+                let claims = schema.resolve(&context, &requested);
+
+                Preview {
+                    subject: claims.subject,
+                    id_token: claims.id_token,
+                    access_token: claims.access_token,
+                    userinfo: claims.userinfo,
+                }
+            },
+        );
+
+        Ok(preview)
+    }
+
+    async fn entry(&self, entry: Entry, schema: &str) -> Result<Preview, Error> {
+        let (schema_id, context) = match entry {
+            Entry::Identity(id) | Entry::Reference { id } => self.identity(&id).await?,
+            Entry::Traits {
+                id,
+                traits,
+                schema_id,
+                mut identity,
+            } => {
+                identity.insert("traits".to_owned(), traits);
+                identity.extend(id.map(|id| ("id".to_owned(), Value::String(id))));
+
+                (
+                    schema_id.unwrap_or_else(|| schema.to_owned()),
+                    Some(Context::new(identity)),
+                )
+            }
+        };
+
+        self.claims(schema_id, context).await
+    }
+}
+
+fn parse_entry(line: &str, csv: bool) -> Result<Entry, Error> {
+    if csv {
+        let id = line.split(',').next().unwrap_or_default().trim();
+
+        return Ok(Entry::Identity(id.trim_matches('"').to_owned()));
+    }
+
+    serde_json::from_str(line)
+        .into_report()
+        .change_context(Error::Entry)
+}
+
+async fn open(path: &Path) -> std::io::Result<Box<dyn AsyncBufRead + Unpin + Send>> {
+    if path == Path::new("-") {
+        return Ok(Box::new(BufReader::new(tokio::io::stdin())));
+    }
+
+    let file = tokio::fs::File::open(path).await?;
+
+    Ok(Box::new(BufReader::new(file)))
+}
+
+/// Resolve every line of the batch, at most `concurrency` at a time, and print the results in the
+/// order they complete.
+async fn batch(resolver: Resolver, path: &Path, options: &Options) -> Result<(), Error> {
+    let csv = path
+        .extension()
+        .map_or(false, |extension| extension == "csv");
+
+    let mut lines = open(path)
+        .await
+        .into_report()
+        .change_context_lazy(|| Error::Batch(path.to_path_buf()))?
+        .lines();
+
+    let resolver = Arc::new(resolver);
+    let semaphore = Arc::new(Semaphore::new(options.concurrency.max(1)));
+    let (sender, mut receiver) = mpsc::channel::<Outcome>(options.concurrency.max(1));
+
+    let writer = tokio::spawn(async move {
+        let mut term = Term::stdout();
+
+        while let Some(outcome) = receiver.recv().await {
+            let line = serde_json::to_string(&outcome)
+                .into_report()
+                .change_context(Error::Output)?;
+
+            writeln!(term, "{line}")
+                .into_report()
+                .change_context(Error::Output)?;
+        }
+
+        Ok::<_, Report<Error>>(())
+    });
+
+    let mut tasks = JoinSet::new();
+    let mut number = 0;
+
+    while let Some(line) = lines
+        .next_line()
+        .await
+        .into_report()
+        .change_context_lazy(|| Error::Batch(path.to_path_buf()))?
+    {
+        number += 1;
+
+        // a header row of a CSV file
+        if line.trim().is_empty() || (csv && number == 1 && line.trim() == "id") {
+            continue;
+        }
+
+        let permit = Arc::clone(&semaphore)
+            .acquire_owned()
+            .await
+            .into_report()
+            .change_context(Error::Output)?;
+
+        let resolver = Arc::clone(&resolver);
+        let sender = sender.clone();
+        let schema = options.schema.clone();
+
+        tasks.spawn(async move {
+            let entry = parse_entry(&line, csv);
+            let identity = match &entry {
+                Ok(Entry::Identity(id) | Entry::Reference { id }) => Some(id.clone()),
+                Ok(Entry::Traits { id, .. }) => id.clone(),
+                Err(_) => None,
+            };
+
+            let result = match entry {
+                Ok(entry) => resolver.entry(entry, &schema).await,
+                Err(report) => Err(report),
+            };
+
+            let result = result.map_or_else(
+                |report| Resolved::Error {
+                    error: format!("{report:#}"),
+                },
+                Resolved::Claims,
+            );
+
+            // the writer only stops early if stdout is gone, there is nobody left to report to
+            let _ = sender
+                .send(Outcome {
+                    line: number,
+                    identity,
+                    result,
+                })
+                .await;
+
+            drop(permit);
+        });
+    }
+
+    drop(sender);
+
+    while let Some(result) = tasks.join_next().await {
+        if let Err(error) = result {
+            std::panic::resume_unwind(error.into_panic());
+        }
+    }
+
+    match writer.await {
+        Ok(result) => result,
+        Err(error) => std::panic::resume_unwind(error.into_panic()),
+    }
+}
+
+/// Resolve the claims of identities the same way `/consent` does, without involving Hydra.
+pub(crate) async fn run(options: Options, config: Config) -> Result<(), Error> {
+    let kratos = validate::connect(&config).change_context(Error::Upstream)?;
+
+    let resolver = Resolver {
+        kratos,
+        cache: SchemaCache::new(config.parse),
+        scopes: options.scopes.clone(),
+    };
+
+    if let Some(path) = &options.batch {
+        return batch(resolver, path, &options).await;
+    }
+
+    let identity = options.identity.unwrap_or_default();
+    let (schema_id, context) = resolver.identity(&identity).await?;
+    let preview = resolver.claims(schema_id, context).await?;
+
+    let output = validate::render(&preview, options.output).change_context(Error::Output)?;
+
+    validate::write(&output).change_context(Error::Output)
+}
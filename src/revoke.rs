@@ -0,0 +1,104 @@
+use std::io::Write;
+
+use console::Term;
+use error_stack::{IntoReport, Result, ResultExt};
+use thiserror::Error;
+
+use crate::{secret::Secret, serve::Config, store::Store, upstream::Hydra};
+
+#[derive(Debug, Copy, Clone, Error)]
+pub(crate) enum Error {
+    #[error("unable to configure upstream clients")]
+    Upstream,
+    #[error("unable to revoke the consent sessions in Hydra")]
+    Hydra,
+    #[error("unable to remove the grants from the grant store")]
+    Store,
+    #[error("unable to write output")]
+    Output,
+}
+
+#[derive(Debug, Clone, clap::Args)]
+pub(crate) struct Options {
+    /// Subject whose consent is revoked
+    #[clap(long)]
+    subject: String,
+
+    /// Only revoke the consent to this client, every client if not given
+    #[clap(long)]
+    client: Option<String>,
+
+    /// Database the grants are recorded in, as given to `serve --grant-store`, its records of the
+    /// revoked consent are removed as well
+    #[clap(long, env, hide_env_values = true)]
+    grant_store: Option<Secret>,
+
+    /// Tenant whose records are removed from the grant store, the top-level options if not given
+    #[clap(long, default_value = "")]
+    tenant: String,
+}
+
+/// Revoke the consent sessions of the subject in Hydra, which also revokes the tokens issued for
+/// them, and remove the grants recorded for them. Returns the number of removed grants.
+pub(crate) async fn revoke(
+    hydra: &Hydra,
+    store: Option<&Store>,
+    tenant: &str,
+    subject: &str,
+    client_id: Option<&str>,
+) -> Result<u64, Error> {
+    hydra
+        .scoped()
+        .call(Error::Hydra, |hydra| {
+            // Hydra requires `all` unless a client is given
+            ory_hydra_client::apis::o_auth2_api::revoke_o_auth2_consent_sessions(
+                hydra,
+                subject,
+                client_id,
+                client_id.is_none().then_some(true),
+            )
+        })
+        .await?;
+
+    let Some(store) = store else {
+        return Ok(0);
+    };
+
+    store
+        .revoke(tenant, subject, client_id)
+        .await
+        .change_context(Error::Store)
+}
+
+pub(crate) async fn run(options: Options, config: Config) -> Result<(), Error> {
+    let upstreams = config.upstream.connect().change_context(Error::Upstream)?;
+    let hydra = upstreams
+        .hydra(&config.hydra_url, config.hydra_credentials.as_ref())
+        .change_context(Error::Upstream)?;
+
+    let store = match &options.grant_store {
+        Some(url) => Some(Store::connect(url).await.change_context(Error::Store)?),
+        None => None,
+    };
+
+    let removed = revoke(
+        &hydra,
+        store.as_ref(),
+        &options.tenant,
+        &options.subject,
+        options.client.as_deref(),
+    )
+    .await?;
+
+    let clients = options
+        .client
+        .map_or_else(|| "every client".to_owned(), |client| format!("`{client}`"));
+
+    writeln!(
+        Term::stdout(),
+        "revoked the consent of `{}` to {clients}, removed {removed} recorded grants",
+        options.subject
+    )
+    .into_report()
+    .change_context(Error::Output)
+}
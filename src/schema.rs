@@ -1,32 +1,627 @@
-use std::{
-    collections::HashSet,
-    fmt::{Display, Formatter},
+#![allow(clippy::std_instead_of_alloc)] // Reason: expansion of the `JsonSchema` derive
+
+use alloc::borrow::Cow;
+use core::{
+    cmp::Ordering,
+    fmt::{Debug, Display, Formatter},
 };
+use std::collections::{HashMap, HashSet};
 
 use indexmap::IndexMap;
 use jsonptr::Token;
-use schemars::schema::{ObjectValidation, SchemaObject};
+use ory_hydra_client::models::OAuth2ConsentRequest;
+use ory_kratos_client::models::{Identity, VerifiableIdentityAddress};
+use regex::Regex;
+use schemars::{
+    gen::{SchemaGenerator, SchemaSettings},
+    schema::{
+        ArrayValidation, InstanceType, Metadata, ObjectValidation, RootSchema, Schema,
+        SchemaObject, SingleOrVec, SubschemaValidation,
+    },
+    JsonSchema,
+};
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
+use serde_json::{json, Value};
+use time::{
+    format_description::{self, well_known::Rfc3339, OwnedFormatItem},
+    Date, OffsetDateTime, UtcOffset,
+};
 
-use crate::cache::{ImplicitScopeCache, ScopeCache};
+use crate::{
+    cache::{ImplicitScopeCache, ScopeCache, TraitMetadata},
+    hash::{self, Hash},
+    overlay::Overlays,
+    plugin,
+    script::Script,
+    source,
+};
 
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
-pub(crate) struct Scope(String);
+/// Scope, either requested or configured. Configured scopes are deserialized, which compiles
+/// their pattern once, requested ones are never matched against others.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(from = "String", into = "String")]
+pub struct Scope {
+    value: String,
+    pattern: Option<ScopePattern>,
+}
 
 impl Scope {
-    pub(crate) fn new(value: String) -> Self {
-        Self(value)
+    /// Requested scope, a `*` in it is matched literally.
+    #[must_use]
+    pub const fn new(value: String) -> Self {
+        Self {
+            value,
+            pattern: None,
+        }
     }
 
-    pub(crate) fn as_str(&self) -> &str {
-        &self.0
+    #[must_use]
+    pub fn as_str(&self) -> &str {
+        &self.value
+    }
+
+    /// Whether the scope is a pattern, in which every `*` stands for a part of a requested scope,
+    /// e.g. `org:*:read`.
+    pub(crate) fn is_pattern(&self) -> bool {
+        self.value.contains('*')
+    }
+
+    /// Parts of the requested scope matched by the `*` of the pattern, in order, e.g. `["acme"]`
+    /// for `org:acme:read` and `org:*:read`.
+    pub(crate) fn captures(&self, requested: &Self) -> Option<Vec<String>> {
+        let captures = self.pattern.as_ref()?.0.captures(&requested.value)?;
+
+        Some(
+            captures
+                .iter()
+                .skip(1)
+                .flatten()
+                .map(|capture| capture.as_str().to_owned())
+                .collect(),
+        )
+    }
+}
+
+/// Configured scope, with its pattern compiled if it has one.
+impl From<String> for Scope {
+    fn from(value: String) -> Self {
+        let pattern = ScopePattern::new(&value);
+
+        Self { value, pattern }
+    }
+}
+
+impl From<Scope> for String {
+    fn from(scope: Scope) -> Self {
+        scope.value
+    }
+}
+
+impl Debug for Scope {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        f.debug_tuple("Scope").field(&self.value).finish()
+    }
+}
+
+impl PartialEq for Scope {
+    fn eq(&self, other: &Self) -> bool {
+        self.value == other.value
+    }
+}
+
+impl Eq for Scope {}
+
+impl PartialOrd for Scope {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Scope {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.value.cmp(&other.value)
+    }
+}
+
+impl core::hash::Hash for Scope {
+    fn hash<H: core::hash::Hasher>(&self, state: &mut H) {
+        self.value.hash(state);
+    }
+}
+
+impl JsonSchema for Scope {
+    fn schema_name() -> String {
+        "Scope".to_owned()
+    }
+
+    fn json_schema(generator: &mut SchemaGenerator) -> Schema {
+        String::json_schema(generator)
+    }
+}
+
+/// Regular expression of a scope pattern, in which every `*` captures a part of the requested
+/// scope, e.g. `^org:(.+?):read$` for `org:*:read`.
+#[derive(Debug, Clone)]
+struct ScopePattern(Regex);
+
+impl ScopePattern {
+    fn new(scope: &str) -> Option<Self> {
+        if !scope.contains('*') {
+            return None;
+        }
+
+        let pattern = scope
+            .split('*')
+            .map(regex::escape)
+            .collect::<Vec<_>>()
+            .join("(.+?)");
+
+        Regex::new(&format!("^{pattern}$")).ok().map(Self)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Severity {
+    /// The configuration is usable, but likely not what was intended
+    Warning,
+    /// Part of the configuration is malformed and skipped
+    Error,
+}
+
+/// Problem in the scope configuration of an identity schema.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Problem {
+    pub severity: Severity,
+    /// JSON pointer into the traits at which the offending configuration is located
+    pub location: String,
+    pub message: String,
+}
+
+impl Problem {
+    fn new(severity: Severity, location: &jsonptr::Pointer, message: String) -> Self {
+        Self {
+            severity,
+            location: location.to_string(),
+            message,
+        }
+    }
+
+    pub(crate) fn warning(location: &jsonptr::Pointer, message: String) -> Self {
+        Self::new(Severity::Warning, location, message)
+    }
+
+    pub(crate) fn error(location: &jsonptr::Pointer, message: String) -> Self {
+        Self::new(Severity::Error, location, message)
+    }
+
+    #[must_use]
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        if self.location.is_empty() {
+            write!(f, "traits: {}", self.message)
+        } else {
+            write!(f, "traits at `{}`: {}", self.location, self.message)
+        }
+    }
+}
+
+/// Remove the value of a keyword from the extensions of a schema, a keyword starting with `/` is a
+/// JSON pointer into them, e.g. `/x-internal/consent` for `{"x-internal": {"consent": ..}}`.
+fn take_extension(extensions: &mut schemars::Map<String, Value>, keyword: &str) -> Option<Value> {
+    if !keyword.starts_with('/') {
+        return extensions.remove(keyword);
+    }
+
+    let pointer = jsonptr::Pointer::try_from(keyword).ok()?;
+    let tokens: Vec<_> = pointer.tokens().collect();
+
+    let (first, rest) = tokens.split_first()?;
+    let Some((last, parents)) = rest.split_last() else {
+        return extensions.remove(first.as_str());
+    };
+
+    let mut value = extensions.get_mut(first.as_str())?;
+    for token in parents {
+        value = value.get_mut(token.as_str())?;
+    }
+
+    value.as_object_mut()?.remove(last.as_str())
+}
+
+/// Take the value of the first keyword the schema is annotated with, annotations with any of the
+/// other keywords are removed and ignored.
+fn take_keyword<'a>(
+    keywords: &'a [String],
+    extensions: &mut schemars::Map<String, Value>,
+    location: &jsonptr::Pointer,
+    problems: &mut Vec<Problem>,
+) -> Option<(&'a str, Value)> {
+    let mut taken: Option<(&str, Value)> = None;
+
+    for keyword in keywords {
+        let Some(value) = take_extension(extensions, keyword) else {
+            continue;
+        };
+
+        match &taken {
+            None => taken = Some((keyword, value)),
+            Some((preferred, _)) => problems.push(Problem::warning(
+                location,
+                format!("`{keyword}` is ignored, `{preferred}` takes precedence"),
+            )),
+        }
+    }
+
+    taken
+}
+
+/// Problems of a value of the keyword that cannot be deserialized, each located by the JSON pointer
+/// into the value, falling back to the error of serde if the value is valid against its schema.
+fn malformed<T: JsonSchema>(
+    keyword: &str,
+    location: &jsonptr::Pointer,
+    value: &Value,
+    error: &serde_json::Error,
+) -> Vec<Problem> {
+    let fallback = || {
+        vec![Problem::error(
+            location,
+            format!("unable to deserialize {keyword}: {error}"),
+        )]
+    };
+
+    let Ok(schema) = serde_json::to_value(schemars::schema_for!(T)) else {
+        return fallback();
+    };
+
+    let Ok(validator) = jsonschema::JSONSchema::compile(&schema) else {
+        return fallback();
+    };
+
+    let problems: Vec<_> = match validator.validate(value) {
+        Ok(()) => vec![],
+        Err(violations) => violations
+            .map(|violation| {
+                Problem::error(
+                    location,
+                    format!("{keyword}{}: {violation}", violation.instance_path),
+                )
+            })
+            .collect(),
+    };
+
+    if problems.is_empty() {
+        return fallback();
+    }
+
+    problems
+}
+
+/// How the scope configuration is read from an identity schema.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseOptions {
+    /// Keywords of the scope configuration and the annotations of the traits, the first one
+    /// present takes precedence
+    pub(crate) keywords: Vec<String>,
+    /// Every property at the root of the traits is a scope of the same name
+    pub(crate) direct_mapping: bool,
+    /// Traits tagged with a standard claim fill the standard scopes of OIDC
+    pub(crate) oidc_presets: bool,
+    /// Key the claims of the access token are nested under, unless a scope opts out
+    pub(crate) access_token_key: Option<String>,
+    /// Scopes that are only granted, e.g. `openid`
+    pub(crate) passthrough_scopes: Vec<Scope>,
+    /// Personally identifiable information is kept out of the access token
+    pub(crate) exclude_pii: bool,
+    /// How deeply schemas may be nested into each other before the search for annotated traits
+    /// gives up
+    pub(crate) max_schema_depth: usize,
+    /// Scope configurations taking precedence over the ones embedded in identity schemas
+    pub(crate) overlays: Overlays,
+}
+
+/// Standard claims of the scopes defined by OIDC Core 1.0, section 5.4.
+const PRESETS: &[(&str, &[&str])] = &[
+    ("profile", &[
+        "name",
+        "family_name",
+        "given_name",
+        "middle_name",
+        "nickname",
+        "preferred_username",
+        "profile",
+        "picture",
+        "website",
+        "gender",
+        "birthdate",
+        "zoneinfo",
+        "locale",
+        "updated_at",
+    ]),
+    ("email", &["email", "email_verified"]),
+    ("address", &["address"]),
+    ("phone", &["phone_number", "phone_number_verified"]),
+];
+
+/// Whether the claim is one of the standard claims of OIDC, e.g. `given_name`.
+pub(crate) fn is_standard_claim(claim: &str) -> bool {
+    PRESETS.iter().any(|(_, claims)| claims.contains(&claim))
+}
+
+/// Standard claims of the verification status of an address, and the claim of the address.
+const VERIFIED_CLAIMS: &[(&str, &str)] = &[
+    ("email_verified", "email"),
+    ("phone_number_verified", "phone_number"),
+];
+
+/// Fields of an identity that mappings can refer to, credentials are never exposed.
+const IDENTITY_FIELDS: &[&str] = &[
+    "id",
+    "traits",
+    "state",
+    "created_at",
+    "updated_at",
+    "metadata_public",
+    "metadata_admin",
+    "verifiable_addresses",
+];
+
+/// Summary of the credentials of an identity, which leaves out their configuration (hashes, keys
+/// and the tokens of providers), e.g. `{"types": ["oidc"], "oidc": {"identifiers": [..],
+/// "providers": ["github"]}}`.
+fn credentials(credentials: Option<&Value>) -> Value {
+    let Some(Value::Object(credentials)) = credentials else {
+        return Value::Null;
+    };
+
+    let mut summary = serde_json::Map::new();
+    let mut types = Vec::with_capacity(credentials.len());
+
+    for (kind, credential) in credentials {
+        types.push(Value::String(kind.clone()));
+
+        let mut entry: serde_json::Map<_, _> = ["identifiers", "created_at", "updated_at"]
+            .into_iter()
+            .filter_map(|key| Some((key.to_owned(), credential.get(key)?.clone())))
+            .collect();
+
+        // identifiers of OIDC credentials are `<provider>:<subject>`
+        if kind == "oidc" {
+            let providers: Vec<_> = credential
+                .get("identifiers")
+                .and_then(Value::as_array)
+                .into_iter()
+                .flatten()
+                .filter_map(|identifier| identifier.as_str()?.split_once(':'))
+                .map(|(provider, _)| Value::String(provider.to_owned()))
+                .collect();
+
+            entry.insert("providers".to_owned(), Value::Array(providers));
+        }
+
+        summary.insert(kind.clone(), Value::Object(entry));
+    }
+
+    summary.insert("types".to_owned(), Value::Array(types));
+
+    Value::Object(summary)
+}
+
+/// Identity the claims are resolved for.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Context {
+    /// Identity in the shape Kratos returns it, e.g. `{"id": "..", "traits": {..}}`
+    identity: Value,
+    verifiable_addresses: Vec<VerifiableIdentityAddress>,
+    /// Credentials of the identity without their secrets, `null` if Kratos did not return them
+    credentials: Value,
+    /// How the user authenticated, `null` outside of a consent request
+    login: Value,
+    /// Documents of the claim sources keyed by their name, e.g. `{"keto": {"roles": [..]}}`
+    sources: Value,
+    /// Requested scope matched by a pattern, `null` for any other scope
+    scope: Value,
+}
+
+impl Context {
+    /// Context of an identity document, e.g. an export of the identities of Kratos.
+    #[must_use]
+    pub fn new(mut identity: serde_json::Map<String, Value>) -> Self {
+        let credentials = credentials(identity.get("credentials"));
+        identity.retain(|key, _| IDENTITY_FIELDS.contains(&key.as_str()));
+
+        let verifiable_addresses = identity
+            .get("verifiable_addresses")
+            .cloned()
+            .and_then(|addresses| serde_json::from_value(addresses).ok())
+            .unwrap_or_default();
+
+        Self {
+            identity: Value::Object(identity),
+            verifiable_addresses,
+            credentials,
+            login: Value::Null,
+            sources: Value::Object(serde_json::Map::new()),
+            scope: Value::Null,
+        }
+    }
+
+    /// Add the authentication of the login the consent is requested for, as Hydra received it
+    /// from the login provider.
+    #[must_use]
+    pub fn with_login(mut self, request: &OAuth2ConsentRequest) -> Self {
+        self.login = json!({
+            "acr": request.acr,
+            "amr": request.amr,
+            "context": request.context,
+            "oidc_context": request.oidc_context,
+        });
+
+        self
+    }
+
+    /// Add the document of a claim source, e.g. the relationships of the subject listed in Keto
+    /// as `keto`, which replaces an earlier document of the same name.
+    #[must_use]
+    pub fn with_source(mut self, name: &str, document: Value) -> Self {
+        self.sources[name] = document;
+
+        self
+    }
+
+    /// Context of a requested scope matched by a pattern, e.g.
+    /// `{"name": "org:acme:read", "captures": ["acme"]}`.
+    fn with_scope(&self, scope: &Scope, captures: &[String]) -> Self {
+        let mut context = self.clone();
+        context.scope = json!({
+            "name": scope.as_str(),
+            "captures": captures,
+        });
+
+        context
+    }
+
+    /// Context of traits without any further information about the identity, e.g. a preview.
+    #[must_use]
+    pub fn from_traits(traits: Value) -> Self {
+        Self::new(serde_json::Map::from_iter([("traits".to_owned(), traits)]))
+    }
+
+    /// Context of an identity fetched from Kratos, identities without traits have no claims.
+    #[must_use]
+    pub fn from_identity(identity: &Identity) -> Option<Self> {
+        identity.traits.as_ref()?;
+
+        match serde_json::to_value(identity) {
+            Ok(Value::Object(identity)) => Some(Self::new(identity)),
+            Ok(_) => None,
+            Err(error) => {
+                tracing::warn!(?error, "unable to serialize identity");
+
+                None
+            }
+        }
+    }
+
+    /// Context whose traits are replaced by the value, e.g. an item of an array of the traits.
+    fn with_traits(&self, traits: Value) -> Self {
+        let mut context = self.clone();
+        context.identity["traits"] = traits;
+
+        context
+    }
+
+    fn traits(&self) -> &Value {
+        &self.identity["traits"]
+    }
+
+    pub(crate) fn document(&self, source: Source) -> &Value {
+        match source {
+            Source::Traits => self.traits(),
+            Source::Identity => &self.identity,
+            Source::Credentials => &self.credentials,
+            Source::Login => &self.login,
+            Source::Keto => &self.sources[source::KETO],
+            Source::Sessions => &self.sources[source::SESSIONS],
+            Source::Webhook => &self.sources[source::WEBHOOK],
+            Source::Custom => &self.sources,
+            Source::Scope => &self.scope,
+        }
+    }
+
+    /// Whether the value is one of the verifiable addresses of the identity and has been verified.
+    fn is_verified(&self, value: &Value) -> bool {
+        let Some(value) = value.as_str() else {
+            return false;
+        };
+
+        // Kratos normalizes email addresses to lowercase
+        self.verifiable_addresses
+            .iter()
+            .any(|address| address.verified && address.value.eq_ignore_ascii_case(value))
+    }
+}
+
+/// Replace every `*` of the claim with the captures of a scope pattern, in order.
+fn substitute<'a>(claim: &'a str, captures: &[String]) -> Cow<'a, str> {
+    if captures.is_empty() || !claim.contains('*') {
+        return Cow::Borrowed(claim);
+    }
+
+    let mut captures = captures.iter();
+    let mut substituted = String::with_capacity(claim.len());
+
+    for (index, part) in claim.split('*').enumerate() {
+        if index > 0 {
+            substituted.push_str(captures.next().map_or("*", String::as_str));
+        }
+
+        substituted.push_str(part);
+    }
+
+    Cow::Owned(substituted)
+}
+
+/// Write the value to the claim, a pointer (e.g. `/address/street_address`) places it in nested
+/// objects, which are created as needed.
+fn place(claims: &mut serde_json::Map<String, Value>, claim: &str, value: Value) {
+    let pointer = claim
+        .starts_with('/')
+        .then(|| jsonptr::Pointer::try_from(claim).ok())
+        .flatten();
+
+    let Some(mut pointer) = pointer else {
+        claims.insert(claim.to_owned(), value);
+        return;
+    };
+
+    let Some(last) = pointer.pop_back() else {
+        return;
+    };
+
+    let mut object = claims;
+
+    for token in pointer.tokens() {
+        let entry = object
+            .entry(token.as_str())
+            .or_insert_with(|| Value::Object(serde_json::Map::new()));
+
+        if !entry.is_object() {
+            tracing::warn!(claim, "claim overwrites a value that is not an object");
+
+            *entry = Value::Object(serde_json::Map::new());
+        }
+
+        let Value::Object(nested) = entry else {
+            return;
+        };
+
+        object = nested;
     }
+
+    object.insert(last.as_str().to_owned(), value);
 }
 
-pub(crate) struct Claims {
-    pub(crate) id_token: Value,
-    pub(crate) access_token: Value,
+pub struct Claims {
+    pub id_token: Value,
+    pub access_token: Value,
+    /// Claims only returned by the userinfo endpoint
+    pub userinfo: Value,
+    /// Requested scopes that are required, but whose value is `null`
+    pub missing: Vec<Scope>,
+    /// Requested aliases and the scope they are granted as
+    pub aliases: HashMap<Scope, Scope>,
+    /// Shortest time in seconds any of the resolved scopes allows the consent to be remembered for
+    pub remember_for: Option<u64>,
+    /// Remapped subject, `None` if it is not remapped or its value is neither a string nor a
+    /// number
+    pub subject: Option<String>,
 }
 
 // A claim is a resolved scope with a value.
@@ -52,159 +647,1576 @@ impl<'a> IncompleteClaim<'a> {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+/// Claims the value of a scope is written to.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
 pub(crate) struct SessionData {
-    pub(crate) id_token: Option<String>,
-    pub(crate) access_token: Option<String>,
+    /// Claims of the ID token, the value is not added to the ID token if `null`. A pointer (e.g.
+    /// `/address/street_address`) places the value in nested objects
+    pub(crate) id_token: Option<Target>,
+    /// Claims of the access token, the value is not added to the access token if `null`. A
+    /// pointer places the value in nested objects
+    pub(crate) access_token: Option<Target>,
+    /// Claims only returned by the userinfo endpoint (`/userinfo`), which keeps them out of the
+    /// tokens
+    pub(crate) userinfo: Option<Target>,
+    /// Whether the claims of the access token are nested under `--access-token-key` (if set),
+    /// `true` by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) access_token_nested: Option<bool>,
+    /// The value is personally identifiable information, which `--exclude-pii` keeps out of the
+    /// access token
+    #[serde(default)]
+    pub(crate) pii: bool,
+    /// Place the value in the access token, even though it is personally identifiable information
+    /// and `--exclude-pii` is set
+    #[serde(default)]
+    pub(crate) pii_in_access_token: bool,
+}
+
+/// Claims of a token the value of a scope is written to.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub(crate) enum Target {
+    /// Name of the claim
+    Claim(String),
+    /// Names of the claims, each receives the whole value
+    Claims(Vec<String>),
+    /// Name of the claim to a pointer into the value, e.g. `{"given_name": "/first"}`, the claim
+    /// is `null` if the pointer does not exist
+    Spread(IndexMap<String, Pointer>),
+}
+
+impl Target {
+    pub(crate) fn claims(&self) -> Vec<&str> {
+        match self {
+            Self::Claim(claim) => vec![claim.as_str()],
+            Self::Claims(claims) => claims.iter().map(String::as_str).collect(),
+            Self::Spread(claims) => claims.keys().map(String::as_str).collect(),
+        }
+    }
+
+    /// Write the value to the claims, every `*` in the name of a claim is replaced by the
+    /// captures of the scope pattern.
+    fn place(
+        &self,
+        claims: &mut serde_json::Map<String, Value>,
+        value: &Value,
+        captures: &[String],
+    ) {
+        match self {
+            Self::Claim(claim) => place(claims, &substitute(claim, captures), value.clone()),
+            Self::Claims(names) => {
+                for claim in names {
+                    place(claims, &substitute(claim, captures), value.clone());
+                }
+            }
+            Self::Spread(pointers) => {
+                for (claim, pointer) in pointers {
+                    let value = pointer.0.resolve(value).map_or(Value::Null, Clone::clone);
+
+                    place(claims, &substitute(claim, captures), value);
+                }
+            }
+        }
+    }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+/// Annotation of a trait, whose value is used for the listed scopes.
+#[derive(
+    Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, JsonSchema,
+)]
 pub(crate) struct TraitConfiguration {
+    #[serde(default)]
     pub(crate) scopes: Vec<Scope>,
+    /// Standard claim of OIDC the trait fills (e.g. `given_name`), issued for its
+    /// standard scope if presets are enabled
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) claim: Option<String>,
+    /// The value of the trait is personally identifiable information, which tags the implicit
+    /// scopes and standard claim it fills
+    #[serde(default)]
+    pub(crate) pii: bool,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+/// How the values of all traits annotated with the same scope are combined.
+#[derive(
+    Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, JsonSchema,
+)]
 #[serde(rename_all = "camelCase")]
 pub(crate) enum Collect {
+    /// Value of the first trait that is present
     First,
+    /// Value of the last trait that is present
     Last,
+    /// Value of any trait that is present
     Any,
+    /// List of the values of all traits that are present
     All,
+    /// Value of the first trait that is a verified address, the first trait that is present if
+    /// none is verified
+    Verified,
+    /// Objects of all traits that are present merged recursively, on conflicts the value of the
+    /// trait that takes `precedence` wins
+    Merge,
+    /// List of the values of all traits that are present, with the items of arrays instead of the
+    /// arrays themselves, joined into a single string if there is a `separator`
+    Concat,
+    /// Number of the values of all traits that are present, arrays count their items
+    Count,
+    /// List of the distinct values of all traits that are present in the order they appear, arrays
+    /// contribute their items (e.g. roles gathered from several traits)
+    Unique,
+}
+
+/// Which of the values wins if they conflict while they are merged.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub(crate) enum Precedence {
+    First,
+    #[default]
+    Last,
+}
+
+impl Precedence {
+    #[allow(clippy::trivially_copy_pass_by_ref)] // Reason: signature of `skip_serializing_if`
+    fn is_last(&self) -> bool {
+        *self == Self::Last
+    }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+/// Token of a pointer to the traits that matches every item of an array or every property of an
+/// object, e.g. `/emails/*/address`, `*(pattern)` only matches the properties whose key matches a
+/// pattern of `patternProperties`.
+const WILDCARD: &str = "*";
+
+/// Pattern of a wildcard token that only matches some properties.
+fn key_pattern<'a>(token: &Token, patterns: &'a [Pattern]) -> Option<&'a Pattern> {
+    let pattern = token
+        .as_str()
+        .strip_prefix(WILDCARD)?
+        .strip_prefix('(')?
+        .strip_suffix(')')?;
+
+    patterns
+        .iter()
+        .find(|candidate| candidate.as_str() == pattern)
+}
+
+/// Values the pointer points to, a wildcard expands into the items of an array or the properties
+/// of an object, in order.
+fn expand<'a>(tokens: &[Token], value: &'a Value, patterns: &[Pattern]) -> Vec<&'a Value> {
+    let Some((token, rest)) = tokens.split_first() else {
+        return vec![value];
+    };
+
+    if let (Value::Object(object), Some(pattern)) = (value, key_pattern(token, patterns)) {
+        return object
+            .iter()
+            .filter(|(key, _)| pattern.is_match(key))
+            .flat_map(|(_, value)| expand(rest, value, patterns))
+            .collect();
+    }
+
+    match value {
+        Value::Array(items) if token.as_str() == WILDCARD => items
+            .iter()
+            .flat_map(|item| expand(rest, item, patterns))
+            .collect(),
+        Value::Array(items) => token
+            .as_str()
+            .parse::<usize>()
+            .ok()
+            .and_then(|index| items.get(index))
+            .map_or_else(Vec::new, |item| expand(rest, item, patterns)),
+        Value::Object(object) if token.as_str() == WILDCARD => object
+            .values()
+            .flat_map(|value| expand(rest, value, patterns))
+            .collect(),
+        Value::Object(object) => object
+            .get(token.as_str())
+            .map_or_else(Vec::new, |value| expand(rest, value, patterns)),
+        _ => vec![],
+    }
+}
+
+/// Items of arrays instead of the arrays themselves.
+fn flatten<'a>(values: impl IntoIterator<Item = &'a Value>) -> impl Iterator<Item = &'a Value> {
+    values.into_iter().flat_map(|value| match value {
+        Value::Array(items) => items.iter().collect(),
+        value => vec![value],
+    })
+}
+
+/// Flatten arrays into their items and join the items into a string if there is a separator,
+/// objects cannot be joined and are left out.
+fn concat<'a>(values: impl IntoIterator<Item = &'a Value>, separator: Option<&str>) -> Value {
+    let items = flatten(values);
+
+    let Some(separator) = separator else {
+        return items.cloned().collect();
+    };
+
+    let strings: Vec<_> = items
+        .filter_map(|item| match item {
+            Value::String(string) => Some(string.clone()),
+            Value::Number(_) | Value::Bool(_) => Some(item.to_string()),
+            Value::Null | Value::Array(_) | Value::Object(_) => None,
+        })
+        .collect();
+
+    Value::String(strings.join(separator))
+}
+
+/// Merge the overlay into the base recursively, values of the overlay win unless both are objects.
+fn merge(base: Value, overlay: Value) -> Value {
+    match (base, overlay) {
+        (Value::Object(mut base), Value::Object(overlay)) => {
+            for (key, value) in overlay {
+                let merged = match base.remove(&key) {
+                    Some(existing) => merge(existing, value),
+                    None => value,
+                };
+
+                base.insert(key, merged);
+            }
+
+            Value::Object(base)
+        }
+        (_, overlay) => overlay,
+    }
+}
+
+/// Scope whose value is collected from the annotated traits.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
 pub(crate) struct ImplicitScope {
-    collect: Collect,
-    session_data: SessionData,
+    pub(crate) collect: Collect,
+    /// Which value wins on conflicts with `merge`
+    #[serde(default, skip_serializing_if = "Precedence::is_last")]
+    pub(crate) precedence: Precedence,
+    /// String the values are joined with by `concat`, e.g. `" "`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) separator: Option<String>,
+    pub(crate) session_data: SessionData,
+    /// Value used instead of `null` if none of the traits is present, e.g. `""` or `[]`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) default: Option<Value>,
+    /// Consent is refused if the scope is requested and its value is `null`
+    #[serde(default)]
+    pub(crate) required: bool,
+    /// Historical names of the scope, e.g. `mail` for `email`, which are granted as this scope
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) aliases: Vec<Scope>,
+    /// Scopes whose claims are issued as well if this scope is requested, e.g. `address` for
+    /// `profile`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) includes: Vec<Scope>,
+    /// Human-readable name of the scope, e.g. for a consent screen
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) title: Option<String>,
+    /// Human-readable explanation of what the scope releases
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) description: Option<String>,
+    /// Longest time in seconds the consent is remembered for if the scope is granted, `0` never
+    /// remembers it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) remember_for: Option<u64>,
+}
+
+/// State of the search for annotated traits in the schema of the traits.
+struct Traversal<'a> {
+    keywords: &'a [String],
+    /// Schema local references (e.g. `#/definitions/address`) are resolved against
+    document: Value,
+    /// References currently followed, following one of them again would never end
+    following: Vec<String>,
+    /// Schemas currently walked, nested into each other
+    depth: usize,
+    max_depth: usize,
+}
+
+impl<'a> Traversal<'a> {
+    fn new(options: &'a ParseOptions, schema: &SchemaObject) -> Self {
+        Self {
+            keywords: &options.keywords,
+            // serializing a `SchemaObject` cannot fail
+            document: serde_json::to_value(schema).unwrap_or_default(),
+            following: Vec::new(),
+            depth: 0,
+            max_depth: options.max_schema_depth,
+        }
+    }
+
+    fn resolve(&self, reference: &str) -> Option<SchemaObject> {
+        let pointer = jsonptr::Pointer::try_from(reference.strip_prefix('#')?).ok()?;
+        let schema = pointer.resolve(&self.document).ok()?;
+
+        serde_json::from_value::<Schema>(schema.clone())
+            .ok()
+            .map(Schema::into_object)
+    }
 }
 
 impl ImplicitScope {
-    fn find_object(keyword: &str, object: ObjectValidation, path: &[Token]) -> ImplicitScopeCache {
+    fn find_object(
+        object: ObjectValidation,
+        path: &[Token],
+        traversal: &mut Traversal,
+        problems: &mut Vec<Problem>,
+    ) -> ImplicitScopeCache {
         let mut pointers = ImplicitScopeCache::new();
 
-        for (key, value) in object.properties {
+        let mut properties: Vec<_> = object
+            .properties
+            .into_iter()
+            .map(|(key, value)| (Token::new(key), value))
+            .collect();
+
+        // the keys of map-like objects are only known for the traits of an identity
+        for (pattern, value) in object.pattern_properties {
+            match Pattern::try_from(pattern.clone()) {
+                Ok(compiled) => {
+                    pointers.insert_pattern(compiled);
+                    properties.push((Token::new(format!("{WILDCARD}({pattern})")), value));
+                }
+                Err(error) => problems.push(Problem::warning(
+                    &jsonptr::Pointer::new(path),
+                    format!(
+                        "pattern `{pattern}` of `patternProperties` is not a valid regular \
+                         expression, its properties are ignored: {error}"
+                    ),
+                )),
+            }
+        }
+
+        if let Some(additional) = object.additional_properties {
+            properties.push((Token::new(WILDCARD), *additional));
+        }
+
+        for (token, value) in properties {
             let mut path = path.to_vec();
 
-            path.push(Token::new(key));
+            path.push(token);
 
-            pointers.merge(Self::find(keyword, value.into_object(), path));
+            pointers.merge(Self::walk(value.into_object(), &path, traversal, problems));
         }
 
         pointers
     }
 
-    // This is not ideal, ideally we'd go through the user object (with schema in hand) and evaluate
-    // the schema for every entry. However, this is a lot of work and we're not sure if it's worth
-    // for a PoC. (also: I didn't find a way to do this with any of the existing crates)
-    pub(crate) fn find(
-        keyword: &str,
-        mut schema: SchemaObject,
-        path: Vec<Token>,
+    fn find_array(
+        array: ArrayValidation,
+        path: &[Token],
+        traversal: &mut Traversal,
+        problems: &mut Vec<Problem>,
     ) -> ImplicitScopeCache {
         let mut pointers = ImplicitScopeCache::new();
 
-        if let Some(object) = schema.object {
-            pointers.merge(Self::find_object(keyword, *object, &path));
+        // every item shares a schema, a tuple has a schema per position
+        let items: Vec<_> = match array.items {
+            None => vec![],
+            Some(SingleOrVec::Single(item)) => vec![(Token::new(WILDCARD), *item)],
+            Some(SingleOrVec::Vec(items)) => items
+                .into_iter()
+                .enumerate()
+                .map(|(index, item)| (Token::new(index.to_string()), item))
+                .collect(),
+        };
+
+        for (token, item) in items {
+            let mut path = path.to_vec();
+
+            path.push(token);
+
+            pointers.merge(Self::walk(item.into_object(), &path, traversal, problems));
         }
 
-        if let Some(extension) = schema.extensions.remove(keyword) {
-            let pointer = jsonptr::Pointer::new(path);
+        pointers
+    }
 
-            match serde_json::from_value::<TraitConfiguration>(extension.clone()) {
-                Ok(value) => {
-                    for scope in value.scopes {
-                        pointers.insert(scope, pointer.clone());
-                    }
-                }
-                Err(error) => {
-                    tracing::warn!(
-                        ?error,
-                        ?extension,
-                        "unable to deserialize trait configuration"
-                    );
+    fn follow(
+        reference: &str,
+        path: &[Token],
+        traversal: &mut Traversal,
+        problems: &mut Vec<Problem>,
+    ) -> ImplicitScopeCache {
+        if traversal
+            .following
+            .iter()
+            .any(|followed| followed == reference)
+        {
+            tracing::debug!(
+                reference,
+                "reference is recursive, it is only followed once"
+            );
+
+            return ImplicitScopeCache::new();
+        }
+
+        let Some(schema) = traversal.resolve(reference) else {
+            problems.push(Problem::warning(
+                &jsonptr::Pointer::new(path),
+                format!(
+                    "reference `{reference}` cannot be resolved, only references into the identity \
+                     schema are followed"
+                ),
+            ));
+
+            return ImplicitScopeCache::new();
+        };
+
+        traversal.following.push(reference.to_owned());
+        let pointers = Self::walk(schema, path, traversal, problems);
+        traversal.following.pop();
+
+        pointers
+    }
+
+    // This is not ideal, ideally we'd go through the user object (with schema in hand) and evaluate
+    // the schema for every entry. However, this is a lot of work and we're not sure if it's worth
+    // for a PoC. (also: I didn't find a way to do this with any of the existing crates)
+    pub(crate) fn find(
+        options: &ParseOptions,
+        schema: SchemaObject,
+        problems: &mut Vec<Problem>,
+    ) -> ImplicitScopeCache {
+        let mut traversal = Traversal::new(options, &schema);
+
+        Self::walk(schema, &[], &mut traversal, problems)
+    }
+
+    fn walk(
+        schema: SchemaObject,
+        path: &[Token],
+        traversal: &mut Traversal,
+        problems: &mut Vec<Problem>,
+    ) -> ImplicitScopeCache {
+        if traversal.depth >= traversal.max_depth {
+            problems.push(Problem::error(
+                &jsonptr::Pointer::new(path),
+                format!(
+                    "schema is nested deeper than {} levels (see `--max-schema-depth`), the \
+                     traits below are not searched for annotations",
+                    traversal.max_depth
+                ),
+            ));
+
+            return ImplicitScopeCache::new();
+        }
+
+        traversal.depth += 1;
+        let pointers = Self::walk_nested(schema, path, traversal, problems);
+        traversal.depth -= 1;
+
+        pointers
+    }
+
+    fn walk_nested(
+        mut schema: SchemaObject,
+        path: &[Token],
+        traversal: &mut Traversal,
+        problems: &mut Vec<Problem>,
+    ) -> ImplicitScopeCache {
+        let mut pointers = ImplicitScopeCache::new();
+
+        // the referenced schema is walked as if it was part of this one
+        if let Some(reference) = schema.reference.take() {
+            pointers.merge(Self::follow(&reference, path, traversal, problems));
+        }
+
+        if let Some(object) = schema.object {
+            pointers.merge(Self::find_object(*object, path, traversal, problems));
+        }
+
+        if let Some(array) = schema.array {
+            pointers.merge(Self::find_array(*array, path, traversal, problems));
+        }
+
+        // every branch of `allOf` applies to the same value, as if it was part of this schema,
+        // which branch of `oneOf` and `anyOf` applies is only known for the traits of an identity,
+        // their traits are collected nonetheless and are simply missing if another one applies
+        if let Some(subschemas) = schema.subschemas.take() {
+            let branches = [subschemas.all_of, subschemas.one_of, subschemas.any_of]
+                .into_iter()
+                .flatten()
+                .flatten();
+
+            for branch in branches {
+                pointers.merge(Self::walk(branch.into_object(), path, traversal, problems));
+            }
+        }
+
+        // at the root the keyword holds the scope configuration, read by `ScopeConfig`
+        if path.is_empty() {
+            return pointers;
+        }
+
+        let pointer = jsonptr::Pointer::new(path);
+
+        if let Some((keyword, extension)) = take_keyword(
+            traversal.keywords,
+            &mut schema.extensions,
+            &pointer,
+            problems,
+        ) {
+            match serde_json::from_value::<TraitConfiguration>(extension.clone()) {
+                Ok(value) => {
+                    for scope in value.scopes {
+                        pointers.insert(scope, pointer.clone());
+                    }
+
+                    if value.pii {
+                        pointers.insert_pii(pointer.clone());
+                    }
+
+                    if let Some(metadata) = &schema.metadata {
+                        pointers.insert_metadata(pointer.clone(), TraitMetadata {
+                            title: metadata.title.clone(),
+                            description: metadata.description.clone(),
+                        });
+                    }
+
+                    if let Some(claim) = value.claim {
+                        pointers.insert_claim(claim, pointer);
+                    }
+                }
+                Err(error) => {
+                    for problem in
+                        malformed::<TraitConfiguration>(keyword, &pointer, &extension, &error)
+                    {
+                        tracing::warn!(%problem, "unable to deserialize trait configuration");
+
+                        problems.push(problem);
+                    }
+                }
+            }
+        }
+
+        pointers
+    }
+
+    fn resolve<'a>(
+        &'a self,
+        scope: &Scope,
+        context: &Context,
+        cache: &ScopeCache,
+    ) -> IncompleteClaim<'a> {
+        let Some(pointers) = cache.implicit_scopes.get(scope) else {
+            tracing::warn!("unable to find scope in cache");
+
+            return IncompleteClaim {
+                value: self.default.clone().unwrap_or(Value::Null),
+                session_data: &self.session_data,
+            }
+        };
+
+        let mut values = vec![];
+
+        for pointer in pointers {
+            let tokens: Vec<_> = pointer.tokens().collect();
+            let expanded = expand(&tokens, context.traits(), cache.implicit_scopes.patterns());
+
+            if expanded.is_empty() {
+                tracing::warn!(?pointer, "unable to resolve pointer");
+            }
+
+            values.extend(expanded);
+        }
+
+        let missing = values.is_empty();
+
+        let value = match self.collect {
+            Collect::Any | Collect::First => {
+                values.into_iter().next().cloned().unwrap_or(Value::Null)
+            }
+            Collect::Last => values.pop().cloned().unwrap_or(Value::Null),
+            Collect::All => values.into_iter().cloned().collect(),
+            Collect::Verified => values
+                .iter()
+                .find(|value| context.is_verified(value))
+                .or_else(|| values.first())
+                .map_or(Value::Null, |value| (*value).clone()),
+            Collect::Merge => {
+                let values = values.into_iter().cloned();
+
+                match self.precedence {
+                    Precedence::First => values.rev().fold(Value::Null, merge),
+                    Precedence::Last => values.fold(Value::Null, merge),
+                }
+            }
+            Collect::Concat => concat(values, self.separator.as_deref()),
+            Collect::Count => Value::from(
+                values
+                    .iter()
+                    .map(|value| value.as_array().map_or(1, Vec::len))
+                    .sum::<usize>(),
+            ),
+            Collect::Unique => {
+                let mut unique = vec![];
+
+                for item in flatten(values) {
+                    if !unique.contains(item) {
+                        unique.push(item.clone());
+                    }
+                }
+
+                Value::Array(unique)
+            }
+        };
+
+        let value = match &self.default {
+            Some(default) if missing => default.clone(),
+            _ => value,
+        };
+
+        IncompleteClaim {
+            value,
+            session_data: &self.session_data,
+        }
+    }
+}
+
+/// Standard claim of a preset scope and where its value is written to.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub(crate) struct PresetClaim {
+    pub(crate) claim: String,
+    /// Standard claim holding an address, this claim is whether that address is verified instead
+    /// of the value of a tagged trait
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) verifies: Option<String>,
+    pub(crate) session_data: SessionData,
+}
+
+/// Standard scope of OIDC, whose claims are filled by the traits tagged with them.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub(crate) struct PresetScope {
+    pub(crate) claims: Vec<PresetClaim>,
+}
+
+impl PresetScope {
+    /// Every claim is the value of the first tagged trait that is present.
+    fn resolve<'a>(&'a self, context: &Context, cache: &ScopeCache) -> Vec<IncompleteClaim<'a>> {
+        self.claims
+            .iter()
+            .map(|preset| {
+                let claim = preset.verifies.as_ref().unwrap_or(&preset.claim);
+
+                let value = cache
+                    .implicit_scopes
+                    .claim(claim)
+                    .into_iter()
+                    .flatten()
+                    .find_map(|pointer| {
+                        let tokens: Vec<_> = pointer.tokens().collect();
+
+                        expand(&tokens, context.traits(), cache.implicit_scopes.patterns())
+                            .into_iter()
+                            .next()
+                    });
+
+                let value = match (value, &preset.verifies) {
+                    (None, _) => Value::Null,
+                    (Some(value), None) => value.clone(),
+                    (Some(value), Some(_)) => Value::Bool(context.is_verified(value)),
+                };
+
+                IncompleteClaim {
+                    value,
+                    session_data: &preset.session_data,
                 }
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Pointer(pub(crate) jsonptr::Pointer);
+
+impl Display for Pointer {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl JsonSchema for Pointer {
+    fn schema_name() -> String {
+        "Pointer".to_owned()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        SchemaObject {
+            metadata: Some(Box::new(Metadata {
+                description: Some("JSON pointer into the traits, e.g. `/name/first`".to_owned()),
+                ..Metadata::default()
+            })),
+            instance_type: Some(InstanceType::String.into()),
+            format: Some("json-pointer".to_owned()),
+            ..SchemaObject::default()
+        }
+        .into()
+    }
+}
+
+/// Part of a template.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Text(String),
+    Pointer(jsonptr::Pointer),
+}
+
+/// Text with pointers in braces, e.g. `{/name/first} {/name/last}`, `{{` and `}}` are literal
+/// braces.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub(crate) struct Template {
+    template: String,
+    segments: Vec<Segment>,
+}
+
+impl Template {
+    pub(crate) fn pointers(&self) -> impl Iterator<Item = &jsonptr::Pointer> {
+        self.segments.iter().filter_map(|segment| match segment {
+            Segment::Pointer(pointer) => Some(pointer),
+            Segment::Text(_) => None,
+        })
+    }
+
+    /// Pointers that are not present are left empty, `null` if none of them is present.
+    fn render(&self, document: &Value) -> Value {
+        let mut output = String::new();
+        let mut present = false;
+
+        for segment in &self.segments {
+            match segment {
+                Segment::Text(text) => output.push_str(text),
+                Segment::Pointer(pointer) => match pointer.resolve(document) {
+                    Ok(Value::Null) | Err(_) => {}
+                    Ok(Value::String(value)) => {
+                        present = true;
+                        output.push_str(value);
+                    }
+                    Ok(value) => {
+                        present = true;
+                        output.push_str(&value.to_string());
+                    }
+                },
+            }
+        }
+
+        if present || self.pointers().next().is_none() {
+            Value::String(output)
+        } else {
+            Value::Null
+        }
+    }
+}
+
+impl TryFrom<String> for Template {
+    type Error = String;
+
+    fn try_from(template: String) -> Result<Self, Self::Error> {
+        let mut segments = vec![];
+        let mut text = String::new();
+        let mut chars = template.chars().peekable();
+
+        while let Some(char) = chars.next() {
+            match char {
+                '{' | '}' if chars.peek() == Some(&char) => {
+                    chars.next();
+                    text.push(char);
+                }
+                '{' => {
+                    let mut pointer = String::new();
+
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(char) => pointer.push(char),
+                            None => return Err(format!("unclosed `{{` in template `{template}`")),
+                        }
+                    }
+
+                    let pointer =
+                        jsonptr::Pointer::try_from(pointer.as_str()).map_err(|error| {
+                            format!("invalid pointer `{pointer}` in template `{template}`: {error}")
+                        })?;
+
+                    if !text.is_empty() {
+                        segments.push(Segment::Text(core::mem::take(&mut text)));
+                    }
+
+                    segments.push(Segment::Pointer(pointer));
+                }
+                '}' => {
+                    return Err(format!(
+                        "unmatched `}}` in template `{template}`, `}}}}` is a literal brace"
+                    ));
+                }
+                char => text.push(char),
+            }
+        }
+
+        if !text.is_empty() {
+            segments.push(Segment::Text(text));
+        }
+
+        Ok(Self { template, segments })
+    }
+}
+
+impl From<Template> for String {
+    fn from(template: Template) -> Self {
+        template.template
+    }
+}
+
+impl Display for Template {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        f.write_str(&self.template)
+    }
+}
+
+impl JsonSchema for Template {
+    fn schema_name() -> String {
+        "Template".to_owned()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        SchemaObject {
+            metadata: Some(Box::new(Metadata {
+                description: Some(
+                    "Text with pointers in braces, e.g. `{/name/first} {/name/last}`".to_owned(),
+                ),
+                ..Metadata::default()
+            })),
+            instance_type: Some(InstanceType::String.into()),
+            ..SchemaObject::default()
+        }
+        .into()
+    }
+}
+
+/// Document a pointer of an explicit mapping is resolved in.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub(crate) enum Source {
+    /// Traits of the identity
+    #[default]
+    Traits,
+    /// Identity as returned by Kratos, e.g. `/metadata_public/tier`, `/created_at` or `/state`
+    Identity,
+    /// Credentials of the identity without their secrets, e.g. `/types` (`["oidc", "password"]`),
+    /// `/password/identifiers` or `/oidc/providers` (`["github"]`)
+    Credentials,
+    /// Authentication of the login, e.g. `/acr`, `/amr` or `/oidc_context/acr_values`, `null`
+    /// outside of a consent request
+    Login,
+    /// Relationships of the subject listed in Keto, keyed by the name of the relationship, e.g.
+    /// `/roles`, `null` unless Keto is configured
+    Keto,
+    /// Sessions of the subject listed in Kratos, `/last_login_at` and `/active_sessions`, `null`
+    /// unless `--list-sessions` is set
+    Sessions,
+    /// Response of the enrichment webhook, e.g. `/entitlements`, `null` unless a webhook is
+    /// configured
+    Webhook,
+    /// Documents of the claim sources an application embedding the router registered, keyed by
+    /// their name, e.g. `/crm/tier` of a source named `crm`
+    Custom,
+    /// Requested scope matched by a pattern, e.g. `/captures/0` for the part matched by the first
+    /// `*` or `/name`, `null` unless the scope is a pattern
+    Scope,
+}
+
+impl Source {
+    #[allow(clippy::trivially_copy_pass_by_ref)] // Reason: signature of `skip_serializing_if`
+    fn is_traits(&self) -> bool {
+        *self == Self::Traits
+    }
+}
+
+/// Predicate on a value, which holds if the value equals `equals` or, without it, if the value is
+/// present and neither `null` nor `false`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub(crate) struct Condition {
+    #[serde(rename = "$ref")]
+    pub(crate) ref_: Pointer,
+    #[serde(default, skip_serializing_if = "Source::is_traits")]
+    pub(crate) source: Source,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) equals: Option<Value>,
+}
+
+impl Condition {
+    fn holds(&self, context: &Context) -> bool {
+        let value = ScopeExplicitMappingKind::resolve_pointer(&self.ref_, self.source, context);
+
+        match (&self.equals, value) {
+            (Some(expected), Some(value)) => value == expected,
+            (Some(_), None) => false,
+            (None, value) => !matches!(value, None | Some(Value::Null | Value::Bool(false))),
+        }
+    }
+}
+
+/// Regular expression of a `replace` normalization, or of `patternProperties` in the schema of
+/// the traits.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub(crate) struct Pattern(Regex);
+
+impl Pattern {
+    fn as_str(&self) -> &str {
+        self.0.as_str()
+    }
+
+    fn is_match(&self, text: &str) -> bool {
+        self.0.is_match(text)
+    }
+}
+
+impl TryFrom<String> for Pattern {
+    type Error = regex::Error;
+
+    fn try_from(pattern: String) -> Result<Self, Self::Error> {
+        Regex::new(&pattern).map(Self)
+    }
+}
+
+impl From<Pattern> for String {
+    fn from(pattern: Pattern) -> Self {
+        pattern.0.as_str().to_owned()
+    }
+}
+
+impl PartialEq for Pattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+impl Eq for Pattern {}
+
+impl Display for Pattern {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        f.write_str(self.0.as_str())
+    }
+}
+
+impl JsonSchema for Pattern {
+    fn schema_name() -> String {
+        "Pattern".to_owned()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        SchemaObject {
+            instance_type: Some(InstanceType::String.into()),
+            format: Some("regex".to_owned()),
+            ..SchemaObject::default()
+        }
+        .into()
+    }
+}
+
+/// `JMESPath` expression of a `query` mapping, compiled once it is deserialized. One that does not
+/// compile is kept, so that it resolves to `null` and is reported by the lints, instead of failing
+/// the whole scope configuration.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "String", into = "String")]
+pub(crate) struct Query {
+    expression: String,
+    compiled: core::result::Result<jmespath::Expression<'static>, jmespath::JmespathError>,
+}
+
+impl Query {
+    pub(crate) fn as_str(&self) -> &str {
+        &self.expression
+    }
+
+    pub(crate) const fn compiled(
+        &self,
+    ) -> core::result::Result<&jmespath::Expression<'static>, &jmespath::JmespathError> {
+        self.compiled.as_ref()
+    }
+}
+
+impl From<String> for Query {
+    fn from(expression: String) -> Self {
+        let compiled = jmespath::compile(&expression);
+
+        Self {
+            expression,
+            compiled,
+        }
+    }
+}
+
+impl From<Query> for String {
+    fn from(query: Query) -> Self {
+        query.expression
+    }
+}
+
+impl PartialEq for Query {
+    fn eq(&self, other: &Self) -> bool {
+        self.expression == other.expression
+    }
+}
+
+impl Eq for Query {}
+
+impl Display for Query {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        f.write_str(&self.expression)
+    }
+}
+
+impl JsonSchema for Query {
+    fn is_referenceable() -> bool {
+        false
+    }
+
+    fn schema_name() -> String {
+        "Query".to_owned()
+    }
+
+    fn json_schema(generator: &mut SchemaGenerator) -> Schema {
+        String::json_schema(generator)
+    }
+}
+
+/// String operation applied to a resolved value, e.g. to normalize email addresses or strip
+/// phone numbers.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub(crate) enum Normalization {
+    Lowercase,
+    Uppercase,
+    Trim,
+    /// Every match of the pattern is replaced, `$1` or `${name}` refer to capture groups
+    Replace {
+        pattern: Pattern,
+        with: String,
+    },
+}
+
+impl Normalization {
+    fn apply(&self, value: &str) -> String {
+        match self {
+            Self::Lowercase => value.to_lowercase(),
+            Self::Uppercase => value.to_uppercase(),
+            Self::Trim => value.trim().to_owned(),
+            Self::Replace { pattern, with } => pattern.0.replace_all(value, with).into_owned(),
+        }
+    }
+}
+
+impl Display for Normalization {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Lowercase => f.write_str("lowercase"),
+            Self::Uppercase => f.write_str("uppercase"),
+            Self::Trim => f.write_str("trim"),
+            Self::Replace { pattern, with } => write!(f, "replace `{pattern}` with `{with}`"),
+        }
+    }
+}
+
+/// Apply the normalizations in order to strings, items of arrays are normalized individually and
+/// any other value is left as is.
+fn normalize(normalizations: &[Normalization], value: Value) -> Value {
+    match value {
+        Value::String(string) => Value::String(
+            normalizations
+                .iter()
+                .fold(string, |string, normalization| normalization.apply(&string)),
+        ),
+        Value::Array(items) => items
+            .into_iter()
+            .map(|item| normalize(normalizations, item))
+            .collect(),
+        value => value,
+    }
+}
+
+/// How a date is formatted, `rfc3339`, `epoch` for seconds since the Unix epoch or a format
+/// description, e.g. `[year]-[month]-[day]` for `birthdate`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub(crate) enum DateFormat {
+    #[default]
+    Rfc3339,
+    Epoch,
+    Description(String, OwnedFormatItem),
+}
+
+impl TryFrom<String> for DateFormat {
+    type Error = time::error::InvalidFormatDescription;
+
+    fn try_from(format: String) -> Result<Self, Self::Error> {
+        match format.as_str() {
+            "rfc3339" => Ok(Self::Rfc3339),
+            "epoch" => Ok(Self::Epoch),
+            _ => {
+                let description = format_description::parse_owned::<2>(&format)?;
+
+                Ok(Self::Description(format, description))
             }
         }
+    }
+}
+
+impl From<DateFormat> for String {
+    fn from(format: DateFormat) -> Self {
+        match format {
+            DateFormat::Rfc3339 => "rfc3339".to_owned(),
+            DateFormat::Epoch => "epoch".to_owned(),
+            DateFormat::Description(format, _) => format,
+        }
+    }
+}
+
+impl PartialEq for DateFormat {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Rfc3339, Self::Rfc3339) | (Self::Epoch, Self::Epoch) => true,
+            (Self::Description(left, _), Self::Description(right, _)) => left == right,
+            _ => false,
+        }
+    }
+}
+
+impl Eq for DateFormat {}
+
+impl Display for DateFormat {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Rfc3339 => f.write_str("rfc3339"),
+            Self::Epoch => f.write_str("epoch"),
+            Self::Description(format, _) => f.write_str(format),
+        }
+    }
+}
+
+impl JsonSchema for DateFormat {
+    fn schema_name() -> String {
+        "DateFormat".to_owned()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        SchemaObject {
+            metadata: Some(Box::new(Metadata {
+                description: Some(
+                    "`rfc3339`, `epoch` or a format description, e.g. `[year]-[month]-[day]`"
+                        .to_owned(),
+                ),
+                ..Metadata::default()
+            })),
+            instance_type: Some(InstanceType::String.into()),
+            ..SchemaObject::default()
+        }
+        .into()
+    }
+}
+
+/// UTC offset a date is converted to before it is formatted, e.g. `+02:00` or `Z`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub(crate) struct Offset(UtcOffset);
+
+impl Default for Offset {
+    fn default() -> Self {
+        Self(UtcOffset::UTC)
+    }
+}
+
+impl TryFrom<String> for Offset {
+    type Error = time::error::Parse;
+
+    fn try_from(offset: String) -> Result<Self, Self::Error> {
+        if offset == "Z" {
+            return Ok(Self(UtcOffset::UTC));
+        }
+
+        let description =
+            format_description::parse_borrowed::<2>("[offset_hour sign:mandatory]:[offset_minute]")
+                .expect("format description of the offset should be valid");
+
+        UtcOffset::parse(&offset, &description).map(Self)
+    }
+}
+
+impl From<Offset> for String {
+    fn from(offset: Offset) -> Self {
+        offset.to_string()
+    }
+}
+
+impl Display for Offset {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        let (hours, minutes, _) = self.0.as_hms();
+
+        if self.0.is_utc() {
+            f.write_str("Z")
+        } else {
+            let sign = if self.0.is_negative() { '-' } else { '+' };
+
+            write!(f, "{sign}{:02}:{:02}", hours.abs(), minutes.abs())
+        }
+    }
+}
+
+impl JsonSchema for Offset {
+    fn schema_name() -> String {
+        "Offset".to_owned()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        SchemaObject {
+            metadata: Some(Box::new(Metadata {
+                description: Some("UTC offset, e.g. `+02:00` or `Z`".to_owned()),
+                ..Metadata::default()
+            })),
+            instance_type: Some(InstanceType::String.into()),
+            ..SchemaObject::default()
+        }
+        .into()
+    }
+}
+
+/// Dates are parsed from RFC 3339 (e.g. `2023-06-05T12:00:00Z`), full dates (e.g. `2023-06-05`,
+/// at midnight UTC) or seconds since the Unix epoch and formatted in the offset.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub(crate) struct DateConversion {
+    #[serde(default)]
+    pub(crate) format: DateFormat,
+    #[serde(default)]
+    pub(crate) offset: Offset,
+}
+
+impl DateConversion {
+    fn parse(value: &Value) -> Option<OffsetDateTime> {
+        match value {
+            Value::Number(number) => OffsetDateTime::from_unix_timestamp(number.as_i64()?).ok(),
+            Value::String(string) => OffsetDateTime::parse(string, &Rfc3339).ok().or_else(|| {
+                let description = format_description::parse_borrowed::<2>("[year]-[month]-[day]")
+                    .expect("format description of a full date should be valid");
+
+                Date::parse(string, &description)
+                    .ok()
+                    .map(|date| date.midnight().assume_utc())
+            }),
+            _ => None,
+        }
+    }
+
+    fn format(&self, date: OffsetDateTime) -> Option<Value> {
+        let date = date.to_offset(self.offset.0);
+
+        match &self.format {
+            DateFormat::Rfc3339 => date.format(&Rfc3339).ok().map(Value::String),
+            DateFormat::Epoch => Some(Value::from(date.unix_timestamp())),
+            DateFormat::Description(_, description) => {
+                date.format(description).ok().map(Value::String)
+            }
+        }
+    }
+
+    /// Reformat the date, items of arrays are reformatted individually, `null` stays `null` and
+    /// values that are not a date become `null`.
+    fn apply(&self, value: Value) -> Value {
+        match value {
+            Value::Null => Value::Null,
+            Value::Array(items) => items.into_iter().map(|item| self.apply(item)).collect(),
+            value => Self::parse(&value)
+                .and_then(|date| self.format(date))
+                .unwrap_or_else(|| {
+                    tracing::warn!(?value, "unable to reformat value, not a date");
+
+                    Value::Null
+                }),
+        }
+    }
+}
+
+/// Type a resolved value is converted to, e.g. traits stored as strings that RPs expect as
+/// numbers.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub(crate) enum Coercion {
+    /// Numbers and booleans are formatted
+    String,
+    /// Strings are parsed as integer or float
+    Number,
+    /// Strings `true` and `false` are parsed, regardless of case
+    Boolean,
+}
+
+impl Coercion {
+    fn convert(self, value: &Value) -> Option<Value> {
+        match (self, value) {
+            (Self::String, Value::String(_))
+            | (Self::Number, Value::Number(_))
+            | (Self::Boolean, Value::Bool(_)) => Some(value.clone()),
+            (Self::String, Value::Number(number)) => Some(Value::String(number.to_string())),
+            (Self::String, Value::Bool(bool)) => Some(Value::String(bool.to_string())),
+            (Self::Number, Value::String(string)) => {
+                let string = string.trim();
+
+                string.parse::<i64>().map(Value::from).ok().or_else(|| {
+                    string
+                        .parse::<f64>()
+                        .ok()
+                        .and_then(serde_json::Number::from_f64)
+                        .map(Value::Number)
+                })
+            }
+            (Self::Boolean, Value::String(string)) => match string.trim() {
+                string if string.eq_ignore_ascii_case("true") => Some(Value::Bool(true)),
+                string if string.eq_ignore_ascii_case("false") => Some(Value::Bool(false)),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    const fn as_str(self) -> &'static str {
+        match self {
+            Self::String => "string",
+            Self::Number => "number",
+            Self::Boolean => "boolean",
+        }
+    }
+
+    /// Whether the value can be converted, `null` and arrays of convertible items included.
+    pub(crate) fn is_convertible(self, value: &Value) -> bool {
+        match value {
+            Value::Null => true,
+            Value::Array(items) => items.iter().all(|item| self.is_convertible(item)),
+            value => self.convert(value).is_some(),
+        }
+    }
 
-        pointers
+    /// Convert the value, items of arrays are converted individually, `null` stays `null` and
+    /// values that cannot be converted become `null`.
+    fn apply(self, value: Value) -> Value {
+        match value {
+            Value::Null => Value::Null,
+            Value::Array(items) => items.into_iter().map(|item| self.apply(item)).collect(),
+            value => self.convert(&value).unwrap_or_else(|| {
+                tracing::warn!(coercion = ?self, ?value, "unable to coerce value");
+
+                Value::Null
+            }),
+        }
     }
+}
 
-    fn resolve<'a>(
-        &'a self,
-        scope: &Scope,
-        traits: &Value,
-        cache: &ScopeCache,
-    ) -> IncompleteClaim<'a> {
-        let Some(pointers) = cache.implicit_scopes.get(scope) else {
-            tracing::warn!("unable to find scope in cache");
+impl Display for Coercion {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
 
-            return IncompleteClaim {
-                value: Value::Null,
-                session_data: &self.session_data,
-            }
-        };
+/// Node of the mapping of an explicit scope.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub(crate) struct ScopeExplicitMapping {
+    #[serde(flatten)]
+    pub(crate) kind: ScopeExplicitMappingKind,
+    /// String operations applied to the value in order, before it is coerced
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) normalize: Vec<Normalization>,
+    /// Date the value is parsed as and reformatted, after it is normalized
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) date: Option<DateConversion>,
+    /// Type the value is converted to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) coerce: Option<Coercion>,
+    /// Hash the value is replaced by, after it is coerced
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) hash: Option<Hash>,
+    /// Value used instead of `null`, e.g. `""` or `[]`, it is neither normalized nor coerced
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) default: Option<Value>,
+}
 
-        let mut values = vec![];
+impl ScopeExplicitMapping {
+    /// This mapping and every mapping nested in it, depth-first.
+    pub(crate) fn nodes(&self) -> Vec<&Self> {
+        let mut nodes = vec![self];
+        nodes.extend(self.kind.children().into_iter().flat_map(Self::nodes));
 
-        for pointer in pointers {
-            match pointer.resolve(traits) {
-                Ok(value) => {
-                    values.push(value);
-                }
-                Err(error) => {
-                    tracing::warn!(?error, ?pointer, "unable to resolve pointer");
-                }
-            }
-        }
+        nodes
+    }
 
-        let value = match self.collect {
-            Collect::Any | Collect::First => {
-                values.into_iter().next().cloned().unwrap_or(Value::Null)
-            }
-            Collect::Last => values.pop().cloned().unwrap_or(Value::Null),
-            Collect::All => values.into_iter().cloned().collect(),
+    fn resolve(&self, context: &Context) -> Value {
+        let value = normalize(&self.normalize, self.kind.resolve(context));
+        let value = match &self.date {
+            Some(date) => date.apply(value),
+            None => value,
         };
 
-        IncompleteClaim {
-            value,
-            session_data: &self.session_data,
-        }
-    }
-}
+        let value = match self.coerce {
+            Some(coercion) => coercion.apply(value),
+            None => value,
+        };
 
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
-pub(crate) struct Pointer(jsonptr::Pointer);
+        let value = match self.hash {
+            Some(hash) => hash::apply(hash, value),
+            None => value,
+        };
 
-impl Display for Pointer {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.0)
+        match (&self.default, value) {
+            (Some(default), Value::Null) => default.clone(),
+            (_, value) => value,
+        }
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+/// Shape of the value of an explicit scope.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
 #[serde(tag = "type", rename_all = "camelCase")]
-pub(crate) enum ScopeExplicitMapping {
+pub(crate) enum ScopeExplicitMappingKind {
+    /// Object with a mapping per key
     Object {
         properties: IndexMap<String, ScopeExplicitMapping>,
     },
+    /// Array with a mapping per item
     Tuple {
         #[serde(rename = "prefixItems")]
         items: Vec<ScopeExplicitMapping>,
     },
+    /// Value of a trait, `null` if it is not present
     Path {
         #[serde(rename = "$ref")]
         ref_: Pointer,
+        #[serde(default, skip_serializing_if = "Source::is_traits")]
+        source: Source,
+    },
+    /// Whether the trait is a verified address of the identity (e.g. for `email_verified`),
+    /// `null` if it is not present
+    Verified {
+        #[serde(rename = "$ref")]
+        ref_: Pointer,
+        #[serde(default, skip_serializing_if = "Source::is_traits")]
+        source: Source,
+    },
+    /// Value of the mapping passed through a transform exported by a WebAssembly plugin, `null`
+    /// if no plugin exports it or it fails
+    Transform {
+        transform: String,
+        value: Box<ScopeExplicitMapping>,
+    },
+    /// Result of a Rhai script, which has the documents of every source in scope (e.g.
+    /// `traits.name.first + " " + traits.name.last`), `null` if it fails
+    Expr { script: Script },
+    /// Result of a JMESPath query, which unlike a pointer can filter and project arrays (e.g.
+    /// `addresses[?primary].city | [0]`), `null` if it fails
+    Query {
+        jmespath: Query,
+        #[serde(default, skip_serializing_if = "Source::is_traits")]
+        source: Source,
+    },
+    /// Text with the values of pointers in braces (e.g. `{/name/first} {/name/last}`), pointers
+    /// that are not present are left empty, `null` if none of them is present
+    Template {
+        template: Template,
+        #[serde(default, skip_serializing_if = "Source::is_traits")]
+        source: Source,
+    },
+    /// Fixed value, e.g. a tenant identifier or the version of the schema
+    Const { value: Value },
+    /// First item that is not `null`, e.g. `preferred_username`, falling back to `username` and
+    /// then `email`
+    Coalesce { items: Vec<ScopeExplicitMapping> },
+    /// Mapping chosen by a condition on another value (e.g. `marketing_consent` only if
+    /// `/newsletter` is `true`), `null` if it does not hold and there is no `else`
+    If {
+        when: Condition,
+        then: Box<ScopeExplicitMapping>,
+        #[serde(rename = "else", default, skip_serializing_if = "Option::is_none")]
+        else_: Option<Box<ScopeExplicitMapping>>,
+    },
+    /// Array with the mapping applied to every item of an array (e.g. `/emails`), within the
+    /// mapping the `traits` source refers to the item, `null` if the value is not an array
+    Map {
+        over: Pointer,
+        #[serde(default, skip_serializing_if = "Source::is_traits")]
+        source: Source,
+        item: Box<ScopeExplicitMapping>,
     },
 }
 
-impl ScopeExplicitMapping {
-    fn resolve(&self, value: &Value) -> Value {
+impl ScopeExplicitMappingKind {
+    /// Mappings directly nested in this mapping.
+    pub(crate) fn children(&self) -> Vec<&ScopeExplicitMapping> {
+        match self {
+            Self::Object { properties } => properties.values().collect(),
+            Self::Tuple { items } | Self::Coalesce { items } => items.iter().collect(),
+            Self::Transform { value: mapping, .. } | Self::Map { item: mapping, .. } => {
+                vec![mapping]
+            }
+            Self::If { then, else_, .. } => {
+                let mut children: Vec<&ScopeExplicitMapping> = vec![then];
+                children.extend(else_.as_deref());
+
+                children
+            }
+            Self::Path { .. }
+            | Self::Verified { .. }
+            | Self::Expr { .. }
+            | Self::Query { .. }
+            | Self::Template { .. }
+            | Self::Const { .. } => vec![],
+        }
+    }
+
+    fn resolve_pointer<'a>(
+        pointer: &Pointer,
+        source: Source,
+        context: &'a Context,
+    ) -> Option<&'a Value> {
+        let pointer = &pointer.0;
+
+        match pointer.resolve(context.document(source)) {
+            Ok(value) => Some(value),
+            Err(error) => {
+                tracing::warn!(?error, ?pointer, "unable to resolve pointer");
+
+                None
+            }
+        }
+    }
+
+    fn query(query: &Query, document: &Value) -> Value {
+        let expression = query.as_str();
+        let result = query
+            .compiled()
+            .map_err(Clone::clone)
+            .and_then(|compiled| compiled.search(document))
+            .map(|result| serde_json::to_value(&*result));
+
+        match result {
+            Ok(Ok(value)) => value,
+            Ok(Err(error)) => {
+                tracing::warn!(%error, expression, "unable to convert result of query");
+
+                Value::Null
+            }
+            Err(error) => {
+                tracing::warn!(%error, expression, "unable to evaluate query");
+
+                Value::Null
+            }
+        }
+    }
+
+    fn resolve(&self, context: &Context) -> Value {
         match self {
             Self::Object { properties } => {
                 let mut object = serde_json::Map::new();
 
                 for (key, mapping) in properties {
-                    object.insert(key.clone(), mapping.resolve(value));
+                    object.insert(key.clone(), mapping.resolve(context));
                 }
 
                 Value::Object(object)
@@ -213,36 +2225,85 @@ impl ScopeExplicitMapping {
                 let mut array = Vec::with_capacity(items.len());
 
                 for mapping in items {
-                    array.push(mapping.resolve(value));
+                    array.push(mapping.resolve(context));
                 }
 
                 Value::Array(array)
             }
-            Self::Path { ref_ } => {
-                let pointer = &ref_.0;
-
-                match pointer.resolve(value) {
-                    Ok(value) => value.clone(),
-                    Err(error) => {
-                        tracing::warn!(?error, ?pointer, "unable to resolve pointer");
+            Self::Path { ref_, source } => {
+                Self::resolve_pointer(ref_, *source, context).map_or(Value::Null, Clone::clone)
+            }
+            Self::Verified { ref_, source } => Self::resolve_pointer(ref_, *source, context)
+                .map_or(Value::Null, |value| Value::Bool(context.is_verified(value))),
+            Self::Transform { transform, value } => {
+                plugin::transform(transform, &value.resolve(context))
+            }
+            Self::Expr { script } => script.evaluate(context),
+            Self::Query { jmespath, source } => Self::query(jmespath, context.document(*source)),
+            Self::Template { template, source } => template.render(context.document(*source)),
+            Self::Const { value } => value.clone(),
+            Self::Coalesce { items } => items
+                .iter()
+                .map(|mapping| mapping.resolve(context))
+                .find(|value| !value.is_null())
+                .unwrap_or(Value::Null),
+            Self::If { when, then, else_ } => {
+                if when.holds(context) {
+                    then.resolve(context)
+                } else {
+                    else_
+                        .as_ref()
+                        .map_or(Value::Null, |mapping| mapping.resolve(context))
+                }
+            }
+            Self::Map { over, source, item } => {
+                match Self::resolve_pointer(over, *source, context) {
+                    Some(Value::Array(values)) => values
+                        .iter()
+                        .map(|value| item.resolve(&context.with_traits(value.clone())))
+                        .collect(),
+                    Some(value) => {
+                        tracing::warn!(?over, ?value, "unable to map over value, not an array");
 
                         Value::Null
                     }
+                    None => Value::Null,
                 }
             }
         }
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+/// Scope whose value is built from the mapping.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
 pub(crate) struct ExplicitScope {
-    mapping: ScopeExplicitMapping,
-    session_data: SessionData,
+    pub(crate) mapping: ScopeExplicitMapping,
+    pub(crate) session_data: SessionData,
+    /// Consent is refused if the scope is requested and its value is `null`
+    #[serde(default)]
+    pub(crate) required: bool,
+    /// Historical names of the scope, e.g. `mail` for `email`, which are granted as this scope
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) aliases: Vec<Scope>,
+    /// Scopes whose claims are issued as well if this scope is requested, e.g. `address` for
+    /// `profile`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) includes: Vec<Scope>,
+    /// Human-readable name of the scope, e.g. for a consent screen
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) title: Option<String>,
+    /// Human-readable explanation of what the scope releases
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) description: Option<String>,
+    /// Longest time in seconds the consent is remembered for if the scope is granted, `0` never
+    /// remembers it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) remember_for: Option<u64>,
 }
 
 impl ExplicitScope {
-    fn resolve(&self, traits: &Value) -> IncompleteClaim {
-        let value = self.mapping.resolve(traits);
+    fn resolve(&self, context: &Context) -> IncompleteClaim {
+        let value = self.mapping.resolve(context);
 
         IncompleteClaim {
             value,
@@ -251,22 +2312,168 @@ impl ExplicitScope {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
 #[serde(tag = "type", rename_all = "camelCase")]
 pub(crate) enum ScopeConfiguration {
     Implicit(ImplicitScope),
     Explicit(ExplicitScope),
+    // only created from tagged traits with `--oidc-presets`
+    #[serde(skip_deserializing)]
+    #[schemars(skip)]
+    Preset(PresetScope),
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+impl ScopeConfiguration {
+    /// Where the values of the scope are written to, a preset writes one claim per standard claim.
+    pub(crate) fn session_data(&self) -> Vec<&SessionData> {
+        match self {
+            Self::Implicit(implicit) => vec![&implicit.session_data],
+            Self::Explicit(explicit) => vec![&explicit.session_data],
+            Self::Preset(preset) => preset
+                .claims
+                .iter()
+                .map(|claim| &claim.session_data)
+                .collect(),
+        }
+    }
+
+    /// Whether consent is refused if the value of the scope is `null`.
+    pub(crate) const fn is_required(&self) -> bool {
+        match self {
+            Self::Implicit(implicit) => implicit.required,
+            Self::Explicit(explicit) => explicit.required,
+            Self::Preset(_) => false,
+        }
+    }
+
+    /// Other names the scope is requested under.
+    pub(crate) fn aliases(&self) -> &[Scope] {
+        match self {
+            Self::Implicit(implicit) => &implicit.aliases,
+            Self::Explicit(explicit) => &explicit.aliases,
+            Self::Preset(_) => &[],
+        }
+    }
+
+    /// Scopes resolved together with this one.
+    pub(crate) fn includes(&self) -> &[Scope] {
+        match self {
+            Self::Implicit(implicit) => &implicit.includes,
+            Self::Explicit(explicit) => &explicit.includes,
+            Self::Preset(_) => &[],
+        }
+    }
+
+    /// Human-readable name and explanation of the scope.
+    pub(crate) fn describe(&self) -> (Option<&str>, Option<&str>) {
+        match self {
+            Self::Implicit(implicit) => {
+                (implicit.title.as_deref(), implicit.description.as_deref())
+            }
+            Self::Explicit(explicit) => {
+                (explicit.title.as_deref(), explicit.description.as_deref())
+            }
+            Self::Preset(_) => (None, None),
+        }
+    }
+
+    /// Longest time in seconds the consent may be remembered for if the scope is granted.
+    pub(crate) const fn remember_for(&self) -> Option<u64> {
+        match self {
+            Self::Implicit(implicit) => implicit.remember_for,
+            Self::Explicit(explicit) => explicit.remember_for,
+            Self::Preset(_) => None,
+        }
+    }
+}
+
+/// Names of the properties of the schema, including the ones of its `allOf`, `oneOf` and `anyOf`
+/// branches.
+fn properties(schema: &SchemaObject) -> Vec<&String> {
+    let mut keys: Vec<&String> = schema
+        .object
+        .iter()
+        .flat_map(|object| object.properties.keys())
+        .collect();
+
+    let branches = schema.subschemas.iter().flat_map(|subschemas| {
+        [&subschemas.all_of, &subschemas.one_of, &subschemas.any_of]
+            .into_iter()
+            .flatten()
+            .flatten()
+    });
+
+    for branch in branches {
+        if let Schema::Object(branch) = branch {
+            for key in properties(branch) {
+                if !keys.contains(&key) {
+                    keys.push(key);
+                }
+            }
+        }
+    }
+
+    keys
+}
+
+/// Configuration of the scopes at the root of the traits.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
 pub(crate) struct ScopeConfig {
     pub(crate) scopes: IndexMap<Scope, ScopeConfiguration>,
+    /// Value the subject is remapped to, e.g. an employee number, issued as `--subject-claim`, as
+    /// `sub` stays the identity ID
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) subject: Option<ScopeExplicitMapping>,
+    /// Key the claims of the access token are nested under, set from the parse options
+    #[serde(skip)]
+    pub(crate) access_token_key: Option<String>,
+    /// Scopes that are only granted and never resolved, set from the parse options
+    #[serde(skip)]
+    pub(crate) passthrough_scopes: Vec<Scope>,
+    /// Personally identifiable information is kept out of the access token, set from the parse
+    /// options
+    #[serde(skip)]
+    pub(crate) exclude_pii: bool,
+}
+
+/// JSON Schema of the keyword, which is either the configuration of the scopes at the root of the
+/// traits or the annotation of a trait.
+pub(crate) fn config_schema(keyword: &str) -> RootSchema {
+    let mut generator = SchemaSettings::draft07().into_generator();
+
+    let config = generator.subschema_for::<ScopeConfig>();
+    let annotation = generator.subschema_for::<TraitConfiguration>();
+
+    let schema = SchemaObject {
+        metadata: Some(Box::new(Metadata {
+            title: Some(keyword.to_owned()),
+            description: Some(format!(
+                "Value of `{keyword}` in the traits of an identity schema"
+            )),
+            ..Metadata::default()
+        })),
+        subschemas: Some(Box::new(SubschemaValidation {
+            one_of: Some(vec![config, annotation]),
+            ..SubschemaValidation::default()
+        })),
+        ..SchemaObject::default()
+    };
+
+    RootSchema {
+        meta_schema: generator.settings().meta_schema.clone(),
+        schema,
+        definitions: generator.take_definitions(),
+    }
 }
 
 impl ScopeConfig {
     fn empty() -> Self {
         Self {
             scopes: IndexMap::new(),
+            subject: None,
+            access_token_key: None,
+            passthrough_scopes: Vec::new(),
+            exclude_pii: false,
         }
     }
 
@@ -278,72 +2485,240 @@ impl ScopeConfig {
     pub(crate) fn resolve<'a>(
         &'a self,
         scope: &'a Scope,
-        traits: &Value,
+        context: &Context,
         cache: &ScopeCache,
-    ) -> Option<Claim<'a>> {
-        let mapping = self.find_scope(scope)?;
+    ) -> Vec<Claim<'a>> {
+        let Some(mapping) = self.find_scope(scope) else {
+            return vec![];
+        };
 
-        let claim = match mapping {
+        let claims = match mapping {
             ScopeConfiguration::Implicit(implicit) => {
                 tracing::debug!(?scope, "resolving implicit scope");
 
-                implicit.resolve(scope, traits, cache)
+                vec![implicit.resolve(scope, context, cache)]
             }
             ScopeConfiguration::Explicit(explicit) => {
                 tracing::debug!(?scope, "resolving explicit scope");
 
-                explicit.resolve(traits)
+                vec![explicit.resolve(context)]
+            }
+            ScopeConfiguration::Preset(preset) => {
+                tracing::debug!(?scope, "resolving preset scope");
+
+                preset.resolve(context, cache)
             }
+        };
+
+        claims
+            .into_iter()
+            .map(|claim| claim.complete(scope))
+            .collect()
+    }
+
+    /// Requested scopes together with the ones they include, transitively.
+    fn include(&self, requested: &HashSet<Scope>) -> HashSet<Scope> {
+        let mut included = requested.clone();
+        let mut pending: Vec<_> = requested.iter().cloned().collect();
+
+        while let Some(scope) = pending.pop() {
+            let configuration = self.find_scope(&scope).or_else(|| {
+                self.scopes
+                    .values()
+                    .find(|configuration| configuration.aliases().contains(&scope))
+            });
+
+            for child in configuration
+                .map(ScopeConfiguration::includes)
+                .unwrap_or_default()
+            {
+                if included.insert(child.clone()) {
+                    tracing::debug!(
+                        scope = scope.as_str(),
+                        child = child.as_str(),
+                        "scope includes another"
+                    );
+
+                    pending.push(child.clone());
+                }
+            }
+        }
+
+        included
+    }
+
+    /// Requested scopes the configured scope is resolved for, with the captures of its pattern.
+    /// Requested aliases are recorded together with the scope they are granted as.
+    fn requested(
+        scope: &Scope,
+        configuration: &ScopeConfiguration,
+        requested: &HashSet<Scope>,
+        aliases: &mut HashMap<Scope, Scope>,
+    ) -> Vec<(Scope, Vec<String>)> {
+        if scope.is_pattern() {
+            let mut matched: Vec<_> = requested
+                .iter()
+                .filter_map(|instance| {
+                    scope
+                        .captures(instance)
+                        .map(|captures| (instance.clone(), captures))
+                })
+                .collect();
+
+            // the requested scopes are unordered, claims written by several of them must not be
+            matched.sort();
+
+            return matched;
+        }
+
+        let matching: Vec<_> = configuration
+            .aliases()
+            .iter()
+            .filter(|alias| requested.contains(*alias))
+            .collect();
+
+        for alias in &matching {
+            aliases.insert((*alias).clone(), scope.clone());
         }
-        .complete(scope);
 
-        Some(claim)
+        if requested.contains(scope) || !matching.is_empty() {
+            vec![(scope.clone(), vec![])]
+        } else {
+            vec![]
+        }
     }
 
     #[tracing::instrument]
     pub(crate) fn resolve_all(
         &self,
-        traits: &Value,
+        context: &Context,
         cache: &ScopeCache,
         requested: &HashSet<Scope>,
     ) -> Claims {
+        let requested: HashSet<_> = requested
+            .iter()
+            .filter(|scope| {
+                let passthrough = self.passthrough_scopes.contains(scope);
+
+                if passthrough {
+                    tracing::trace!(scope = scope.as_str(), "scope passes through");
+                }
+
+                !passthrough
+            })
+            .cloned()
+            .collect();
+        let requested = &self.include(&requested);
+
         let mut claims = vec![];
+        let mut missing = vec![];
+        let mut aliases = HashMap::new();
+        let mut remember_for: Option<u64> = None;
+
+        for (scope, configuration) in &self.scopes {
+            for (instance, captures) in
+                Self::requested(scope, configuration, requested, &mut aliases)
+            {
+                if let Some(limit) = configuration.remember_for() {
+                    remember_for = Some(remember_for.map_or(limit, |current| current.min(limit)));
+                }
 
-        for scope in self.scopes.keys() {
-            if !requested.contains(scope) {
-                continue;
-            }
+                let resolved = if scope.is_pattern() {
+                    self.resolve(scope, &context.with_scope(&instance, &captures), cache)
+                } else {
+                    self.resolve(scope, context, cache)
+                };
+
+                if configuration.is_required() && resolved.iter().all(|claim| claim.value.is_null())
+                {
+                    tracing::debug!(scope = instance.as_str(), "required scope is missing");
+
+                    missing.push(instance.clone());
+                }
+
+                for claim in resolved {
+                    tracing::debug!(
+                        scope = claim.scope.as_str(),
+                        requested = instance.as_str(),
+                        value = ?claim.value,
+                        "resolved claim"
+                    );
 
-            if let Some(claim) = self.resolve(scope, traits, cache) {
-                claims.push(claim);
+                    claims.push((claim, captures.clone()));
+                }
             }
         }
 
-        let id_token = claims
-            .iter()
-            .filter_map(|claim| {
-                claim
-                    .session_data
-                    .id_token
-                    .clone()
-                    .map(|id_token| (id_token, claim.value.clone()))
-            })
-            .collect();
+        let mut id_token = serde_json::Map::new();
+        let mut access_token = serde_json::Map::new();
+        let mut nested = serde_json::Map::new();
+        let mut userinfo = serde_json::Map::new();
 
-        let access_token = claims
-            .into_iter()
-            .filter_map(|claim| {
-                claim
-                    .session_data
-                    .access_token
-                    .clone()
-                    .map(|access_token| (access_token, claim.value))
-            })
-            .collect();
+        for (claim, captures) in claims {
+            if let Some(target) = &claim.session_data.id_token {
+                target.place(&mut id_token, &claim.value, &captures);
+            }
+
+            let excluded = self.exclude_pii
+                && claim.session_data.pii
+                && !claim.session_data.pii_in_access_token;
+
+            if excluded {
+                tracing::debug!(
+                    scope = claim.scope.as_str(),
+                    "personally identifiable information is kept out of the access token"
+                );
+            }
+
+            if let Some(target) = claim
+                .session_data
+                .access_token
+                .as_ref()
+                .filter(|_| !excluded)
+            {
+                let nest = self.access_token_key.is_some()
+                    && claim.session_data.access_token_nested.unwrap_or(true);
+
+                if nest {
+                    target.place(&mut nested, &claim.value, &captures);
+                } else {
+                    target.place(&mut access_token, &claim.value, &captures);
+                }
+            }
+
+            if let Some(target) = &claim.session_data.userinfo {
+                target.place(&mut userinfo, &claim.value, &captures);
+            }
+        }
+
+        if let Some(key) = &self.access_token_key {
+            if !nested.is_empty() {
+                place(&mut access_token, key, Value::Object(nested));
+            }
+        }
 
         Claims {
             id_token: Value::Object(id_token),
             access_token: Value::Object(access_token),
+            userinfo: Value::Object(userinfo),
+            missing,
+            aliases,
+            remember_for,
+            subject: self.subject(context),
+        }
+    }
+
+    fn subject(&self, context: &Context) -> Option<String> {
+        let value = self.subject.as_ref()?.resolve(context);
+
+        match value {
+            Value::String(subject) => Some(subject),
+            Value::Number(subject) => Some(subject.to_string()),
+            value => {
+                tracing::warn!(?value, "remapped subject is neither a string nor a number");
+
+                None
+            }
         }
     }
 
@@ -360,25 +2735,118 @@ impl ScopeConfig {
 
             let mapping = ScopeConfiguration::Implicit(ImplicitScope {
                 collect: Collect::First,
+                precedence: Precedence::default(),
+                separator: None,
                 session_data: SessionData {
-                    id_token: Some(scope.as_str().to_owned()),
-                    access_token: Some(scope.as_str().to_owned()),
+                    id_token: Some(Target::Claim(scope.as_str().to_owned())),
+                    access_token: Some(Target::Claim(scope.as_str().to_owned())),
+                    userinfo: None,
+                    access_token_nested: None,
+                    pii: false,
+                    pii_in_access_token: false,
                 },
+                default: None,
+                required: false,
+                aliases: Vec::new(),
+                includes: Vec::new(),
+                title: None,
+                description: None,
+                remember_for: None,
             });
 
             self.scopes.insert(scope.clone(), mapping);
         }
     }
 
+    // presets are created for the standard scopes with tagged traits, which are not configured
+    // explicitly, they take precedence over traits annotated with the scope itself
+    fn insert_presets(&mut self, cache: &ScopeCache, problems: &mut Vec<Problem>) {
+        let mut presets: IndexMap<&str, Vec<PresetClaim>> = IndexMap::new();
+
+        for (claim, pointers) in cache.implicit_scopes.claims() {
+            let Some((scope, _)) = PRESETS
+                .iter()
+                .find(|(_, claims)| claims.contains(&claim.as_str()))
+            else {
+                for pointer in pointers {
+                    problems.push(Problem::warning(
+                        pointer,
+                        format!("`{claim}` is not a standard claim of OpenID Connect, it is not issued"),
+                    ));
+                }
+
+                continue;
+            };
+
+            presets.entry(scope).or_default().push(PresetClaim {
+                claim: claim.clone(),
+                verifies: None,
+                session_data: SessionData {
+                    id_token: Some(Target::Claim(claim.clone())),
+                    access_token: Some(Target::Claim(claim.clone())),
+                    userinfo: None,
+                    access_token_nested: None,
+                    pii: false,
+                    pii_in_access_token: false,
+                },
+            });
+        }
+
+        for (scope, standard) in PRESETS {
+            let Some(mut claims) = presets.remove(scope) else {
+                continue;
+            };
+
+            // relying parties expect the verification status alongside the address, Kratos
+            // already knows it
+            for (verified, address) in VERIFIED_CLAIMS {
+                let tagged = |name: &str| claims.iter().any(|claim| claim.claim == name);
+
+                if !tagged(address) || tagged(verified) {
+                    continue;
+                }
+
+                claims.push(PresetClaim {
+                    claim: (*verified).to_owned(),
+                    verifies: Some((*address).to_owned()),
+                    session_data: SessionData {
+                        id_token: Some(Target::Claim((*verified).to_owned())),
+                        access_token: Some(Target::Claim((*verified).to_owned())),
+                        userinfo: None,
+                        access_token_nested: None,
+                        pii: false,
+                        pii_in_access_token: false,
+                    },
+                });
+            }
+
+            let scope = Scope::new((*scope).to_owned());
+
+            if self.scopes.contains_key(&scope) {
+                problems.push(Problem::warning(
+                    &jsonptr::Pointer::root(),
+                    format!(
+                        "scope `{}` is configured explicitly, the tagged claims are ignored",
+                        scope.as_str()
+                    ),
+                ));
+
+                continue;
+            }
+
+            // the order of the specification, instead of the one of the schema
+            claims.sort_by_key(|claim| standard.iter().position(|name| *name == claim.claim));
+
+            self.scopes
+                .insert(scope, ScopeConfiguration::Preset(PresetScope { claims }));
+        }
+    }
+
     // direct mappings are automatic mappings for the first level of the object
     // we do not overwrite existing mappings
     fn insert_direct_mapping(&mut self, value: &SchemaObject, cache: &mut ScopeCache) {
-        let Some(object) = &value.object else {
-            return;
-        };
-
-        for key in object.properties.keys() {
-            let scope = Scope(key.clone());
+        for key in properties(value) {
+            let scope = Scope::from(key.clone());
 
             if self.scopes.contains_key(&scope) {
                 continue;
@@ -386,10 +2854,23 @@ impl ScopeConfig {
 
             let mapping = ScopeConfiguration::Implicit(ImplicitScope {
                 collect: Collect::First,
+                precedence: Precedence::default(),
+                separator: None,
                 session_data: SessionData {
-                    id_token: Some(key.clone()),
-                    access_token: Some(key.clone()),
+                    id_token: Some(Target::Claim(key.clone())),
+                    access_token: Some(Target::Claim(key.clone())),
+                    userinfo: None,
+                    access_token_nested: None,
+                    pii: false,
+                    pii_in_access_token: false,
                 },
+                default: None,
+                required: false,
+                aliases: Vec::new(),
+                includes: Vec::new(),
+                title: None,
+                description: None,
+                remember_for: None,
             });
 
             self.scopes.insert(scope.clone(), mapping);
@@ -400,36 +2881,135 @@ impl ScopeConfig {
         }
     }
 
-    fn create(keyword: &str, schema: &mut SchemaObject) -> Self {
-        let Some(value) = schema.extensions.remove(keyword) else {
-            tracing::warn!("unable to find {keyword} in identity schema");
+    /// Describe implicit scopes without a title and description with the ones of the first
+    /// annotated trait that has any.
+    fn describe(&mut self, cache: &ScopeCache) {
+        for (scope, configuration) in &mut self.scopes {
+            let ScopeConfiguration::Implicit(implicit) = configuration else {
+                continue;
+            };
+
+            if implicit.title.is_some() || implicit.description.is_some() {
+                continue;
+            }
+
+            let metadata = cache
+                .implicit_scopes
+                .get(scope)
+                .into_iter()
+                .flatten()
+                .find_map(|pointer| cache.implicit_scopes.metadata(pointer));
+
+            if let Some(metadata) = metadata {
+                implicit.title = metadata.title.clone();
+                implicit.description = metadata.description.clone();
+            }
+        }
+    }
+
+    /// Tag the implicit scopes and standard claims filled by traits that are personally
+    /// identifiable information.
+    fn tag_pii(&mut self, cache: &ScopeCache) {
+        let is_pii = |pointers: Option<&Vec<jsonptr::Pointer>>| {
+            pointers
+                .into_iter()
+                .flatten()
+                .any(|pointer| cache.implicit_scopes.is_pii(pointer))
+        };
+
+        for (scope, configuration) in &mut self.scopes {
+            match configuration {
+                ScopeConfiguration::Implicit(implicit) => {
+                    if is_pii(cache.implicit_scopes.get(scope)) {
+                        implicit.session_data.pii = true;
+                    }
+                }
+                ScopeConfiguration::Preset(preset) => {
+                    for claim in &mut preset.claims {
+                        let tagged = claim.verifies.as_ref().unwrap_or(&claim.claim);
+
+                        if is_pii(cache.implicit_scopes.claim(tagged)) {
+                            claim.session_data.pii = true;
+                        }
+                    }
+                }
+                ScopeConfiguration::Explicit(_) => {}
+            }
+        }
+    }
+
+    fn create(keywords: &[String], schema: &mut SchemaObject, problems: &mut Vec<Problem>) -> Self {
+        let Some((keyword, value)) =
+            take_keyword(keywords, &mut schema.extensions, &jsonptr::Pointer::root(), problems)
+        else {
+            tracing::warn!(?keywords, "unable to find any keyword in identity schema");
 
             return Self::empty();
         };
 
-        match serde_json::from_value::<Self>(value) {
+        match serde_json::from_value::<Self>(value.clone()) {
             Ok(this) => this,
             Err(error) => {
-                tracing::warn!(?error, "unable to deserialize {keyword} in identity schema");
+                for problem in malformed::<Self>(keyword, &jsonptr::Pointer::root(), &value, &error)
+                {
+                    tracing::warn!(%problem, "unable to deserialize {keyword} in identity schema");
+
+                    problems.push(problem);
+                }
 
                 Self::empty()
             }
         }
     }
 
+    /// Scopes of the overlay replace the ones of the same name, so does its subject.
+    fn overlay(&mut self, overlay: &Self) {
+        for (scope, configuration) in &overlay.scopes {
+            self.scopes.insert(scope.clone(), configuration.clone());
+        }
+
+        if overlay.subject.is_some() {
+            self.subject = overlay.subject.clone();
+        }
+    }
+
     pub(crate) fn from_root(
-        keyword: &str,
+        options: &ParseOptions,
+        id: Option<&str>,
         mut schema: SchemaObject,
         cache: &mut ScopeCache,
-        direct_mapping: bool,
+        problems: &mut Vec<Problem>,
     ) -> Self {
-        let mut this = Self::create(keyword, &mut schema);
+        let mut this = Self::create(&options.keywords, &mut schema, problems);
+        if let Some(overlay) = options.overlays.get(id) {
+            this.overlay(overlay);
+        }
+
+        this.access_token_key = options.access_token_key.clone();
+        this.passthrough_scopes = options.passthrough_scopes.clone();
+        this.exclude_pii = options.exclude_pii;
+
+        if options.oidc_presets {
+            this.insert_presets(cache, problems);
+        } else {
+            for (claim, pointers) in cache.implicit_scopes.claims() {
+                for pointer in pointers {
+                    problems.push(Problem::warning(
+                        pointer,
+                        format!("claim `{claim}` is only issued with `--oidc-presets`"),
+                    ));
+                }
+            }
+        }
 
         this.insert_implicit_mapping(cache);
-        if direct_mapping {
+        if options.direct_mapping {
             this.insert_direct_mapping(&schema, cache);
         }
 
+        this.tag_pii(cache);
+        this.describe(cache);
+
         this
     }
 }
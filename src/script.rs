@@ -0,0 +1,189 @@
+use core::fmt::{self, Display, Formatter};
+use std::sync::OnceLock;
+
+use error_stack::Report;
+use rhai::{Dynamic, Engine, EvalAltResult, ParseError, Scope, AST};
+use schemars::{gen::SchemaGenerator, schema::Schema, JsonSchema};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use thiserror::Error;
+
+use crate::schema::{Context, Source};
+
+/// Default number of operations a script may execute before it is aborted.
+pub(crate) const MAX_OPERATIONS: u64 = 100_000;
+
+/// Variables the documents of the context are bound to, named like the sources of pointers.
+const VARIABLES: &[(&str, Source)] = &[
+    ("traits", Source::Traits),
+    ("identity", Source::Identity),
+    ("credentials", Source::Credentials),
+    ("login", Source::Login),
+    ("keto", Source::Keto),
+    ("sessions", Source::Sessions),
+    ("webhook", Source::Webhook),
+    ("custom", Source::Custom),
+    ("scope", Source::Scope),
+];
+
+#[derive(Debug, Copy, Clone, Error)]
+#[error("scripts are limited to a different number of operations already")]
+pub(crate) struct Error;
+
+struct Scripts {
+    max_operations: u64,
+    engine: Engine,
+}
+
+// configured once at startup, like the plugins
+static ENGINE: OnceLock<Scripts> = OnceLock::new();
+
+fn create(max_operations: u64) -> Scripts {
+    let mut engine = Engine::new();
+    engine.set_max_operations(max_operations);
+
+    // scripts must not write to stdout, which is the output of the CLI
+    engine.on_print(|text| tracing::debug!(text, "script printed"));
+    engine.on_debug(|text, _, _| tracing::debug!(text, "script printed"));
+
+    Scripts {
+        max_operations,
+        engine,
+    }
+}
+
+/// Set the number of operations a script may execute, must be called before any script is run.
+/// The engine is shared by the whole process, setting a different number afterwards fails.
+pub(crate) fn configure(max_operations: u64) -> error_stack::Result<(), Error> {
+    let scripts = ENGINE.get_or_init(|| create(max_operations));
+
+    if scripts.max_operations != max_operations {
+        return Err(Report::new(Error)
+            .attach_printable(format!("configured with {}", scripts.max_operations)));
+    }
+
+    Ok(())
+}
+
+fn engine() -> &'static Engine {
+    &ENGINE.get_or_init(|| create(MAX_OPERATIONS)).engine
+}
+
+// scripts are compiled once they are deserialized, which may be before the engine is configured,
+// the budget only applies to evaluating them
+static PARSER: OnceLock<Engine> = OnceLock::new();
+
+fn parser() -> &'static Engine {
+    PARSER.get_or_init(Engine::new)
+}
+
+/// Rhai script of an `expr` mapping, compiled once it is deserialized. One that does not compile
+/// is kept, so that it resolves to `null` and is reported by the lints, instead of failing the
+/// whole scope configuration.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "String", into = "String")]
+pub(crate) struct Script {
+    source: String,
+    compiled: Result<AST, ParseError>,
+    /// Variables the script refers to, only their documents are converted when it is evaluated
+    variables: Vec<(&'static str, Source)>,
+}
+
+impl Script {
+    pub(crate) const fn compiled(&self) -> Result<&AST, &ParseError> {
+        self.compiled.as_ref()
+    }
+
+    fn run(&self, context: &Context) -> Result<Value, Box<EvalAltResult>> {
+        let ast = match &self.compiled {
+            Ok(ast) => ast,
+            Err(error) => return Err(error.clone().into()),
+        };
+
+        let mut scope = Scope::new();
+
+        for (name, source) in &self.variables {
+            let document = rhai::serde::to_dynamic(context.document(*source))?;
+            scope.push_constant(*name, document);
+        }
+
+        let result = engine().eval_ast_with_scope::<Dynamic>(&mut scope, ast)?;
+
+        rhai::serde::from_dynamic(&result)
+    }
+
+    /// Evaluate the script with the documents of the context in scope, `null` if it fails or
+    /// exceeds its budget.
+    pub(crate) fn evaluate(&self, context: &Context) -> Value {
+        self.run(context).unwrap_or_else(|error| {
+            tracing::warn!(%error, script = self.source, "unable to evaluate script");
+
+            Value::Null
+        })
+    }
+}
+
+/// Variables of [`VARIABLES`] the script refers to, every identifier that is one of them counts,
+/// even if it is part of a string. `eval` could refer to any of them.
+fn referenced(script: &str) -> Vec<(&'static str, Source)> {
+    let identifiers: Vec<_> = script
+        .split(|character: char| !(character.is_alphanumeric() || character == '_'))
+        .collect();
+
+    if identifiers.contains(&"eval") {
+        return VARIABLES.to_vec();
+    }
+
+    VARIABLES
+        .iter()
+        .filter(|(name, _)| identifiers.contains(name))
+        .copied()
+        .collect()
+}
+
+impl From<String> for Script {
+    fn from(source: String) -> Self {
+        let compiled = parser().compile(&source);
+        let variables = referenced(&source);
+
+        Self {
+            source,
+            compiled,
+            variables,
+        }
+    }
+}
+
+impl From<Script> for String {
+    fn from(script: Script) -> Self {
+        script.source
+    }
+}
+
+impl PartialEq for Script {
+    fn eq(&self, other: &Self) -> bool {
+        self.source == other.source
+    }
+}
+
+impl Eq for Script {}
+
+impl Display for Script {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl JsonSchema for Script {
+    fn is_referenceable() -> bool {
+        false
+    }
+
+    fn schema_name() -> String {
+        "Script".to_owned()
+    }
+
+    fn json_schema(generator: &mut SchemaGenerator) -> Schema {
+        String::json_schema(generator)
+    }
+}
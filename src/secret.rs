@@ -0,0 +1,68 @@
+use core::{convert::Infallible, fmt, str::FromStr};
+use std::path::{Path, PathBuf};
+
+use error_stack::{IntoReport, Result, ResultExt};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+#[error("unable to read secret from {}", .0.display())]
+pub(crate) struct Error(PathBuf);
+
+/// A value that must never end up in logs, its `Debug` implementation is redacted.
+#[derive(Clone, PartialEq, Eq)]
+pub(crate) struct Secret(String);
+
+impl Secret {
+    /// Read a secret from a file, as mounted by Kubernetes or Docker secrets. Trailing newlines are
+    /// not part of the secret.
+    pub(crate) fn from_file(path: &Path) -> Result<Self, Error> {
+        let value = std::fs::read_to_string(path)
+            .into_report()
+            .change_context_lazy(|| Error(path.to_path_buf()))?;
+
+        Ok(Self(value.trim_end_matches(['\r', '\n']).to_owned()))
+    }
+
+    /// Use the value if it has been given directly, otherwise read it from the `*_FILE` variant.
+    pub(crate) fn resolve(value: Option<Self>, file: Option<&Path>) -> Result<Option<Self>, Error> {
+        match (value, file) {
+            (Some(value), _) => Ok(Some(value)),
+            (None, Some(file)) => Self::from_file(file).map(Some),
+            (None, None) => Ok(None),
+        }
+    }
+
+    pub(crate) fn expose(&self) -> &str {
+        &self.0
+    }
+
+    /// Compare against a presented value in constant time (with regard to the content), to not
+    /// leak how much of the secret has been guessed correctly.
+    pub(crate) fn matches(&self, candidate: &str) -> bool {
+        let (secret, candidate) = (self.0.as_bytes(), candidate.as_bytes());
+
+        if secret.len() != candidate.len() {
+            return false;
+        }
+
+        secret
+            .iter()
+            .zip(candidate)
+            .fold(0, |difference, (lhs, rhs)| difference | (lhs ^ rhs))
+            == 0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret(***)")
+    }
+}
+
+impl FromStr for Secret {
+    type Err = Infallible;
+
+    fn from_str(value: &str) -> core::result::Result<Self, Self::Err> {
+        Ok(Self(value.to_owned()))
+    }
+}
@@ -1,82 +1,462 @@
-use std::{collections::HashSet, net::SocketAddr, sync::Arc};
+use alloc::sync::Arc;
+use core::{convert::Infallible, time::Duration};
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::OsString,
+    path::PathBuf,
+};
 
-use axum::{response::Redirect, routing::get, Json, Server};
-use error_stack::{IntoReport, Report, Result, ResultExt};
-use ory_hydra_client::models::{AcceptOAuth2ConsentRequest, AcceptOAuth2ConsentRequestSession};
+use axum::{
+    extract::FromRequestParts,
+    http::{header, request::Parts, HeaderMap, HeaderValue, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Redirect, Response},
+    routing::{delete, get, post},
+    Json,
+};
+use clap::{Args as _, FromArgMatches, ValueHint};
+use error_stack::{Report, Result, ResultExt};
+use indexmap::IndexMap;
+use ory_hydra_client::models::{
+    AcceptOAuth2ConsentRequest, AcceptOAuth2ConsentRequestSession, OAuth2Client,
+    OAuth2ConsentRequest, RejectOAuth2Request,
+};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use thiserror::Error;
-use tower_http::trace::TraceLayer;
+use tower_http::{
+    request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer},
+    trace::TraceLayer,
+};
 use url::Url;
 
 use crate::{
+    breaker::{BreakerState, CircuitOpen},
     cache::{SchemaCache, SchemaId},
-    schema::Scope,
+    consents::{self, Consent},
+    history,
+    keto::{KetoConfig, KetoSource, Relationship},
+    lint,
+    listen::{self, Address},
+    middleware::{
+        self, AdminTokenConfig, AllowlistConfig, ClientIpConfig, EndpointTokenConfig, LimitConfig,
+        RateLimitConfig, SecurityHeadersConfig, TokenHookTokenConfig,
+    },
+    namespace::NamespaceConfig,
+    overlay::{Location, Overlays, Remote, Watch},
+    revoke,
+    schema::{Claims, Context, ParseOptions, Scope},
+    secret::Secret,
+    sessions::SessionsSource,
+    size::SizeConfig,
+    source::{self, ClaimSource},
+    store::{Grant, Store, StoreConfig},
+    telemetry::Metrics,
+    tenant::{self, Selector, TenantConfig, Tenants},
+    upstream::{Credentials, Hydra, Keto, Kratos, TimedOut, UpstreamConfig, Upstreams, Webhook},
+    webhook::{WebhookConfig, WebhookSource},
 };
 
 type SharedState = Arc<State>;
 
+/// State of the routes, the tenants and what they share.
+#[derive(Debug)]
+struct Server {
+    tenants: Tenants<SharedState>,
+    metrics: Metrics,
+}
+
+type SharedServer = Arc<Server>;
+
+/// State of the tenant the request is for, as selected by `select_tenant`.
+#[derive(Clone)]
+struct Tenant(SharedState);
+
+#[axum::async_trait]
+impl FromRequestParts<SharedServer> for Tenant {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        server: &SharedServer,
+    ) -> core::result::Result<Self, Self::Rejection> {
+        Ok(parts
+            .extensions
+            .get::<Self>()
+            .cloned()
+            .unwrap_or_else(|| Self(Arc::clone(server.tenants.default()))))
+    }
+}
+
+/// Name of the tenant the request is for, `None` for the top-level options.
+async fn requested_tenant(server: &SharedServer, parts: &mut Parts) -> Option<String> {
+    match server.tenants.selector() {
+        Selector::Host => {
+            let host = parts.headers.get(header::HOST)?.to_str().ok()?;
+
+            server.tenants.host(host).map(ToOwned::to_owned)
+        }
+        // routes outside of `/<tenant>` have no parameters
+        Selector::Path => {
+            axum::extract::Path::<HashMap<String, String>>::from_request_parts(parts, server)
+                .await
+                .ok()?
+                .remove("tenant")
+        }
+        Selector::Query => url::form_urlencoded::parse(parts.uri.query()?.as_bytes())
+            .find(|(key, _)| key == "tenant")
+            .map(|(_, value)| value.into_owned()),
+    }
+}
+
+/// Select the tenant of the request, which handles it and labels what is recorded meanwhile.
+async fn select_tenant<B: Send>(
+    axum::extract::State(server): axum::extract::State<SharedServer>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let (mut parts, body) = request.into_parts();
+    let name = requested_tenant(&server, &mut parts).await;
+    let mut request = Request::from_parts(parts, body);
+
+    let state = match &name {
+        Some(name) => match server.tenants.named(name) {
+            Some(state) => state,
+            None => return ErrorResponse(Report::new(Error::UnknownTenant)).into_response(),
+        },
+        None => server.tenants.default(),
+    };
+
+    request.extensions_mut().insert(Tenant(Arc::clone(state)));
+
+    middleware::scope_tenant(name.unwrap_or_default(), request, next).await
+}
+
 #[derive(Debug)]
 struct State {
-    kratos: ory_kratos_client::apis::configuration::Configuration,
-    hydra: ory_hydra_client::apis::configuration::Configuration,
+    kratos: Arc<Kratos>,
+    kratos_public: Option<Kratos>,
+    verify_session: bool,
+    record_consent: bool,
+    subject_claim: String,
+    hydra: Hydra,
+    keto: Option<Arc<Keto>>,
+    webhook: Option<Arc<Webhook>>,
+    /// Fetched in order for every request the claims are resolved for
+    sources: Vec<Arc<dyn ClaimSource>>,
 
+    size: SizeConfig,
+    namespace: NamespaceConfig,
+    /// Key the claims of the access token are nested under, see [`ParseOptions`]
+    access_token_key: Option<String>,
+    remediation_url: Option<Url>,
+    remember_for: Option<Duration>,
+
+    store: Option<Store>,
     cache: SchemaCache,
 }
 
 #[derive(Debug, Copy, Clone, Error)]
-pub(crate) enum Error {
+pub enum Error {
     #[error("API error to Hydra")]
     Hydra,
     #[error("API error to Kratos")]
     Kratos,
+    #[error("unable to fetch a claim source")]
+    Source,
     #[error("request does not contain subject")]
     SubjectMissing,
+    #[error("access token is missing or inactive")]
+    InvalidToken,
     #[error("unable to fetch schema from Kratos")]
     IdentitySchema,
+    #[error("unable to configure upstream clients")]
+    Upstream,
+    #[error("unable to open the grant store")]
+    Store,
+    #[error("request to upstream timed out")]
+    Timeout,
+    #[error("upstream is unavailable")]
+    Unavailable,
+    #[error("unable to listen for connections")]
+    Listen,
+    #[error("unable to read endpoint token")]
+    EndpointToken,
+    #[error("unable to read admin token")]
+    AdminToken,
+    #[error("unable to read token hook token")]
+    TokenHookToken,
+    #[error("unable to revoke the consent")]
+    Revoke,
+    #[error("unable to list the consents")]
+    Consents,
+    #[error("unable to read webhook token")]
+    WebhookToken,
+    #[error("unable to watch the scope configuration")]
+    ScopeConfig,
+    #[error("unable to configure the tenants")]
+    Tenants,
+    #[error("claim source is registered more than once")]
+    DuplicateSource,
+    #[error("tenant of the request is unknown")]
+    UnknownTenant,
+    #[error("verifying sessions requires the public URL of Kratos")]
+    KratosPublicUrl,
+    #[error("browser has no session of the subject")]
+    SessionMismatch,
 }
 
-async fn handle_consent(state: &State, challenge: &str) -> Result<Redirect, Error> {
-    let request =
-        ory_hydra_client::apis::o_auth2_api::get_o_auth2_consent_request(&state.hydra, challenge)
-            .await
-            .into_report()
-            .change_context(Error::Hydra)?;
+impl Error {
+    const fn status(self) -> StatusCode {
+        match self {
+            Self::Hydra
+            | Self::Kratos
+            | Self::Source
+            | Self::Revoke
+            | Self::Consents
+            | Self::IdentitySchema
+            | Self::SubjectMissing => StatusCode::BAD_GATEWAY,
+            Self::Upstream
+            | Self::Store
+            | Self::Listen
+            | Self::EndpointToken
+            | Self::AdminToken
+            | Self::TokenHookToken
+            | Self::WebhookToken
+            | Self::ScopeConfig
+            | Self::Tenants
+            | Self::DuplicateSource
+            | Self::KratosPublicUrl => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::SessionMismatch => StatusCode::FORBIDDEN,
+            Self::UnknownTenant => StatusCode::NOT_FOUND,
+            Self::InvalidToken => StatusCode::UNAUTHORIZED,
+            Self::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            Self::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+}
 
-    tracing::debug!(?request, "fetched consent request from hydra");
+struct ErrorResponse(Report<Error>);
 
-    // fetch all info from kratos
-    let subject = request
-        .subject
-        .ok_or_else(|| Report::new(Error::SubjectMissing))?;
+impl From<Report<Error>> for ErrorResponse {
+    fn from(report: Report<Error>) -> Self {
+        // timeouts may happen deep down (e.g. while filling the schema cache), surface them
+        if report.contains::<TimedOut>() {
+            return Self(report.change_context(Error::Timeout));
+        }
 
-    let identity =
-        ory_kratos_client::apis::identity_api::get_identity(&state.kratos, &subject, None)
-            .await
-            .into_report()
-            .change_context(Error::Kratos)?;
+        if report.contains::<CircuitOpen>() {
+            return Self(report.change_context(Error::Unavailable));
+        }
+
+        Self(report)
+    }
+}
+
+impl IntoResponse for ErrorResponse {
+    fn into_response(self) -> Response {
+        let status = self.0.current_context().status();
+        let unauthorized = status == StatusCode::UNAUTHORIZED;
+
+        let report = match middleware::request_id() {
+            Some(id) => self.0.attach_printable(format!("request id: {id}")),
+            None => self.0,
+        };
+
+        let mut response = (status, Json(report)).into_response();
+
+        if unauthorized {
+            response.headers_mut().insert(
+                header::WWW_AUTHENTICATE,
+                HeaderValue::from_static(r#"Bearer error="invalid_token""#),
+            );
+        }
+
+        response
+    }
+}
+
+/// Resolve the claims of the subject for the requested scopes, `None` if the identity has no
+/// traits. The consent request (if any) is used for the login context.
+async fn resolve(
+    state: &State,
+    subject: &str,
+    client_id: Option<&str>,
+    requested_scope: &[String],
+    request: Option<&OAuth2ConsentRequest>,
+) -> Result<Option<Claims>, Error> {
+    // fetch all info from kratos
+    let identity = state
+        .kratos
+        .scoped()
+        .call(Error::Kratos, |kratos| {
+            ory_kratos_client::apis::identity_api::get_identity(kratos, subject, None)
+        })
+        .await?;
 
     tracing::debug!(?identity, "fetched identity from kratos");
 
     let schema = state
         .cache
-        .fetch(&state.kratos, &SchemaId::new(identity.schema_id))
+        .fetch(&state.kratos, &SchemaId::new(identity.schema_id.clone()))
         .await
         .change_context(Error::IdentitySchema)?;
 
-    let scopes: HashSet<_> = request
-        .requested_scope
+    let scopes: HashSet<_> = requested_scope.iter().cloned().map(Scope::new).collect();
+
+    let Some(context) = Context::from_identity(&identity) else {
+        return Ok(None);
+    };
+
+    let mut context = match request {
+        Some(request) => context.with_login(request),
+        None => context,
+    };
+
+    let tenant = middleware::tenant();
+    let source_request = source::Request {
+        identity: &identity,
+        subject,
+        client_id,
+        requested_scope,
+        tenant: &tenant,
+    };
+
+    for source in &state.sources {
+        let document = source
+            .fetch(&source_request)
+            .await
+            .attach_printable_lazy(|| format!("source `{}`", source.name()))
+            .change_context(Error::Source)?;
+
+        context = context.with_source(source.name(), document);
+    }
+
+    let mut claims = schema.resolve(&context, &scopes);
+
+    // `sub` of `/userinfo` must equal the one of the ID token, which keeps the identity ID
+    if let Some(subject) = &claims.subject {
+        for claims in [&mut claims.id_token, &mut claims.userinfo] {
+            if let Value::Object(claims) = claims {
+                claims.insert(state.subject_claim.clone(), Value::String(subject.clone()));
+            }
+        }
+    }
+
+    Ok(Some(claims))
+}
+
+/// Ensure the browser has an active session of the subject in Kratos, so that consent is only
+/// given by the user Hydra asks it for.
+async fn verify_session(state: &State, subject: &str, cookie: Option<&str>) -> Result<(), Error> {
+    let Some(kratos) = &state.kratos_public else {
+        return Err(Report::new(Error::KratosPublicUrl));
+    };
+
+    let Some(cookie) = cookie else {
+        return Err(Report::new(Error::SessionMismatch));
+    };
+
+    let session = kratos
+        .scoped()
+        .call(Error::Kratos, |kratos| async move {
+            // without a valid session Kratos responds with `401` or `403`
+            match ory_kratos_client::apis::frontend_api::to_session(kratos, None, Some(cookie))
+                .await
+            {
+                Err(ory_kratos_client::apis::Error::ResponseError(response))
+                    if response.status.is_client_error() =>
+                {
+                    Ok(None)
+                }
+                result => result.map(Some),
+            }
+        })
+        .await?;
+
+    let verified = session.map_or(false, |session| {
+        session.active == Some(true) && session.identity.id == subject
+    });
+
+    if !verified {
+        tracing::info!(
+            subject,
+            "refusing consent, browser has no session of the subject"
+        );
+
+        return Err(Report::new(Error::SessionMismatch));
+    }
+
+    Ok(())
+}
+
+async fn handle_consent(
+    state: &State,
+    challenge: &str,
+    cookie: Option<&str>,
+) -> Result<Redirect, Error> {
+    let request = state
+        .hydra
+        .scoped()
+        .call(Error::Hydra, |hydra| {
+            ory_hydra_client::apis::o_auth2_api::get_o_auth2_consent_request(hydra, challenge)
+        })
+        .await?;
+
+    tracing::debug!(?request, "fetched consent request from hydra");
+
+    let subject = request
+        .subject
         .clone()
-        .unwrap_or_default()
-        .into_iter()
-        .map(Scope::new)
-        .collect();
+        .ok_or_else(|| Report::new(Error::SubjectMissing))?;
+
+    if state.verify_session {
+        verify_session(state, &subject, cookie).await?;
+    }
+
+    let client_id = request
+        .client
+        .as_ref()
+        .and_then(|client| client.client_id.as_deref());
+
+    let session = resolve(
+        state,
+        &subject,
+        client_id,
+        request.requested_scope.as_deref().unwrap_or_default(),
+        Some(&request),
+    )
+    .await?;
+
+    if let Some(missing) = session
+        .as_ref()
+        .map(|session| &session.missing)
+        .filter(|missing| !missing.is_empty())
+    {
+        return refuse(state, challenge, request.request_url.as_deref(), missing).await;
+    }
+
+    let client = request.client.as_deref();
+
+    let grant_scope = session.as_ref().map_or_else(
+        || request.requested_scope.clone(),
+        |session| grant(request.requested_scope.as_deref(), &session.aliases),
+    );
 
-    let session = identity
-        .traits
-        .map(|traits| schema.resolve(&traits, &scopes));
+    let (remember, remember_for) = remember(
+        state.remember_for,
+        session.as_ref().and_then(|session| session.remember_for),
+    );
 
     let (id_token, access_token) = if let Some(session) = session {
-        (Some(session.id_token), Some(session.access_token))
+        let nested = state.access_token_key.as_deref();
+        let id_token = state.namespace.apply(client, session.id_token, None);
+        let access_token = state.namespace.apply(client, session.access_token, nested);
+
+        (
+            Some(state.size.apply("id_token", id_token, None)),
+            Some(state.size.apply("access_token", access_token, nested)),
+        )
     } else {
         (None, None)
     };
@@ -84,24 +464,135 @@ async fn handle_consent(state: &State, challenge: &str) -> Result<Redirect, Erro
     tracing::debug!(?id_token, ?access_token, "resolved session");
 
     // we automatically skip consent, always
-    let response = ory_hydra_client::apis::o_auth2_api::accept_o_auth2_consent_request(
-        &state.hydra,
-        challenge,
-        Some(&AcceptOAuth2ConsentRequest {
-            grant_access_token_audience: request.requested_access_token_audience,
-            grant_scope: request.requested_scope,
-            handled_at: None,
-            remember: None,
-            remember_for: None,
-            session: Some(Box::new(AcceptOAuth2ConsentRequestSession {
-                access_token,
-                id_token,
-            })),
-        }),
-    )
-    .await
-    .into_report()
-    .change_context(Error::Hydra)?;
+    let accept = AcceptOAuth2ConsentRequest {
+        grant_access_token_audience: request.requested_access_token_audience,
+        grant_scope,
+        handled_at: None,
+        remember,
+        remember_for,
+        session: Some(Box::new(AcceptOAuth2ConsentRequestSession {
+            access_token,
+            id_token,
+        })),
+    };
+
+    let response = state
+        .hydra
+        .scoped()
+        .call(Error::Hydra, |hydra| {
+            ory_hydra_client::apis::o_auth2_api::accept_o_auth2_consent_request(
+                hydra,
+                challenge,
+                Some(&accept),
+            )
+        })
+        .await?;
+
+    if state.record_consent {
+        let scopes = accept.grant_scope.as_deref().unwrap_or_default();
+
+        // the consent is already accepted, a missing record must not fail the login
+        if let Err(error) = history::record(&state.kratos, &subject, client_id, scopes).await {
+            tracing::warn!(?error, "unable to record the consent in the identity");
+        }
+    }
+
+    if let Some(store) = &state.store {
+        let grant = Grant::now(
+            subject,
+            client_id.map(ToOwned::to_owned),
+            accept.grant_scope.unwrap_or_default(),
+            serde_json::to_value(accept.session).unwrap_or_default(),
+        );
+
+        // like the record in the identity, the consent is accepted regardless
+        if let Err(error) = store.record(&grant).await {
+            tracing::warn!(?error, "unable to record the consent in the grant store");
+        }
+    }
+
+    Ok(Redirect::to(&response.redirect_to))
+}
+
+/// Scopes to grant, requested aliases are granted as the scope they belong to.
+fn grant(requested: Option<&[String]>, aliases: &HashMap<Scope, Scope>) -> Option<Vec<String>> {
+    let requested = requested?;
+    let mut granted: Vec<String> = Vec::with_capacity(requested.len());
+
+    for scope in requested {
+        let scope = aliases
+            .get(&Scope::new(scope.clone()))
+            .map_or(scope.as_str(), Scope::as_str);
+
+        if !granted.iter().any(|granted| granted == scope) {
+            granted.push(scope.to_owned());
+        }
+    }
+
+    Some(granted)
+}
+
+/// Whether and for how many seconds Hydra remembers the consent, the shortest of `--remember-for`
+/// and the limits of the resolved scopes, a limit of `0` is never remembered.
+fn remember(global: Option<Duration>, limit: Option<u64>) -> (Option<bool>, Option<i64>) {
+    let Some(global) = global else {
+        return (None, None);
+    };
+
+    // Hydra remembers a consent with `remember_for` of `0` indefinitely
+    let seconds = match (global.as_secs(), limit) {
+        (_, Some(0)) => return (Some(false), None),
+        (0, limit) => limit.unwrap_or(0),
+        (global, limit) => limit.map_or(global, |limit| global.min(limit)),
+    };
+
+    (Some(true), Some(i64::try_from(seconds).unwrap_or(i64::MAX)))
+}
+
+/// Send the user to the remediation URL if there is one, otherwise reject the consent request,
+/// because required scopes cannot be resolved.
+async fn refuse(
+    state: &State,
+    challenge: &str,
+    request_url: Option<&str>,
+    missing: &[Scope],
+) -> Result<Redirect, Error> {
+    let missing = missing
+        .iter()
+        .map(Scope::as_str)
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    tracing::info!(missing, "refusing consent, required scopes are missing");
+
+    if let Some(remediation_url) = &state.remediation_url {
+        let mut url = remediation_url.clone();
+
+        if let Some(request_url) = request_url {
+            url.query_pairs_mut().append_pair("return_to", request_url);
+        }
+
+        return Ok(Redirect::to(url.as_str()));
+    }
+
+    let reject = RejectOAuth2Request {
+        error: Some("access_denied".to_owned()),
+        error_description: Some(format!("required scopes are missing: {missing}")),
+        status_code: Some(403),
+        ..RejectOAuth2Request::default()
+    };
+
+    let response = state
+        .hydra
+        .scoped()
+        .call(Error::Hydra, |hydra| {
+            ory_hydra_client::apis::o_auth2_api::reject_o_auth2_consent_request(
+                hydra,
+                challenge,
+                Some(&reject),
+            )
+        })
+        .await?;
 
     Ok(Redirect::to(&response.redirect_to))
 }
@@ -112,12 +603,258 @@ struct ConsentQuery {
 }
 
 async fn consent(
-    axum::extract::State(state): axum::extract::State<SharedState>,
+    Tenant(state): Tenant,
     query: axum::extract::Query<ConsentQuery>,
-) -> core::result::Result<Redirect, Json<Report<Error>>> {
-    handle_consent(&state, &query.consent_challenge)
+    headers: HeaderMap,
+) -> core::result::Result<Redirect, ErrorResponse> {
+    let cookie = headers
+        .get(header::COOKIE)
+        .and_then(|cookie| cookie.to_str().ok());
+
+    handle_consent(&state, &query.consent_challenge, cookie)
+        .await
+        .map_err(ErrorResponse::from)
+}
+
+/// Claims of the `userinfo` channel for the access token, with the `sub` of the token.
+async fn handle_userinfo(state: &State, headers: &HeaderMap) -> Result<Value, Error> {
+    let token = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .ok_or_else(|| Report::new(Error::InvalidToken))?;
+
+    let introspection = state
+        .hydra
+        .scoped()
+        .call(Error::Hydra, |hydra| {
+            ory_hydra_client::apis::o_auth2_api::introspect_o_auth2_token(hydra, token, None)
+        })
+        .await?;
+
+    if !introspection.active || introspection.token_use.as_deref() != Some("access_token") {
+        return Err(Report::new(Error::InvalidToken));
+    }
+
+    let subject = introspection
+        .sub
+        .ok_or_else(|| Report::new(Error::SubjectMissing))?;
+
+    let requested_scope: Vec<_> = introspection
+        .scope
+        .as_deref()
+        .unwrap_or_default()
+        .split_whitespace()
+        .map(ToOwned::to_owned)
+        .collect();
+
+    let claims = resolve(
+        state,
+        &subject,
+        introspection.client_id.as_deref(),
+        &requested_scope,
+        None,
+    )
+    .await?;
+
+    let client = client(state, introspection.client_id.as_deref()).await?;
+
+    let userinfo = claims.map(|claims| {
+        let userinfo = state
+            .namespace
+            .apply(client.as_ref(), claims.userinfo, None);
+
+        state.size.apply("userinfo", userinfo, None)
+    });
+
+    let mut userinfo = match userinfo {
+        Some(Value::Object(userinfo)) => userinfo,
+        _ => serde_json::Map::new(),
+    };
+
+    userinfo.insert("sub".to_owned(), Value::String(subject));
+
+    Ok(Value::Object(userinfo))
+}
+
+/// Client with the ID, used for its claim namespace.
+async fn client(state: &State, client_id: Option<&str>) -> Result<Option<OAuth2Client>, Error> {
+    let Some(client_id) = client_id else {
+        return Ok(None);
+    };
+
+    let client = state
+        .hydra
+        .scoped()
+        .call(Error::Hydra, |hydra| {
+            ory_hydra_client::apis::o_auth2_api::get_o_auth2_client(hydra, client_id)
+        })
+        .await?;
+
+    Ok(Some(client))
+}
+
+async fn userinfo(
+    Tenant(state): Tenant,
+    headers: HeaderMap,
+) -> core::result::Result<Json<Value>, ErrorResponse> {
+    handle_userinfo(&state, &headers)
         .await
-        .map_err(Json)
+        .map(Json)
+        .map_err(ErrorResponse::from)
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+struct TokenHookIdToken {
+    subject: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+struct TokenHookSession {
+    id_token: TokenHookIdToken,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+struct TokenHookGrant {
+    client_id: Option<String>,
+    granted_scopes: Vec<String>,
+    grant_types: Vec<String>,
+}
+
+/// Request of the token hook of Hydra, only the parts used to resolve the claims again.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+struct TokenHookRequest {
+    session: TokenHookSession,
+    request: TokenHookGrant,
+}
+
+#[derive(Debug, Serialize)]
+struct TokenHookResponse {
+    session: AcceptOAuth2ConsentRequestSession,
+}
+
+/// Claims of the tokens issued by a refresh grant, resolved again so that they reflect the current
+/// identity. Any other grant keeps the claims of the consent.
+async fn handle_token_hook(
+    state: &State,
+    hook: TokenHookRequest,
+) -> Result<Option<TokenHookResponse>, Error> {
+    if !hook
+        .request
+        .grant_types
+        .iter()
+        .any(|grant| grant == "refresh_token")
+    {
+        return Ok(None);
+    }
+
+    let subject = hook
+        .session
+        .id_token
+        .subject
+        .ok_or_else(|| Report::new(Error::SubjectMissing))?;
+
+    let client_id = hook.request.client_id.as_deref();
+
+    // the granted scopes are the ones the aliases belong to, which resolve the same claims
+    let Some(claims) = resolve(
+        state,
+        &subject,
+        client_id,
+        &hook.request.granted_scopes,
+        None,
+    )
+    .await?
+    else {
+        return Ok(None);
+    };
+
+    let client = client(state, client_id).await?;
+
+    let nested = state.access_token_key.as_deref();
+    let id_token = state
+        .namespace
+        .apply(client.as_ref(), claims.id_token, None);
+    let access_token = state
+        .namespace
+        .apply(client.as_ref(), claims.access_token, nested);
+
+    let session = AcceptOAuth2ConsentRequestSession {
+        access_token: Some(state.size.apply("access_token", access_token, nested)),
+        id_token: Some(state.size.apply("id_token", id_token, None)),
+    };
+
+    tracing::debug!(?session, "resolved session for refresh grant");
+
+    Ok(Some(TokenHookResponse { session }))
+}
+
+async fn token_hook(
+    Tenant(state): Tenant,
+    Json(hook): Json<TokenHookRequest>,
+) -> core::result::Result<Response, ErrorResponse> {
+    let response = handle_token_hook(&state, hook).await?;
+
+    // Hydra keeps the claims as they are on `204 No Content`
+    Ok(response.map_or_else(
+        || StatusCode::NO_CONTENT.into_response(),
+        |response| Json(response).into_response(),
+    ))
+}
+
+/// Consents the subject has given, e.g. for an account dashboard that lists the applications with
+/// access to the account.
+async fn consents(
+    Tenant(state): Tenant,
+    // the path also holds the tenant if it is selected by the path
+    axum::extract::Path(params): axum::extract::Path<HashMap<String, String>>,
+) -> core::result::Result<Json<Vec<Consent>>, ErrorResponse> {
+    let subject = params.get("subject").map_or("", String::as_str);
+
+    let consents = consents::list(
+        &state.hydra,
+        state.store.as_ref(),
+        &middleware::tenant(),
+        subject,
+    )
+    .await
+    .change_context(Error::Consents)?;
+
+    Ok(Json(consents))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+struct RevokeQuery {
+    subject: String,
+    client: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+struct RevokeResponse {
+    removed_grants: u64,
+}
+
+/// Revoke the consent of the subject to the client (every client without one) in Hydra and remove
+/// its records from the grant store, e.g. if a user asks to disconnect an application.
+async fn revoke_consents(
+    Tenant(state): Tenant,
+    query: axum::extract::Query<RevokeQuery>,
+) -> core::result::Result<Json<RevokeResponse>, ErrorResponse> {
+    let removed_grants = revoke::revoke(
+        &state.hydra,
+        state.store.as_ref(),
+        &middleware::tenant(),
+        &query.subject,
+        query.client.as_deref(),
+    )
+    .await
+    .change_context(Error::Revoke)?;
+
+    Ok(Json(RevokeResponse { removed_grants }))
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
@@ -126,85 +863,660 @@ struct LogoutQuery {
 }
 
 async fn logout(
-    axum::extract::State(state): axum::extract::State<SharedState>,
+    Tenant(state): Tenant,
     query: axum::extract::Query<LogoutQuery>,
-) -> core::result::Result<Redirect, Json<Report<Error>>> {
+) -> core::result::Result<Redirect, ErrorResponse> {
     // for now, we just accept the logout request, in the future we might want to also enable asking
     // the user
-    let request = ory_hydra_client::apis::o_auth2_api::get_o_auth2_logout_request(
-        &state.hydra,
-        &query.logout_challenge,
-    )
-    .await
-    .into_report()
-    .change_context(Error::Hydra)
-    .map_err(Json)?;
+    let request = state
+        .hydra
+        .scoped()
+        .call(Error::Hydra, |hydra| {
+            ory_hydra_client::apis::o_auth2_api::get_o_auth2_logout_request(
+                hydra,
+                &query.logout_challenge,
+            )
+        })
+        .await?;
 
     // TODO: unsure if sid or subject
     if let Some(sid) = request.sid {
-        ory_kratos_client::apis::identity_api::delete_identity_sessions(&state.kratos, &sid)
-            .await
-            .into_report()
-            .change_context(Error::Kratos)
-            .map_err(Json)?;
+        state
+            .kratos
+            .scoped()
+            .call(Error::Kratos, |kratos| {
+                ory_kratos_client::apis::identity_api::delete_identity_sessions(kratos, &sid)
+            })
+            .await?;
     };
 
-    let response = ory_hydra_client::apis::o_auth2_api::accept_o_auth2_logout_request(
-        &state.hydra,
-        &query.logout_challenge,
-    )
-    .await
-    .into_report()
-    .change_context(Error::Hydra)
-    .map_err(Json)?;
+    let response = state
+        .hydra
+        .scoped()
+        .call(Error::Hydra, |hydra| {
+            ory_hydra_client::apis::o_auth2_api::accept_o_auth2_logout_request(
+                hydra,
+                &query.logout_challenge,
+            )
+        })
+        .await?;
 
     Ok(Redirect::to(&response.redirect_to))
 }
 
-#[derive(Debug)]
-pub(crate) struct Config {
+#[derive(Debug, Serialize)]
+struct Health {
+    status: &'static str,
+    upstreams: IndexMap<String, BreakerState>,
+}
+
+#[allow(clippy::unused_async)] // Reason: axum handlers must be async
+async fn health(
+    axum::extract::State(server): axum::extract::State<SharedServer>,
+) -> (StatusCode, Json<Health>) {
+    let mut upstreams = IndexMap::new();
+
+    // the upstreams of tenants are prefixed with their name, e.g. `acme/hydra`
+    for (tenant, state) in server.tenants.iter() {
+        let mut insert = |upstream: &str, breaker: BreakerState| {
+            let key = tenant.map_or_else(
+                || upstream.to_owned(),
+                |tenant| format!("{tenant}/{upstream}"),
+            );
+
+            upstreams.insert(key, breaker);
+        };
+
+        insert("hydra", state.hydra.breaker().state());
+        insert("kratos", state.kratos.breaker().state());
+
+        if let Some(kratos_public) = &state.kratos_public {
+            insert("kratos_public", kratos_public.breaker().state());
+        }
+
+        if let Some(keto) = &state.keto {
+            insert("keto", keto.breaker().state());
+        }
+
+        if let Some(webhook) = &state.webhook {
+            insert("webhook", webhook.breaker().state());
+        }
+    }
+
+    // a half-open upstream has not answered a probe yet, it is not known to be available
+    let healthy = upstreams
+        .values()
+        .all(|state| *state == BreakerState::Closed);
+
+    let (status, code) = if healthy {
+        ("ok", StatusCode::OK)
+    } else {
+        ("degraded", StatusCode::SERVICE_UNAVAILABLE)
+    };
+
+    (code, Json(Health { status, upstreams }))
+}
+
+#[allow(clippy::unused_async)] // Reason: axum handlers must be async
+async fn metrics(axum::extract::State(server): axum::extract::State<SharedServer>) -> String {
+    server.metrics.render()
+}
+
+fn parse_base_path(value: &str) -> core::result::Result<String, &'static str> {
+    if !value.starts_with('/') {
+        return Err("base path must start with `/`");
+    }
+
+    Ok(value.trim_end_matches('/').to_owned())
+}
+
+fn parse_subject_claim(value: &str) -> core::result::Result<String, String> {
+    if lint::RESERVED_CLAIMS.contains(&value) {
+        return Err(format!("`{value}` is set by Hydra itself"));
+    }
+
+    Ok(value.to_owned())
+}
+
+/// Options of `serve`, see [`Options::try_parse_from`].
+#[derive(Debug, Clone, clap::Args)]
+pub struct Options {
+    /// Path prefix under which all routes are served, e.g. `/auth/hydra`
+    #[clap(long, env, value_parser = parse_base_path)]
+    pub(crate) base_path: Option<String>,
+
+    /// URL users are sent to if a required scope cannot be resolved, e.g. the settings flow of
+    /// Kratos to complete their profile, with the original authorization request as `return_to`.
+    /// Without it the consent request is rejected
+    #[clap(long, env, value_hint = ValueHint::Url)]
+    pub(crate) remediation_url: Option<Url>,
+
+    /// How long Hydra remembers the consent, e.g. `30d`, scopes with a shorter `remember_for`
+    /// shorten it. `0s` remembers it indefinitely, without it consent is not remembered
+    #[clap(long, env, value_parser = humantime::parse_duration)]
+    pub(crate) remember_for: Option<Duration>,
+
+    /// Refuse consent unless the browser has an active Kratos session of the subject, verified
+    /// through `--kratos-public-url`, which needs to share the cookie domain with this server
+    #[clap(long, env)]
+    pub(crate) verify_session: bool,
+
+    /// List the sessions of the subject in Kratos during consent, exposed to mappings with the
+    /// `sessions` source as `last_login_at` and `active_sessions`
+    #[clap(long, env)]
+    pub(crate) list_sessions: bool,
+
+    /// Record every accepted consent (client, granted scopes and time) in the `consents` of the
+    /// `metadata_admin` of the identity in Kratos, which keeps the most recent 100
+    #[clap(long, env)]
+    pub(crate) record_consent: bool,
+
+    /// Claim of the ID token and `/userinfo` the remapped `subject` of the consent configuration
+    /// is issued as, `sub` keeps the identity ID, as Hydra only accepts a different one when the
+    /// login is accepted, which Kratos does. Claims Hydra sets itself (e.g. `sub`) are rejected
+    #[clap(long, env, default_value = "legacy_sub", value_parser = parse_subject_claim)]
+    pub(crate) subject_claim: String,
+
+    /// How often `--scope-config` is fetched again if it is a URL, e.g. `5m`, revalidated with its
+    /// `ETag` and `Last-Modified`. Failures keep the last good version, without it it is only
+    /// fetched on start
+    #[clap(long, env, value_parser = humantime::parse_duration)]
+    pub(crate) scope_config_refresh: Option<Duration>,
+
+    /// YAML or TOML file of named tenants, each with their own Kratos and Hydra, keywords and
+    /// policies, keyed by the long names of the options they replace
+    #[clap(long, env, value_hint = ValueHint::FilePath)]
+    pub(crate) tenants: Option<PathBuf>,
+
+    /// How the tenant of a request is selected
+    #[clap(long, env, value_enum, default_value_t)]
+    pub(crate) tenant_selector: Selector,
+
+    #[command(flatten)]
+    pub(crate) keto: KetoConfig,
+
+    #[command(flatten)]
+    pub(crate) webhook: WebhookConfig,
+
+    #[command(flatten)]
+    pub(crate) store: StoreConfig,
+
+    #[command(flatten)]
+    pub(crate) size: SizeConfig,
+
+    #[command(flatten)]
+    pub(crate) namespace: NamespaceConfig,
+
+    #[command(flatten)]
+    pub(crate) client_ip: ClientIpConfig,
+
+    #[command(flatten)]
+    pub(crate) allowlist: AllowlistConfig,
+
+    #[command(flatten)]
+    pub(crate) endpoint_token: EndpointTokenConfig,
+
+    #[command(flatten)]
+    pub(crate) admin_token: AdminTokenConfig,
+
+    #[command(flatten)]
+    pub(crate) token_hook_token: TokenHookTokenConfig,
+
+    #[command(flatten)]
+    pub(crate) limit: LimitConfig,
+
+    #[command(flatten)]
+    pub(crate) rate_limit: RateLimitConfig,
+
+    #[command(flatten)]
+    pub(crate) security_headers: SecurityHeadersConfig,
+}
+
+/// Upstreams and parse options shared by every command, see [`Config::try_parse_from`].
+#[derive(Debug, Clone)]
+pub struct Config {
     pub(crate) kratos_url: Url,
+    pub(crate) kratos_credentials: Option<Credentials>,
+    pub(crate) kratos_public_url: Option<Url>,
 
     pub(crate) hydra_url: Url,
+    pub(crate) hydra_credentials: Option<Credentials>,
+
+    pub(crate) parse: ParseOptions,
+    pub(crate) scope_config: Option<Location>,
+    /// Claim sources registered by the application embedding the router
+    pub(crate) sources: Vec<Arc<dyn ClaimSource>>,
 
-    pub(crate) direct_mapping: bool,
-    pub(crate) keyword: String,
+    pub(crate) upstream: UpstreamConfig,
 }
 
-fn setup(config: Config) -> State {
-    let kratos = ory_kratos_client::apis::configuration::Configuration {
-        base_path: config.kratos_url.as_str().trim_end_matches('/').to_owned(),
-        ..Default::default()
-    };
+/// Overlay the identity schemas of every tenant.
+async fn overlay(server: &Server, overlays: Overlays) {
+    for (_, state) in server.tenants.iter() {
+        state.cache.overlay(overlays.clone()).await;
+    }
+}
 
-    let hydra = ory_hydra_client::apis::configuration::Configuration {
-        base_path: config.hydra_url.as_str().trim_end_matches('/').to_owned(),
-        ..Default::default()
-    };
+/// Load the scope configuration in the file again whenever it is written.
+fn watch(server: &SharedServer, mut watch: Watch) {
+    let server = Arc::clone(server);
+
+    tokio::spawn(async move {
+        while let Some(overlays) = watch.changed().await {
+            overlay(&server, overlays).await;
+        }
+    });
+}
+
+/// Fetch the scope configuration at the URL every interval, the first poll fetches it in full,
+/// later ones only if it changed since.
+fn refresh(server: &SharedServer, url: Url, client: reqwest::Client, period: Duration) {
+    let server = Arc::clone(server);
+    let mut remote = Remote::new(url, client);
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        loop {
+            interval.tick().await;
+
+            if let Some(overlays) = remote.poll().await {
+                overlay(&server, overlays).await;
+            }
+        }
+    });
+}
 
-    let cache = SchemaCache::new(config.keyword, config.direct_mapping);
+/// Claim sources of the tenant, the configured built-in ones followed by the ones the application
+/// registered.
+fn sources(
+    state: &State,
+    relationships: Vec<Relationship>,
+    list_sessions: bool,
+    registered: Vec<Arc<dyn ClaimSource>>,
+) -> Result<Vec<Arc<dyn ClaimSource>>, Error> {
+    let mut sources: Vec<Arc<dyn ClaimSource>> = vec![];
 
-    State {
-        kratos,
+    if let Some(keto) = &state.keto {
+        sources.push(Arc::new(KetoSource {
+            keto: Arc::clone(keto),
+            relationships,
+        }));
+    }
+
+    if list_sessions {
+        sources.push(Arc::new(SessionsSource {
+            kratos: Arc::clone(&state.kratos),
+        }));
+    }
+
+    if let Some(webhook) = &state.webhook {
+        sources.push(Arc::new(WebhookSource {
+            webhook: Arc::clone(webhook),
+        }));
+    }
+
+    // the names of the built-in sources are reserved, even if they are not configured
+    let mut names = HashSet::from([source::KETO, source::SESSIONS, source::WEBHOOK]);
+    for source in &registered {
+        if !names.insert(source.name()) {
+            return Err(Report::new(Error::DuplicateSource)
+                .attach_printable(format!("source `{}`", source.name())));
+        }
+    }
+
+    sources.extend(registered);
+
+    Ok(sources)
+}
+
+fn setup(
+    upstreams: &Upstreams,
+    config: Config,
+    options: Options,
+    store: Option<&Store>,
+) -> Result<State, Error> {
+    let Options {
+        keto,
+        webhook,
+        size,
+        namespace,
+        remediation_url,
+        remember_for,
+        verify_session,
+        list_sessions,
+        record_consent,
+        subject_claim,
+        ..
+    } = options;
+
+    if verify_session && config.kratos_public_url.is_none() {
+        return Err(Report::new(Error::KratosPublicUrl));
+    }
+
+    let kratos_public = config
+        .kratos_public_url
+        .as_ref()
+        .map(|url| upstreams.kratos_public(url))
+        .transpose()
+        .change_context(Error::Upstream)?;
+
+    // without a remediation URL users complete their profile in the settings flow of Kratos
+    let remediation_url = remediation_url.or_else(|| {
+        let mut url = config.kratos_public_url.clone()?;
+        url.path_segments_mut().ok()?.pop_if_empty().extend([
+            "self-service",
+            "settings",
+            "browser",
+        ]);
+
+        Some(url)
+    });
+
+    let kratos = upstreams
+        .kratos(&config.kratos_url, config.kratos_credentials.as_ref())
+        .change_context(Error::Upstream)?;
+
+    let hydra = upstreams
+        .hydra(&config.hydra_url, config.hydra_credentials.as_ref())
+        .change_context(Error::Upstream)?;
+
+    let relationships = keto.keto_relationships;
+    let keto = keto
+        .keto_read_url
+        .map(|url| upstreams.keto(&url))
+        .transpose()
+        .change_context(Error::Upstream)?;
+
+    let webhook_token =
+        Secret::resolve(webhook.webhook_token, webhook.webhook_token_file.as_deref())
+            .change_context(Error::WebhookToken)?;
+    let webhook = webhook
+        .webhook_url
+        .map(|url| upstreams.webhook(&url, webhook_token.map(Credentials::Bearer).as_ref()))
+        .transpose()
+        .change_context(Error::Upstream)?;
+
+    let access_token_key = config.parse.access_token_key.clone();
+    let cache = SchemaCache::new(config.parse);
+
+    let mut state = State {
+        kratos: Arc::new(kratos),
+        kratos_public,
+        verify_session,
+        record_consent,
+        subject_claim,
         hydra,
+        keto: keto.map(Arc::new),
+        webhook: webhook.map(Arc::new),
+        sources: vec![],
+        size,
+        namespace,
+        access_token_key,
+        remediation_url,
+        remember_for,
+        store: store.cloned(),
         cache,
+    };
+    state.sources = sources(&state, relationships, list_sessions, config.sources)?;
+
+    Ok(state)
+}
+
+/// Options of the tenant, anything it does not set is taken from the top-level options. Credentials
+/// are only taken along with the URL, so that they are never sent to the upstream of another
+/// tenant.
+fn tenant(
+    config: &Config,
+    options: &Options,
+    tenant: TenantConfig,
+) -> Result<(Config, Options), Error> {
+    let mut config = config.clone();
+    let mut options = options.clone();
+
+    let kratos_api_key =
+        Secret::resolve(tenant.kratos_api_key, tenant.kratos_api_key_file.as_deref())
+            .change_context(Error::Tenants)?;
+
+    if let Some(url) = tenant.kratos_admin_url {
+        config.kratos_url = url;
+        config.kratos_credentials = None;
+        config.kratos_public_url = None;
+    }
+
+    if let Some(url) = tenant.kratos_public_url {
+        config.kratos_public_url = Some(url);
+    }
+
+    if let Some(api_key) = kratos_api_key {
+        config.kratos_credentials = Some(Credentials::Bearer(api_key));
+    }
+
+    let hydra_api_key = Secret::resolve(tenant.hydra_api_key, tenant.hydra_api_key_file.as_deref())
+        .change_context(Error::Tenants)?;
+
+    if let Some(url) = tenant.hydra_admin_url {
+        config.hydra_url = url;
+        config.hydra_credentials = None;
+    }
+
+    if let Some(api_key) = hydra_api_key {
+        config.hydra_credentials = Some(Credentials::Bearer(api_key));
+    }
+
+    if !tenant.keyword.is_empty() {
+        config.parse.keywords = tenant.keyword;
+    }
+
+    if let Some(remember_for) = tenant.remember_for {
+        options.remember_for = Some(remember_for.into());
+    }
+
+    if let Some(remediation_url) = tenant.remediation_url {
+        options.remediation_url = Some(remediation_url);
+    }
+
+    if let Some(claim_namespace) = tenant.claim_namespace {
+        options.namespace.claim_namespace = Some(claim_namespace);
     }
+
+    Ok((config, options))
+}
+
+/// State of the top-level options and of every tenant in `--tenants`.
+fn tenants(
+    config: &Config,
+    options: &Options,
+    store: Option<&Store>,
+) -> Result<Tenants<SharedState>, Error> {
+    let upstreams = config.upstream.connect().change_context(Error::Upstream)?;
+
+    let configs = options
+        .tenants
+        .as_deref()
+        .map(tenant::load)
+        .transpose()
+        .change_context(Error::Tenants)?
+        .unwrap_or_default();
+
+    let default = setup(&upstreams, config.clone(), options.clone(), store)?;
+    let mut tenants = Tenants::new(options.tenant_selector, Arc::new(default));
+
+    for (name, overrides) in configs {
+        let hosts = overrides.hosts.clone();
+        let (config, options) = tenant(config, options, overrides)?;
+
+        let state = setup(&upstreams.tenant(&name), config, options, store)?;
+        tenants.insert(name, hosts, Arc::new(state));
+    }
+
+    Ok(tenants)
 }
 
-pub(crate) async fn run(address: SocketAddr, config: Config) -> Result<(), Error> {
-    let state = setup(config);
-    let state = Arc::new(state);
+impl Options {
+    /// Options read from the same options and environment variables as `serve`, without the
+    /// addresses, e.g. `["--remember-for", "1h", "--record-consent"]`.
+    pub fn try_parse_from<I, T>(args: I) -> core::result::Result<Self, clap::Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString> + Clone,
+    {
+        let command = Self::augment_args(clap::Command::new("serve").no_binary_name(true));
+
+        Self::from_arg_matches(&command.try_get_matches_from(args)?)
+    }
+}
+
+impl Config {
+    /// Register a claim source, whose document mappings with the `custom` source resolve pointers
+    /// in. Sources are fetched in the order they are registered, after the built-in ones.
+    #[must_use]
+    pub fn with_source(mut self, source: impl ClaimSource + 'static) -> Self {
+        self.sources.push(Arc::new(source));
+
+        self
+    }
+
+    /// How the scope configuration is read from the identity schemas, e.g. to parse a schema
+    /// with [`Schema::parse`](crate::Schema::parse).
+    #[must_use]
+    pub const fn parse_options(&self) -> &ParseOptions {
+        &self.parse
+    }
+}
+
+/// Routes of `serve` (`/consent`, `/logout`, `/userinfo`, ..) with their middleware, to be served
+/// on their own or mounted in an existing application, e.g. with `Router::nest`. The scope
+/// configuration is watched or refreshed in the background, which requires a Tokio runtime.
+pub async fn router(config: Config, options: Options) -> Result<axum::Router, Error> {
+    let scope_config = config.scope_config.clone();
+
+    // installed first, so that the initial state of the circuit breakers is recorded, an
+    // application the router is mounted in may have installed a recorder of its own already
+    let recorder = Metrics::install().unwrap_or_else(|_| Metrics::detached());
+
+    // shared by every tenant, grants are told apart by the name of the tenant
+    let store = match &options.store.grant_store {
+        Some(url) => Some(Store::connect(url).await.change_context(Error::Store)?),
+        None => None,
+    };
+
+    let tenants = tenants(&config, &options, store.as_ref())?;
+    let selector = tenants.selector();
+    let server = Arc::new(Server {
+        tenants,
+        metrics: recorder,
+    });
 
-    let router = axum::Router::new()
+    let period = options
+        .scope_config_refresh
+        .filter(|period| !period.is_zero());
+    match (scope_config, period) {
+        (Some(Location::Url(url)), Some(period)) => {
+            let client = config.upstream.http().change_context(Error::Upstream)?;
+            refresh(&server, url, client, period);
+        }
+        (Some(Location::File(path)), _) => {
+            let watcher = Watch::new(&path).change_context(Error::ScopeConfig)?;
+            watch(&server, watcher);
+        }
+        _ => {}
+    }
+
+    let limit = options.limit.limit();
+    let rate_limit = options.rate_limit.limit();
+
+    let flows = axum::Router::new()
         .route("/consent", get(consent))
-        .route("/logout", get(logout))
-        .with_state(state)
-        .layer(TraceLayer::new_for_http());
+        .route("/logout", get(logout));
+    let flows = options
+        .endpoint_token
+        .apply(flows)
+        .change_context(Error::EndpointToken)?;
+    let flows = rate_limit.apply(flows);
+    let flows = limit.apply(flows);
 
-    Server::bind(&address)
-        .serve(router.into_make_service())
-        .await
-        .expect("should run forever-ish");
+    // called by relying parties with their access token, not by Hydra
+    let userinfo = axum::Router::new().route("/userinfo", get(userinfo));
+    let userinfo = rate_limit.apply(userinfo);
+    let userinfo = limit.apply(userinfo);
 
-    Ok(())
+    // called by Hydra, only served if there is a token hook token, as it resolves the claims of
+    // whichever subject the request names
+    let hook = axum::Router::new().route("/token-hook", post(token_hook));
+    let hook = options
+        .token_hook_token
+        .apply(hook)
+        .change_context(Error::TokenHookToken)?
+        .map(|hook| limit.apply(hook));
+
+    if hook.is_none() {
+        tracing::info!("no token hook token is set, `/token-hook` is not served");
+    }
+
+    // called by support teams, only served if there is an admin token
+    let admin = axum::Router::new()
+        .route("/admin/consents", delete(revoke_consents))
+        .route("/admin/consents/:subject", get(consents));
+    let admin = options
+        .admin_token
+        .apply(admin)
+        .change_context(Error::AdminToken)?;
+
+    let tenanted = axum::Router::new().merge(flows).merge(userinfo);
+    let tenanted = match hook {
+        Some(hook) => tenanted.merge(hook),
+        None => tenanted,
+    };
+    let tenanted = match admin {
+        Some(admin) => tenanted.merge(admin),
+        None => tenanted,
+    };
+
+    // the routes without a tenant are served by the top-level options
+    let tenanted = match selector {
+        Selector::Path => tenanted.clone().nest("/:tenant", tenanted),
+        Selector::Host | Selector::Query => tenanted,
+    };
+    let tenanted = tenanted.layer(axum::middleware::from_fn_with_state(
+        Arc::clone(&server),
+        select_tenant,
+    ));
+
+    let router = tenanted
+        .route("/health", get(health))
+        .route("/metrics", get(metrics));
+
+    // axum does not support nesting at the root, `/` is normalized to an empty base path
+    let router = match options.base_path.as_deref() {
+        Some(base_path) if !base_path.is_empty() => axum::Router::new().nest(base_path, router),
+        _ => router,
+    };
+
+    let router = router.with_state(server);
+    let router = options.security_headers.apply(router);
+    let router = options.allowlist.apply(router);
+
+    Ok(router
+        .layer(axum::middleware::from_fn_with_state(
+            options.client_ip,
+            middleware::client_ip,
+        ))
+        .layer(axum::middleware::from_fn(middleware::scope_request_id))
+        .layer(TraceLayer::new_for_http().make_span_with(middleware::make_span))
+        .layer(PropagateRequestIdLayer::x_request_id())
+        .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid)))
+}
+
+pub(crate) async fn run(
+    addresses: &[Address],
+    options: Options,
+    config: Config,
+) -> Result<(), Error> {
+    let router = router(config, options).await?;
+
+    listen::serve_all(addresses, router)
+        .await
+        .change_context(Error::Listen)
 }
@@ -0,0 +1,73 @@
+use alloc::sync::Arc;
+
+use error_stack::{Result, ResultExt};
+use serde_json::{json, Value};
+use thiserror::Error;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+use crate::{
+    source::{self, ClaimSource, Request},
+    upstream::Kratos,
+};
+
+#[derive(Debug, Copy, Clone, Error)]
+#[error("unable to list the sessions of the subject in Kratos")]
+pub(crate) struct Error;
+
+// subjects rarely have more sessions than fit into a single page, sessions beyond it are ignored
+const PAGE_SIZE: i64 = 1000;
+
+/// Summarize the sessions of the subject, e.g.
+/// `{"last_login_at": "2023-06-01T12:00:00Z", "active_sessions": 2}`, `last_login_at` is `null` if
+/// the subject never logged in.
+async fn sessions(kratos: &Kratos, subject: &str) -> Result<Value, Error> {
+    let sessions = kratos
+        .scoped()
+        .call(Error, |kratos| {
+            ory_kratos_client::apis::identity_api::list_identity_sessions(
+                kratos,
+                subject,
+                Some(PAGE_SIZE),
+                None,
+                None,
+            )
+        })
+        .await?;
+
+    let active_sessions = sessions
+        .iter()
+        .filter(|session| session.active == Some(true))
+        .count();
+
+    // compared as timestamps, the precision of the fraction is not fixed
+    let last_login_at = sessions
+        .iter()
+        .filter_map(|session| session.authenticated_at.as_deref())
+        .filter_map(|at| Some((OffsetDateTime::parse(at, &Rfc3339).ok()?, at)))
+        .max_by_key(|(parsed, _)| *parsed)
+        .map(|(_, at)| at);
+
+    Ok(json!({
+        "last_login_at": last_login_at,
+        "active_sessions": active_sessions,
+    }))
+}
+
+/// Summary of the sessions of the subject, the document of the `sessions` source.
+#[derive(Debug)]
+pub(crate) struct SessionsSource {
+    pub(crate) kratos: Arc<Kratos>,
+}
+
+#[axum::async_trait]
+impl ClaimSource for SessionsSource {
+    fn name(&self) -> &str {
+        source::SESSIONS
+    }
+
+    async fn fetch(&self, request: &Request<'_>) -> Result<Value, source::Error> {
+        sessions(&self.kratos, request.subject)
+            .await
+            .change_context(source::Error)
+    }
+}
@@ -0,0 +1,173 @@
+use serde_json::{Map, Value};
+
+use crate::middleware;
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, clap::ValueEnum)]
+pub(crate) enum Oversized {
+    /// Claims that exceed the limit are left out
+    #[default]
+    Drop,
+    /// Strings and arrays that exceed the limit of a claim are shortened until they fit, any other
+    /// claim is left out
+    Truncate,
+}
+
+#[derive(Debug, Copy, Clone, clap::Args)]
+pub(crate) struct SizeConfig {
+    /// Maximum size of the serialized value of a single claim in bytes
+    #[clap(long, env)]
+    pub(crate) max_claim_size: Option<usize>,
+
+    /// Maximum size of the serialized claims of a single token in bytes, claims are added in the
+    /// order of the scopes and the ones that no longer fit are left out
+    #[clap(long, env)]
+    pub(crate) max_token_size: Option<usize>,
+
+    /// What happens to claims exceeding `--max-claim-size`
+    #[clap(long, env, value_enum, default_value_t)]
+    pub(crate) oversized_claims: Oversized,
+}
+
+fn size(value: &Value) -> usize {
+    // serializing a `Value` cannot fail
+    serde_json::to_vec(value).map_or(0, |bytes| bytes.len())
+}
+
+/// Shorten strings (at a character boundary) and arrays (by their last items) until they fit.
+fn truncate(value: Value, max: usize) -> Option<Value> {
+    let mut total = size(&value);
+
+    match value {
+        Value::String(mut string) => {
+            while total > max {
+                let char = string.pop()?;
+                // escaped characters take up more than their UTF-8 encoding
+                total -= size(&Value::String(char.to_string())) - 2;
+            }
+
+            Some(Value::String(string))
+        }
+        Value::Array(mut items) => {
+            while total > max {
+                let item = items.pop()?;
+                // every item but the first is preceded by a comma
+                total -= size(&item) + usize::from(!items.is_empty());
+            }
+
+            Some(Value::Array(items))
+        }
+        _ => None,
+    }
+}
+
+impl SizeConfig {
+    fn claim(&self, token: &'static str, claim: &str, value: Value) -> Option<Value> {
+        let Some(max) = self.max_claim_size else {
+            return Some(value);
+        };
+
+        let actual = size(&value);
+        if actual <= max {
+            return Some(value);
+        }
+
+        let truncated = match self.oversized_claims {
+            Oversized::Drop => None,
+            Oversized::Truncate => truncate(value, max),
+        };
+
+        let action = if truncated.is_some() {
+            "truncated"
+        } else {
+            "dropped"
+        };
+
+        tracing::warn!(
+            token,
+            claim,
+            size = actual,
+            max,
+            action,
+            "claim exceeds maximum size"
+        );
+        metrics::increment_counter!(
+            "claims_oversized_total",
+            "token" => token,
+            "action" => action,
+            "tenant" => middleware::tenant()
+        );
+
+        truncated
+    }
+
+    /// Enforce the size limits on the claims of a token, e.g. `id_token`. The claims of the object
+    /// under `nested` (the `--access-token-key`) are limited one by one, like the ones at the top
+    /// level.
+    pub(crate) fn apply(&self, token: &'static str, claims: Value, nested: Option<&str>) -> Value {
+        let Value::Object(claims) = claims else {
+            return claims;
+        };
+
+        // the braces of the object
+        let mut total = 2;
+
+        Value::Object(self.guard(token, claims, nested, &mut total))
+    }
+
+    fn guard(
+        &self,
+        token: &'static str,
+        claims: Map<String, Value>,
+        nested: Option<&str>,
+        total: &mut usize,
+    ) -> Map<String, Value> {
+        let mut guarded = Map::new();
+
+        for (claim, value) in claims {
+            let value = match value {
+                Value::Object(inner) if nested == Some(claim.as_str()) => {
+                    // the quoted key, the colon, the separating comma and the braces
+                    let overhead = size(&Value::String(claim.clone())) + 4;
+                    *total += overhead;
+
+                    let inner = self.guard(token, inner, None, total);
+
+                    if inner.is_empty() {
+                        *total -= overhead;
+                    } else {
+                        guarded.insert(claim, Value::Object(inner));
+                    }
+
+                    continue;
+                }
+                value => value,
+            };
+
+            let Some(value) = self.claim(token, &claim, value) else {
+                continue;
+            };
+
+            // the quoted key, the colon and the separating comma
+            let added = size(&Value::String(claim.clone())) + size(&value) + 2;
+
+            if let Some(max) = self.max_token_size {
+                if *total + added > max {
+                    tracing::warn!(token, claim, max, "claim exceeds maximum size of the token");
+                    metrics::increment_counter!(
+                        "claims_oversized_total",
+                        "token" => token,
+                        "action" => "dropped",
+                        "tenant" => middleware::tenant()
+                    );
+
+                    continue;
+                }
+            }
+
+            *total += added;
+            guarded.insert(claim, value);
+        }
+
+        guarded
+    }
+}
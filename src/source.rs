@@ -0,0 +1,41 @@
+use core::fmt::Debug;
+
+use error_stack::Result;
+use ory_kratos_client::models::Identity;
+use serde_json::Value;
+use thiserror::Error;
+
+#[derive(Debug, Copy, Clone, Error)]
+#[error("unable to fetch the claim source")]
+pub struct Error;
+
+/// Names of the built-in sources, which mappings refer to with a source of the same name.
+pub(crate) const KETO: &str = "keto";
+pub(crate) const SESSIONS: &str = "sessions";
+pub(crate) const WEBHOOK: &str = "webhook";
+
+/// Request the claims of a subject are resolved for, during consent, userinfo or the token hook.
+#[derive(Debug, Copy, Clone)]
+pub struct Request<'a> {
+    /// Identity of the subject as Kratos returns it
+    pub identity: &'a Identity,
+    pub subject: &'a str,
+    pub client_id: Option<&'a str>,
+    pub requested_scope: &'a [String],
+    /// Name of the tenant the request is for, empty for the top-level options
+    pub tenant: &'a str,
+}
+
+/// Document fetched for every request, which explicit mappings resolve pointers in. Keto, the
+/// sessions in Kratos and the enrichment webhook are built-in sources, an application embedding
+/// the router adds its own with [`Config::with_source`](crate::Config::with_source).
+#[axum::async_trait]
+pub trait ClaimSource: Debug + Send + Sync {
+    /// Name of the source, mappings with the `custom` source resolve `/<name>/..`, e.g.
+    /// `/crm/tier` in the document of a source named `crm`
+    fn name(&self) -> &str;
+
+    /// Fetch the document of the subject, `null` if the source has nothing to contribute. An
+    /// error fails the request, as its claims would be incomplete otherwise.
+    async fn fetch(&self, request: &Request<'_>) -> Result<Value, Error>;
+}
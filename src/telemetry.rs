@@ -0,0 +1,37 @@
+use core::fmt;
+
+use error_stack::{IntoReport, Result, ResultExt};
+use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+#[error("unable to install metrics recorder")]
+pub(crate) struct Error;
+
+/// Process-wide Prometheus recorder, every `metrics::*!` invocation is collected here.
+pub(crate) struct Metrics(PrometheusHandle);
+
+impl Metrics {
+    pub(crate) fn install() -> Result<Self, Error> {
+        PrometheusBuilder::new()
+            .install_recorder()
+            .into_report()
+            .change_context(Error)
+            .map(Self)
+    }
+
+    /// Recorder that is not installed, `/metrics` stays empty.
+    pub(crate) fn detached() -> Self {
+        Self(PrometheusBuilder::new().build_recorder().handle())
+    }
+
+    pub(crate) fn render(&self) -> String {
+        self.0.render()
+    }
+}
+
+impl fmt::Debug for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Metrics").finish_non_exhaustive()
+    }
+}
@@ -0,0 +1,157 @@
+use core::{fmt::Display, str::FromStr};
+use std::path::{Path, PathBuf};
+
+use error_stack::{IntoReport, Result, ResultExt};
+use indexmap::IndexMap;
+use serde::{de::Error as _, Deserialize, Deserializer};
+use thiserror::Error;
+use url::Url;
+
+use crate::secret::Secret;
+
+#[derive(Debug, Error)]
+pub(crate) enum Error {
+    #[error("unable to read tenants from {}", .0.display())]
+    Io(PathBuf),
+    #[error("unable to parse tenants in {}", .0.display())]
+    Parse(PathBuf),
+}
+
+/// How the tenant of a request is selected, requests without one are served by the top-level
+/// options.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, clap::ValueEnum)]
+pub(crate) enum Selector {
+    /// `Host` header of the request, matched against the `hosts` of the tenants
+    #[default]
+    Host,
+    /// First segment of the path, e.g. `/<tenant>/consent`
+    Path,
+    /// `tenant` query parameter
+    Query,
+}
+
+fn parsed<'de, D, T>(deserializer: D) -> core::result::Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    Option::<String>::deserialize(deserializer)?
+        .map(|value| value.parse().map_err(D::Error::custom))
+        .transpose()
+}
+
+/// Options of a tenant, keyed by the long names of the command line flags they replace, anything
+/// not given is taken from the top-level options.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct TenantConfig {
+    /// Hosts the tenant is selected by with `--tenant-selector host`
+    #[serde(default)]
+    pub(crate) hosts: Vec<String>,
+
+    #[serde(default, deserialize_with = "parsed")]
+    pub(crate) kratos_admin_url: Option<Url>,
+    #[serde(default, deserialize_with = "parsed")]
+    pub(crate) kratos_api_key: Option<Secret>,
+    pub(crate) kratos_api_key_file: Option<PathBuf>,
+    #[serde(default, deserialize_with = "parsed")]
+    pub(crate) kratos_public_url: Option<Url>,
+
+    #[serde(default, deserialize_with = "parsed")]
+    pub(crate) hydra_admin_url: Option<Url>,
+    #[serde(default, deserialize_with = "parsed")]
+    pub(crate) hydra_api_key: Option<Secret>,
+    pub(crate) hydra_api_key_file: Option<PathBuf>,
+
+    #[serde(default)]
+    pub(crate) keyword: Vec<String>,
+
+    #[serde(default, deserialize_with = "parsed")]
+    pub(crate) remember_for: Option<humantime::Duration>,
+    #[serde(default, deserialize_with = "parsed")]
+    pub(crate) remediation_url: Option<Url>,
+    pub(crate) claim_namespace: Option<String>,
+}
+
+/// Read the tenants from a YAML (or JSON) or TOML file, keyed by their name.
+pub(crate) fn load(path: &Path) -> Result<IndexMap<String, TenantConfig>, Error> {
+    let contents = std::fs::read_to_string(path)
+        .into_report()
+        .change_context_lazy(|| Error::Io(path.to_path_buf()))?;
+
+    let is_toml = path
+        .extension()
+        .map_or(false, |extension| extension == "toml");
+
+    if is_toml {
+        toml::from_str(&contents)
+            .into_report()
+            .change_context_lazy(|| Error::Parse(path.to_path_buf()))
+    } else {
+        serde_yaml::from_str(&contents)
+            .into_report()
+            .change_context_lazy(|| Error::Parse(path.to_path_buf()))
+    }
+}
+
+/// Value of every tenant, together with the default used for requests without a tenant.
+#[derive(Debug)]
+pub(crate) struct Tenants<T> {
+    selector: Selector,
+    default: T,
+    tenants: IndexMap<String, (Vec<String>, T)>,
+}
+
+impl<T> Tenants<T> {
+    pub(crate) fn new(selector: Selector, default: T) -> Self {
+        Self {
+            selector,
+            default,
+            tenants: IndexMap::new(),
+        }
+    }
+
+    pub(crate) fn insert(&mut self, name: String, hosts: Vec<String>, value: T) {
+        self.tenants.insert(name, (hosts, value));
+    }
+
+    pub(crate) const fn selector(&self) -> Selector {
+        self.selector
+    }
+
+    pub(crate) const fn default(&self) -> &T {
+        &self.default
+    }
+
+    /// Tenant with the name, `None` if there is none.
+    pub(crate) fn named(&self, name: &str) -> Option<&T> {
+        self.tenants.get(name).map(|(_, value)| value)
+    }
+
+    /// Name of the tenant serving the host (with or without port), `None` if there is none.
+    pub(crate) fn host(&self, host: &str) -> Option<&str> {
+        let host = host
+            .rsplit_once(':')
+            .filter(|(_, port)| port.bytes().all(|byte| byte.is_ascii_digit()))
+            .map_or(host, |(host, _)| host);
+
+        self.tenants
+            .iter()
+            .find(|(_, (hosts, _))| {
+                hosts
+                    .iter()
+                    .any(|candidate| candidate.eq_ignore_ascii_case(host))
+            })
+            .map(|(name, _)| name.as_str())
+    }
+
+    /// Every tenant by name, the default first without one.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (Option<&str>, &T)> {
+        core::iter::once((None, &self.default)).chain(
+            self.tenants
+                .iter()
+                .map(|(name, (_, value))| (Some(name.as_str()), value)),
+        )
+    }
+}
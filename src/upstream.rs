@@ -0,0 +1,642 @@
+use alloc::borrow::Cow;
+use core::{fmt::Debug, future::Future, time::Duration};
+use std::path::{Path, PathBuf};
+
+use base64::Engine;
+use clap::ValueHint;
+use error_stack::{Context, IntoReport, Report, Result, ResultExt};
+use reqwest::{
+    header::{HeaderMap, HeaderValue, AUTHORIZATION},
+    Certificate, Client, ClientBuilder, Identity, Proxy,
+};
+use thiserror::Error;
+use url::Url;
+
+use crate::{
+    breaker::{BreakerConfig, CircuitBreaker},
+    middleware,
+    secret::Secret,
+};
+
+#[derive(Debug, Error)]
+pub(crate) enum Error {
+    #[error("unable to read {}", .0.display())]
+    Io(PathBuf),
+    #[error("client certificate or key is malformed")]
+    Identity,
+    #[error("CA certificate is malformed")]
+    Certificate,
+    #[error("unable to construct HTTP client")]
+    Client,
+    #[error("credentials contain characters that are not allowed in a header")]
+    Credentials,
+    #[error("proxy URL is invalid")]
+    Proxy,
+}
+
+#[derive(Debug, Clone, clap::Args)]
+pub(crate) struct TlsConfig {
+    /// PEM encoded client certificate, presented to Hydra and Kratos for mutual TLS
+    #[clap(
+        long = "upstream-client-cert",
+        env = "UPSTREAM_CLIENT_CERT",
+        requires = "client_key",
+        value_hint = ValueHint::FilePath
+    )]
+    pub(crate) client_cert: Option<PathBuf>,
+
+    /// PEM encoded private key (PKCS#8, RSA or SEC1) belonging to the client certificate
+    #[clap(
+        long = "upstream-client-key",
+        env = "UPSTREAM_CLIENT_KEY",
+        requires = "client_cert",
+        value_hint = ValueHint::FilePath
+    )]
+    pub(crate) client_key: Option<PathBuf>,
+
+    /// PEM encoded CA bundle, trusted in addition to the system roots when talking to Hydra and
+    /// Kratos
+    #[clap(
+        long = "upstream-ca-file",
+        env = "UPSTREAM_CA_FILE",
+        value_hint = ValueHint::FilePath
+    )]
+    pub(crate) ca_file: Option<PathBuf>,
+
+    /// Disable certificate verification toward Hydra and Kratos, this is dangerous and should only
+    /// ever be used during development
+    #[clap(
+        long = "upstream-insecure-skip-verify",
+        env = "UPSTREAM_INSECURE_SKIP_VERIFY"
+    )]
+    pub(crate) insecure_skip_verify: bool,
+}
+
+/// Attached to reports of upstream requests which ran into the configured timeout.
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct TimedOut;
+
+/// Errors returned by the generated Hydra and Kratos clients.
+pub(crate) trait Failure: Context {
+    fn is_timeout(&self) -> bool;
+
+    /// Whether the request may succeed if tried again, this is the case for connection errors and
+    /// server errors.
+    fn is_transient(&self) -> bool;
+}
+
+/// The generated clients each have their own, but identical, error type.
+macro_rules! impl_failure {
+    ($($error:path),+) => {
+        $(
+            impl<T: Debug + Send + Sync + 'static> Failure for $error {
+                fn is_timeout(&self) -> bool {
+                    matches!(self, Self::Reqwest(error) if error.is_timeout())
+                }
+
+                fn is_transient(&self) -> bool {
+                    match self {
+                        Self::Reqwest(error) => error.is_connect() || error.is_timeout(),
+                        Self::ResponseError(response) => response.status.is_server_error(),
+                        Self::Serde(_) | Self::Io(_) => false,
+                    }
+                }
+            }
+        )+
+    };
+}
+
+impl_failure!(
+    ory_kratos_client::apis::Error<T>,
+    ory_hydra_client::apis::Error<T>,
+    ory_keto_client::apis::Error<T>
+);
+
+impl Failure for reqwest::Error {
+    fn is_timeout(&self) -> bool {
+        self.is_timeout()
+    }
+
+    fn is_transient(&self) -> bool {
+        self.is_connect()
+            || self.is_timeout()
+            || self
+                .status()
+                .map_or(false, |status| status.is_server_error())
+    }
+}
+
+#[derive(Debug, Clone, clap::Args)]
+pub(crate) struct RetryPolicy {
+    /// How often a failed upstream request is retried, only connection errors and server errors
+    /// are retried
+    #[clap(
+        long = "upstream-retries",
+        env = "UPSTREAM_RETRIES",
+        default_value_t = 2
+    )]
+    pub(crate) retries: u32,
+
+    /// Delay before the first retry, doubled on every subsequent attempt
+    #[clap(
+        long = "upstream-retry-backoff",
+        env = "UPSTREAM_RETRY_BACKOFF",
+        default_value = "100ms",
+        value_parser = humantime::parse_duration
+    )]
+    pub(crate) backoff: Duration,
+
+    /// Upper bound of the delay between two attempts
+    #[clap(
+        long = "upstream-retry-max-backoff",
+        env = "UPSTREAM_RETRY_MAX_BACKOFF",
+        default_value = "2s",
+        value_parser = humantime::parse_duration
+    )]
+    pub(crate) max_backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// "Full jitter" exponential backoff, the delay is chosen uniformly between zero and the
+    /// exponential bound to avoid synchronized retries of many clients.
+    fn delay(&self, attempt: u32) -> Duration {
+        let bound = self
+            .backoff
+            .saturating_mul(2_u32.saturating_pow(attempt))
+            .min(self.max_backoff);
+
+        bound.mul_f64(fastrand::f64())
+    }
+
+    pub(crate) async fn run<T, E, F, Fut>(&self, mut call: F) -> core::result::Result<T, E>
+    where
+        T: Send,
+        E: Failure,
+        F: FnMut() -> Fut + Send,
+        Fut: Future<Output = core::result::Result<T, E>> + Send,
+    {
+        let mut attempt = 0;
+
+        loop {
+            match call().await {
+                Err(error) if attempt < self.retries && error.is_transient() => {
+                    let delay = self.delay(attempt);
+                    tracing::warn!(%error, attempt, ?delay, "upstream request failed, retrying");
+
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+pub(crate) trait UpstreamResultExt<T> {
+    /// Convert the error of an upstream call into a report, marking it with [`TimedOut`] if the
+    /// request did not complete in time.
+    fn upstream<C: Context>(self, context: C) -> Result<T, C>;
+}
+
+impl<T, E: Failure> UpstreamResultExt<T> for core::result::Result<T, E> {
+    fn upstream<C: Context>(self, context: C) -> Result<T, C> {
+        self.map_err(|error| {
+            let timed_out = error.is_timeout();
+            let report = Report::new(error).change_context(context);
+
+            if timed_out {
+                report.attach(TimedOut)
+            } else {
+                report
+            }
+        })
+    }
+}
+
+/// Credentials attached as `Authorization` header to every request toward an upstream.
+#[derive(Debug, Clone)]
+pub(crate) enum Credentials {
+    Bearer(Secret),
+    Basic {
+        username: String,
+        password: Option<Secret>,
+    },
+}
+
+impl Credentials {
+    fn authorization(&self) -> String {
+        match self {
+            Self::Bearer(token) => format!("Bearer {}", token.expose()),
+            Self::Basic { username, password } => {
+                let password = password.as_ref().map_or("", Secret::expose);
+                let encoded = base64::engine::general_purpose::STANDARD
+                    .encode(format!("{username}:{password}"));
+
+                format!("Basic {encoded}")
+            }
+        }
+    }
+}
+
+// `Certificate::from_pem` only ever reads the first certificate, bundles are split on this marker
+const PEM_CERTIFICATE_END: &str = "-----END CERTIFICATE-----";
+
+fn read(path: &Path) -> Result<Vec<u8>, Error> {
+    std::fs::read(path)
+        .into_report()
+        .change_context_lazy(|| Error::Io(path.to_path_buf()))
+}
+
+impl TlsConfig {
+    fn identity(&self) -> Result<Option<Identity>, Error> {
+        let (Some(cert), Some(key)) = (&self.client_cert, &self.client_key) else {
+            return Ok(None);
+        };
+
+        // rustls expects both the certificate chain and the key in a single PEM buffer
+        let mut pem = read(cert)?;
+        pem.push(b'\n');
+        pem.extend(read(key)?);
+
+        Identity::from_pem(&pem)
+            .into_report()
+            .change_context(Error::Identity)
+            .map(Some)
+    }
+
+    fn certificates(&self) -> Result<Vec<Certificate>, Error> {
+        let Some(path) = &self.ca_file else {
+            return Ok(vec![]);
+        };
+
+        let bundle = read(path)?;
+        let bundle = String::from_utf8(bundle)
+            .into_report()
+            .change_context(Error::Certificate)?;
+
+        bundle
+            .split_inclusive(PEM_CERTIFICATE_END)
+            .filter(|block| block.contains(PEM_CERTIFICATE_END))
+            .map(|block| {
+                Certificate::from_pem(block.trim().as_bytes())
+                    .into_report()
+                    .change_context(Error::Certificate)
+            })
+            .collect()
+    }
+
+    fn apply(&self, mut builder: ClientBuilder) -> Result<ClientBuilder, Error> {
+        if let Some(identity) = self.identity()? {
+            builder = builder.identity(identity);
+        }
+
+        for certificate in self.certificates()? {
+            builder = builder.add_root_certificate(certificate);
+        }
+
+        if self.insecure_skip_verify {
+            tracing::warn!("certificate verification toward upstreams is disabled");
+
+            builder = builder.danger_accept_invalid_certs(true);
+        }
+
+        Ok(builder)
+    }
+}
+
+#[derive(Debug, Clone, clap::Args)]
+pub(crate) struct UpstreamConfig {
+    #[command(flatten)]
+    pub(crate) tls: TlsConfig,
+
+    #[command(flatten)]
+    pub(crate) retry: RetryPolicy,
+
+    #[command(flatten)]
+    pub(crate) breaker: BreakerConfig,
+
+    /// Proxy used for all requests toward Hydra and Kratos, credentials may be embedded in the
+    /// URL. If unset, `HTTP_PROXY`, `HTTPS_PROXY` and `NO_PROXY` are honored.
+    #[clap(
+        long = "upstream-proxy",
+        env = "UPSTREAM_PROXY",
+        value_hint = ValueHint::Url
+    )]
+    pub(crate) proxy: Option<Url>,
+
+    /// Maximum number of idle connections kept open per upstream
+    #[clap(
+        long = "upstream-pool-size",
+        env = "UPSTREAM_POOL_SIZE",
+        default_value_t = 32
+    )]
+    pub(crate) pool_size: usize,
+
+    /// How long idle connections are kept in the pool
+    #[clap(
+        long = "upstream-pool-idle-timeout",
+        env = "UPSTREAM_POOL_IDLE_TIMEOUT",
+        default_value = "90s",
+        value_parser = humantime::parse_duration
+    )]
+    pub(crate) pool_idle_timeout: Duration,
+
+    /// Interval of TCP keep-alive probes on upstream connections
+    #[clap(
+        long = "upstream-keep-alive",
+        env = "UPSTREAM_KEEP_ALIVE",
+        default_value = "60s",
+        value_parser = humantime::parse_duration
+    )]
+    pub(crate) keep_alive: Duration,
+
+    /// Maximum time a single request toward Hydra or Kratos may take, including the connect phase
+    #[clap(
+        long = "upstream-timeout",
+        env = "UPSTREAM_TIMEOUT",
+        default_value = "10s",
+        value_parser = humantime::parse_duration
+    )]
+    pub(crate) timeout: Duration,
+
+    /// Maximum time to wait for a connection to Hydra or Kratos to be established
+    #[clap(
+        long = "upstream-connect-timeout",
+        env = "UPSTREAM_CONNECT_TIMEOUT",
+        default_value = "5s",
+        value_parser = humantime::parse_duration
+    )]
+    pub(crate) connect_timeout: Duration,
+}
+
+impl UpstreamConfig {
+    fn client(&self, credentials: Option<&Credentials>) -> Result<Client, Error> {
+        let mut builder = Client::builder()
+            .use_rustls_tls()
+            .pool_max_idle_per_host(self.pool_size)
+            .pool_idle_timeout(self.pool_idle_timeout)
+            .tcp_keepalive(self.keep_alive)
+            .timeout(self.timeout)
+            .connect_timeout(self.connect_timeout);
+
+        // not every endpoint of the generated clients honors the configured credentials (e.g.
+        // `get_identity_schema`), we therefore attach them to every request instead
+        if let Some(credentials) = credentials {
+            let mut value = HeaderValue::try_from(credentials.authorization())
+                .into_report()
+                .change_context(Error::Credentials)?;
+            value.set_sensitive(true);
+
+            builder = builder.default_headers(HeaderMap::from_iter([(AUTHORIZATION, value)]));
+        }
+
+        if let Some(proxy) = &self.proxy {
+            let proxy = Proxy::all(proxy.as_str())
+                .into_report()
+                .change_context(Error::Proxy)?;
+
+            builder = builder.proxy(proxy);
+        }
+
+        let builder = self.tls.apply(builder)?;
+
+        builder.build().into_report().change_context(Error::Client)
+    }
+
+    /// Client without credentials for other requests in the deployment, e.g. toward the scope
+    /// configuration, so that they honor the same TLS, proxy and timeout settings.
+    pub(crate) fn http(&self) -> Result<Client, Error> {
+        self.client(None)
+    }
+
+    pub(crate) fn connect(&self) -> Result<Upstreams, Error> {
+        Ok(Upstreams {
+            config: self,
+            shared: self.client(None)?,
+            tenant: String::new(),
+        })
+    }
+}
+
+/// Clients toward Hydra and Kratos.
+///
+/// Both share a single connection pool, unless credentials need to be attached, in that case a
+/// dedicated client with the same settings is used for that upstream.
+pub(crate) struct Upstreams<'a> {
+    config: &'a UpstreamConfig,
+    shared: Client,
+    tenant: String,
+}
+
+impl Upstreams<'_> {
+    /// Clients of the tenant, sharing the connection pool, but not the circuit breakers.
+    pub(crate) fn tenant(&self, tenant: &str) -> Self {
+        Self {
+            config: self.config,
+            shared: self.shared.clone(),
+            tenant: tenant.to_owned(),
+        }
+    }
+
+    fn client(&self, credentials: Option<&Credentials>) -> Result<Client, Error> {
+        credentials.map_or_else(
+            || Ok(self.shared.clone()),
+            |credentials| self.config.client(Some(credentials)),
+        )
+    }
+
+    fn wrap<C>(&self, name: &'static str, config: C) -> Upstream<C> {
+        Upstream {
+            config,
+            retry: self.config.retry.clone(),
+            breaker: CircuitBreaker::new(name, self.tenant.clone(), self.config.breaker.clone()),
+        }
+    }
+
+    pub(crate) fn kratos(
+        &self,
+        url: &Url,
+        credentials: Option<&Credentials>,
+    ) -> Result<Kratos, Error> {
+        let config = ory_kratos_client::apis::configuration::Configuration {
+            base_path: url.as_str().trim_end_matches('/').to_owned(),
+            client: self.client(credentials)?,
+            ..Default::default()
+        };
+
+        Ok(self.wrap("kratos", config))
+    }
+
+    /// Client toward the public API of Kratos, which never carries the credentials of the admin
+    /// API.
+    pub(crate) fn kratos_public(&self, url: &Url) -> Result<Kratos, Error> {
+        let config = ory_kratos_client::apis::configuration::Configuration {
+            base_path: url.as_str().trim_end_matches('/').to_owned(),
+            client: self.client(None)?,
+            ..Default::default()
+        };
+
+        Ok(self.wrap("kratos_public", config))
+    }
+
+    pub(crate) fn hydra(
+        &self,
+        url: &Url,
+        credentials: Option<&Credentials>,
+    ) -> Result<Hydra, Error> {
+        let config = ory_hydra_client::apis::configuration::Configuration {
+            base_path: url.as_str().trim_end_matches('/').to_owned(),
+            client: self.client(credentials)?,
+            ..Default::default()
+        };
+
+        Ok(self.wrap("hydra", config))
+    }
+
+    pub(crate) fn keto(&self, url: &Url) -> Result<Keto, Error> {
+        let config = ory_keto_client::apis::configuration::Configuration {
+            base_path: url.as_str().trim_end_matches('/').to_owned(),
+            client: self.client(None)?,
+            ..Default::default()
+        };
+
+        Ok(self.wrap("keto", config))
+    }
+
+    pub(crate) fn webhook(
+        &self,
+        url: &Url,
+        credentials: Option<&Credentials>,
+    ) -> Result<Webhook, Error> {
+        let config = Endpoint {
+            url: url.clone(),
+            client: self.client(credentials)?,
+            user_agent: None,
+        };
+
+        Ok(self.wrap("webhook", config))
+    }
+}
+
+/// Single URL called with a plain client, for upstreams without a generated client.
+#[derive(Debug, Clone)]
+pub(crate) struct Endpoint {
+    pub(crate) url: Url,
+    pub(crate) client: Client,
+    pub(crate) user_agent: Option<String>,
+}
+
+pub(crate) type Kratos = Upstream<ory_kratos_client::apis::configuration::Configuration>;
+pub(crate) type Hydra = Upstream<ory_hydra_client::apis::configuration::Configuration>;
+pub(crate) type Keto = Upstream<ory_keto_client::apis::configuration::Configuration>;
+pub(crate) type Webhook = Upstream<Endpoint>;
+
+/// Configuration of a generated client, together with the retry policy and circuit breaker that
+/// guard every call made through it.
+#[derive(Debug)]
+pub(crate) struct Upstream<C> {
+    pub(crate) config: C,
+    retry: RetryPolicy,
+    breaker: CircuitBreaker,
+}
+
+/// Configuration of the generated clients that can carry the id of the request being handled.
+pub(crate) trait RequestIdentified: Clone {
+    fn with_request_id(&self, id: &str) -> Self;
+}
+
+// the generated clients do not allow to set headers per request, the request id is instead
+// forwarded as part of the user agent, which is logged by Hydra and Kratos
+fn user_agent(user_agent: Option<&str>, id: &str) -> String {
+    user_agent.map_or_else(
+        || format!("request-id/{id}"),
+        |user_agent| format!("{user_agent} request-id/{id}"),
+    )
+}
+
+impl RequestIdentified for ory_kratos_client::apis::configuration::Configuration {
+    fn with_request_id(&self, id: &str) -> Self {
+        Self {
+            user_agent: Some(user_agent(self.user_agent.as_deref(), id)),
+            ..self.clone()
+        }
+    }
+}
+
+impl RequestIdentified for ory_hydra_client::apis::configuration::Configuration {
+    fn with_request_id(&self, id: &str) -> Self {
+        Self {
+            user_agent: Some(user_agent(self.user_agent.as_deref(), id)),
+            ..self.clone()
+        }
+    }
+}
+
+impl RequestIdentified for ory_keto_client::apis::configuration::Configuration {
+    fn with_request_id(&self, id: &str) -> Self {
+        Self {
+            user_agent: Some(user_agent(self.user_agent.as_deref(), id)),
+            ..self.clone()
+        }
+    }
+}
+
+impl RequestIdentified for Endpoint {
+    fn with_request_id(&self, id: &str) -> Self {
+        Self {
+            user_agent: Some(user_agent(self.user_agent.as_deref(), id)),
+            ..self.clone()
+        }
+    }
+}
+
+impl<C: RequestIdentified> Upstream<C> {
+    pub(crate) const fn breaker(&self) -> &CircuitBreaker {
+        &self.breaker
+    }
+
+    /// View of the upstream for the request currently being handled.
+    pub(crate) fn scoped(&self) -> Scoped<'_, C> {
+        let config = middleware::request_id().map_or_else(
+            || Cow::Borrowed(&self.config),
+            |id| Cow::Owned(self.config.with_request_id(&id)),
+        );
+
+        Scoped {
+            upstream: self,
+            config,
+        }
+    }
+}
+
+pub(crate) struct Scoped<'a, C: Clone> {
+    upstream: &'a Upstream<C>,
+    config: Cow<'a, C>,
+}
+
+impl<C: Clone + Send + Sync> Scoped<'_, C> {
+    pub(crate) async fn call<'a, T, E, F, Fut, X>(&'a self, context: X, mut call: F) -> Result<T, X>
+    where
+        T: Send,
+        E: Failure,
+        F: FnMut(&'a C) -> Fut + Send,
+        Fut: Future<Output = core::result::Result<T, E>> + Send,
+        X: Context,
+    {
+        let Upstream { retry, breaker, .. } = self.upstream;
+
+        let permit = match breaker.acquire() {
+            Ok(permit) => permit,
+            Err(open) => return Err(Report::new(open).change_context(context)),
+        };
+
+        let result = retry.run(|| call(&self.config)).await;
+
+        match &result {
+            Err(error) if error.is_transient() => permit.failure(),
+            _ => permit.success(),
+        }
+
+        result.upstream(context)
+    }
+}
@@ -1,41 +1,100 @@
-use std::io::Write;
+use core::{
+    convert::Infallible,
+    fmt::{self, Display, Formatter, Write as _},
+    str::FromStr,
+};
+use std::{
+    collections::HashSet,
+    io::Write,
+    path::{Path, PathBuf},
+};
 
-use console::Term;
-use error_stack::{IntoReport, Result, ResultExt};
-use ory_kratos_client::apis::configuration::Configuration;
+use clap::ValueHint;
+use console::{style, Term};
+use error_stack::{IntoReport, Report, Result, ResultExt};
+use ory_kratos_client::models::IdentitySchemaContainer;
 use ron_to_table::RonTable;
 use schemars::schema::SchemaObject;
-use serde::Deserialize;
-use tabled::settings::Style;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tabled::{builder::Builder, settings::Style};
 use thiserror::Error;
 
-use crate::{cache::ScopeCache, schema::ImplicitScope, serve::Config};
+use crate::{
+    cache::ScopeCache,
+    lint, schema,
+    schema::{Context, ImplicitScope, ParseOptions, Problem, Scope, ScopeConfig},
+    serve::Config,
+    upstream::Kratos,
+};
 
 #[derive(Debug, Error)]
-pub(crate) enum Error {
+pub enum Error {
     #[error("error while fetching from Kratos")]
     Kratos,
     #[error("schema is malformed")]
     IdentitySchemaMalformed,
     #[error("unable to deserialize schema")]
     Serde,
-    #[error("unable to write to stdout")]
+    #[error("unable to write output")]
     Io,
+    #[error("unable to configure upstream clients")]
+    Upstream,
+    #[error("unable to read {}", .0.display())]
+    File(PathBuf),
+    #[error("traits are malformed")]
+    TraitsMalformed,
+    #[error("{0} problems found in the scope configuration")]
+    Strict(usize),
+    #[error("unable to write snapshot {}", .0.display())]
+    Snapshot(PathBuf),
+    #[error("{0} snapshots diverge from the issued claims")]
+    Diverged(usize),
 }
 
-pub(crate) async fn fetch(
-    config: &Configuration,
-    keyword: &str,
-    id: &str,
-    direct_mapping: bool,
-) -> Result<(ScopeCache, crate::schema::ScopeConfig), Error> {
-    // fetch the identity schema from kratos
-    let identity_schema = ory_kratos_client::apis::identity_api::get_identity_schema(config, id)
-        .await
-        .into_report()
-        .change_context(Error::Kratos)?;
+#[derive(Debug, Copy, Clone, PartialEq, Eq, clap::ValueEnum)]
+pub(crate) enum Output {
+    Table,
+    Json,
+    Yaml,
+    Ron,
+}
+
+/// Options of the `validate` command, shared between the remote and the local schema.
+#[derive(Debug, Clone, clap::Args)]
+pub(crate) struct Options {
+    /// Output format, defaults to `table` for the configuration and `json` for the claim preview
+    #[clap(long, value_enum)]
+    pub(crate) output: Option<Output>,
+
+    /// Identity traits (JSON) to preview the issued claims for instead of printing the
+    /// configuration, `-` reads from stdin
+    #[clap(long, value_hint = ValueHint::FilePath)]
+    pub(crate) traits: Option<PathBuf>,
+
+    /// Scopes requested in the preview, defaults to all configured scopes
+    #[clap(long, value_delimiter = ',', requires = "traits")]
+    pub(crate) scopes: Vec<String>,
+
+    /// Fail if any part of the scope configuration is malformed, instead of skipping it with a
+    /// warning
+    #[clap(long)]
+    pub(crate) strict: bool,
+
+    /// Write the claims issued for the traits to a canonical JSON file per scope in this
+    /// directory, e.g. to commit them as regression tests
+    #[clap(long, requires = "traits", value_hint = ValueHint::DirPath)]
+    pub(crate) snapshot: Option<PathBuf>,
+
+    /// Compare the claims with the files in the `--snapshot` directory instead of writing them,
+    /// failing if any of them diverges
+    #[clap(long, requires = "snapshot")]
+    pub(crate) check: bool,
+}
 
-    let traits = identity_schema
+/// Extract the schema of the traits from an identity schema.
+pub(crate) fn traits_of(identity_schema: &Value) -> Result<SchemaObject, Error> {
+    let mut traits = identity_schema
         .get("properties")
         .ok_or_else(|| {
             tracing::error!("identity schema is malformed");
@@ -48,45 +107,611 @@ pub(crate) async fn fetch(
         })?
         .clone();
 
+    // references are relative to the identity schema, its definitions are carried over, so that
+    // `#/definitions/..` and `#/$defs/..` resolve against the traits as well
+    if let Value::Object(traits) = &mut traits {
+        for key in ["definitions", "$defs"] {
+            if let Some(definitions) = identity_schema.get(key) {
+                traits.entry(key).or_insert_with(|| definitions.clone());
+            }
+        }
+    }
+
     let schema: SchemaObject = serde_json::from_value(traits)
         .into_report()
         .change_context(Error::IdentitySchemaMalformed)?;
 
-    tracing::debug!(?schema, "fetched schema from kratos");
+    Ok(schema)
+}
 
-    let cache = ImplicitScope::find(keyword, schema.clone(), vec![]);
-    let mut cache = ScopeCache::new(cache);
+/// Fetch the identity schema from Kratos and return the schema of its traits.
+pub(crate) async fn fetch_traits(kratos: &Kratos, id: &str) -> Result<SchemaObject, Error> {
+    let identity_schema = kratos
+        .scoped()
+        .call(Error::Kratos, |config| {
+            ory_kratos_client::apis::identity_api::get_identity_schema(config, id)
+        })
+        .await?;
 
-    let config = crate::schema::ScopeConfig::from_root(keyword, schema, &mut cache, direct_mapping);
+    let schema = traits_of(&identity_schema)?;
 
-    Ok((cache, config))
+    tracing::debug!(?schema, "fetched schema from kratos");
+
+    Ok(schema)
 }
 
-pub(crate) async fn run(schema: String, config: Config) -> Result<(), Error> {
-    let kratos = Configuration {
-        base_path: config.kratos_url.as_str().trim_end_matches('/').to_owned(),
-        ..Default::default()
+/// Read a JSON document from a file, or stdin if the path is `-`.
+pub(crate) fn read_json<C: error_stack::Context>(
+    path: &Path,
+    malformed: C,
+) -> Result<Value, Error> {
+    let contents = if path == Path::new("-") {
+        std::io::read_to_string(std::io::stdin())
+    } else {
+        std::fs::read_to_string(path)
     };
 
-    let (_, config) = fetch(&kratos, &config.keyword, &schema, config.direct_mapping).await?;
-
-    let config = serde_value::to_value(config)
+    let contents = contents
         .into_report()
-        .change_context(Error::Kratos)?;
+        .change_context_lazy(|| Error::File(path.to_path_buf()))?;
 
-    let config: ron::Value = ron::Value::deserialize(config)
+    serde_json::from_str(&contents)
         .into_report()
-        .change_context(Error::Serde)?;
+        .change_context(malformed)
+        .change_context_lazy(|| Error::File(path.to_path_buf()))
+}
+
+/// Identity schema either stored in Kratos (`<schema-id>`) or in a local file (`file:<path>`,
+/// `file:-` reads from stdin).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Source {
+    Kratos(String),
+    File(PathBuf),
+}
+
+impl Source {
+    pub(crate) const fn is_remote(&self) -> bool {
+        matches!(self, Self::Kratos(_))
+    }
+
+    /// ID of the identity schema in Kratos, `None` for local files.
+    pub(crate) fn id(&self) -> Option<&str> {
+        match self {
+            Self::Kratos(id) => Some(id),
+            Self::File(_) => None,
+        }
+    }
+
+    /// Load the schema of the traits, `kratos` is only used for remote schemas.
+    pub(crate) async fn load(&self, kratos: Option<&Kratos>) -> Result<SchemaObject, Error> {
+        match (self, kratos) {
+            (Self::Kratos(id), Some(kratos)) => fetch_traits(kratos, id).await,
+            (Self::Kratos(_), None) => Err(Report::new(Error::Upstream)),
+            (Self::File(path), _) => read_traits(path),
+        }
+    }
+}
+
+impl FromStr for Source {
+    type Err = Infallible;
+
+    fn from_str(value: &str) -> core::result::Result<Self, Self::Err> {
+        Ok(value.strip_prefix("file:").map_or_else(
+            || Self::Kratos(value.to_owned()),
+            |path| Self::File(PathBuf::from(path)),
+        ))
+    }
+}
+
+impl Display for Source {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Kratos(id) => f.write_str(id),
+            Self::File(path) => write!(f, "file:{}", path.display()),
+        }
+    }
+}
+
+/// Read an identity schema from a file, or stdin if the path is `-`, and return the schema of its
+/// traits.
+pub(crate) fn read_traits(path: &Path) -> Result<SchemaObject, Error> {
+    let identity_schema = read_json(path, Error::IdentitySchemaMalformed)?;
+
+    traits_of(&identity_schema)
+}
+
+pub(crate) fn connect(config: &Config) -> Result<Kratos, Error> {
+    config
+        .upstream
+        .connect()
+        .and_then(|upstreams| {
+            upstreams.kratos(&config.kratos_url, config.kratos_credentials.as_ref())
+        })
+        .change_context(Error::Upstream)
+}
+
+/// Parse the scope configuration of the identity schema with the ID (`None` for local files),
+/// malformed parts are skipped and recorded in `problems`, together with the findings of the lints.
+pub(crate) fn parse(
+    options: &ParseOptions,
+    id: Option<&str>,
+    schema: &SchemaObject,
+    problems: &mut Vec<Problem>,
+) -> (ScopeCache, ScopeConfig) {
+    let cache = ImplicitScope::find(options, schema.clone(), problems);
+    let mut cache = ScopeCache::new(cache);
+
+    let config = ScopeConfig::from_root(options, id, schema.clone(), &mut cache, problems);
+    lint::lint(&config, &cache, schema, problems);
+
+    if config.scopes.is_empty() {
+        problems.push(Problem::warning(
+            &jsonptr::Pointer::root(),
+            format!(
+                "no scopes are configured, the keyword `{}` is not used",
+                options.keywords.join("`, `")
+            ),
+        ));
+    }
 
-    let table = RonTable::new()
-        .collapse()
-        .with(Style::rounded())
-        .build(&config);
+    (cache, config)
+}
+
+pub(crate) async fn fetch(
+    kratos: &Kratos,
+    options: &ParseOptions,
+    id: &str,
+    problems: &mut Vec<Problem>,
+) -> Result<(ScopeCache, ScopeConfig), Error> {
+    let schema = fetch_traits(kratos, id).await?;
+
+    Ok(parse(options, Some(id), &schema, problems))
+}
+
+/// In strict mode any error fails validation, listing every error found.
+fn check<T: Display>(errors: Vec<T>, options: &Options) -> Result<(), Error> {
+    if !options.strict || errors.is_empty() {
+        return Ok(());
+    }
+
+    let report = Report::new(Error::Strict(errors.len()));
+
+    Err(errors.into_iter().fold(report, |report, error| {
+        report.attach_printable(error.to_string())
+    }))
+}
+
+/// Print the problems to stderr, in strict mode errors are part of the returned report instead.
+fn report(problems: Vec<Problem>, options: &Options) -> Result<(), Error> {
+    let mut term = Term::stderr();
+
+    let (errors, warnings): (Vec<_>, Vec<_>) = problems.into_iter().partition(Problem::is_error);
+
+    for warning in &warnings {
+        writeln!(term, "{} {warning}", style("warning:").yellow())
+            .into_report()
+            .change_context(Error::Io)?;
+    }
+
+    if !options.strict {
+        for error in &errors {
+            writeln!(term, "{} {error}", style("error:").red())
+                .into_report()
+                .change_context(Error::Io)?;
+        }
+    }
+
+    check(errors, options)
+}
+
+pub(crate) fn render<T: Serialize>(value: &T, output: Output) -> Result<String, Error> {
+    let rendered = match output {
+        Output::Table => {
+            let value = serde_value::to_value(value)
+                .into_report()
+                .change_context(Error::Serde)?;
+
+            let value = ron::Value::deserialize(value)
+                .into_report()
+                .change_context(Error::Serde)?;
+
+            RonTable::new()
+                .collapse()
+                .with(Style::rounded())
+                .build(&value)
+        }
+        Output::Json => serde_json::to_string_pretty(value)
+            .into_report()
+            .change_context(Error::Serde)?,
+        Output::Yaml => serde_yaml::to_string(value)
+            .into_report()
+            .change_context(Error::Serde)?,
+        Output::Ron => ron::ser::to_string_pretty(value, ron::ser::PrettyConfig::default())
+            .into_report()
+            .change_context(Error::Serde)?,
+    };
 
+    Ok(rendered)
+}
+
+pub(crate) fn write(output: &str) -> Result<(), Error> {
     let mut term = Term::stdout();
-    term.write_all(table.as_bytes())
+
+    term.write_all(output.as_bytes())
         .into_report()
         .change_context(Error::Io)?;
 
+    if !output.ends_with('\n') {
+        term.write_all(b"\n")
+            .into_report()
+            .change_context(Error::Io)?;
+    }
+
     Ok(())
 }
+
+/// Claims in the shape they are sent to Hydra, and the ones returned by the userinfo endpoint.
+#[derive(Serialize)]
+pub(crate) struct Preview {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) subject: Option<String>,
+    pub(crate) id_token: Value,
+    pub(crate) access_token: Value,
+    pub(crate) userinfo: Value,
+}
+
+fn requested(config: &ScopeConfig, scopes: &[String]) -> Vec<Scope> {
+    if scopes.is_empty() {
+        config.scopes.keys().cloned().collect()
+    } else {
+        scopes.iter().cloned().map(Scope::new).collect()
+    }
+}
+
+fn claims(
+    cache: &ScopeCache,
+    config: &ScopeConfig,
+    context: &Context,
+    requested: &HashSet<Scope>,
+) -> Preview {
+    let claims = config.resolve_all(context, cache, requested);
+
+    Preview {
+        subject: claims.subject,
+        id_token: claims.id_token,
+        access_token: claims.access_token,
+        userinfo: claims.userinfo,
+    }
+}
+
+/// Print the claims that would be issued for the traits, as they are sent to Hydra.
+fn preview(
+    cache: &ScopeCache,
+    config: &ScopeConfig,
+    traits: &Path,
+    scopes: &[String],
+    output: Output,
+) -> Result<(), Error> {
+    let context = Context::from_traits(read_json(traits, Error::TraitsMalformed)?);
+
+    let requested = requested(config, scopes).into_iter().collect();
+    let preview = claims(cache, config, &context, &requested);
+
+    write(&render(&preview, output)?)
+}
+
+/// Sort the keys of every object, so that snapshots only change if the claims do.
+fn canonical(value: Value) -> Value {
+    match value {
+        Value::Object(object) => {
+            let mut entries: Vec<_> = object.into_iter().collect();
+            entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+            Value::Object(
+                entries
+                    .into_iter()
+                    .map(|(key, value)| (key, canonical(value)))
+                    .collect(),
+            )
+        }
+        Value::Array(values) => Value::Array(values.into_iter().map(canonical).collect()),
+        value => value,
+    }
+}
+
+/// Name of the snapshot of a scope, scopes may contain characters that are not valid in a path
+/// (e.g. `https://example.com/scope`), which are percent-encoded, so that no two scopes share a
+/// snapshot.
+fn snapshot_name(scope: &Scope) -> String {
+    let mut name = String::with_capacity(scope.as_str().len() + 5);
+
+    for byte in scope.as_str().bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'_' | b'.') {
+            name.push(char::from(byte));
+        } else {
+            // writing to a string cannot fail
+            let _ = write!(name, "%{byte:02X}");
+        }
+    }
+
+    name.push_str(".json");
+
+    name
+}
+
+/// Compare a snapshot with the claims, returning why it diverges.
+fn compare(path: &Path, expected: &Value) -> Result<Option<String>, Error> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+            return Ok(Some(format!("{} is missing", path.display())));
+        }
+        Err(error) => {
+            return Err(Report::new(error).change_context(Error::File(path.to_path_buf())));
+        }
+    };
+
+    let diverged = match serde_json::from_str::<Value>(&contents) {
+        Ok(actual) if actual == *expected => None,
+        Ok(actual) => Some(format!(
+            "{} contains {actual}, but the claims are {expected}",
+            path.display()
+        )),
+        Err(error) => Some(format!("{} is not valid JSON: {error}", path.display())),
+    };
+
+    Ok(diverged)
+}
+
+/// Write the claims issued for every scope on its own into the directory, or compare them with the
+/// files written before.
+fn snapshot(
+    cache: &ScopeCache,
+    config: &ScopeConfig,
+    traits: &Path,
+    directory: &Path,
+    options: &Options,
+) -> Result<(), Error> {
+    let context = Context::from_traits(read_json(traits, Error::TraitsMalformed)?);
+
+    if !options.check {
+        std::fs::create_dir_all(directory)
+            .into_report()
+            .change_context_lazy(|| Error::Snapshot(directory.to_path_buf()))?;
+    }
+
+    let mut names = HashSet::new();
+    let mut diverged = vec![];
+    let mut term = Term::stdout();
+
+    for scope in requested(config, &options.scopes) {
+        let name = snapshot_name(&scope);
+        let path = directory.join(&name);
+
+        let preview = claims(cache, config, &context, &HashSet::from([scope]));
+        let value = serde_json::to_value(preview)
+            .into_report()
+            .change_context(Error::Serde)
+            .map(canonical)?;
+
+        if options.check {
+            diverged.extend(compare(&path, &value)?);
+        } else {
+            let contents = serde_json::to_string_pretty(&value)
+                .into_report()
+                .change_context(Error::Serde)?;
+
+            std::fs::write(&path, format!("{contents}\n"))
+                .into_report()
+                .change_context_lazy(|| Error::Snapshot(path.clone()))?;
+
+            writeln!(term, "wrote {}", path.display())
+                .into_report()
+                .change_context(Error::Io)?;
+        }
+
+        names.insert(name);
+    }
+
+    if !options.check {
+        return Ok(());
+    }
+
+    let entries = std::fs::read_dir(directory)
+        .into_report()
+        .change_context_lazy(|| Error::File(directory.to_path_buf()))?;
+
+    // snapshots of scopes that have been removed
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let name = entry.file_name().to_string_lossy().into_owned();
+
+        if path
+            .extension()
+            .map_or(false, |extension| extension == "json")
+            && !names.contains(&name)
+        {
+            diverged.push(format!("{} has no matching scope", path.display()));
+        }
+    }
+
+    if diverged.is_empty() {
+        return Ok(());
+    }
+
+    let report = Report::new(Error::Diverged(diverged.len()));
+
+    Err(diverged.into_iter().fold(report, Report::attach_printable))
+}
+
+fn print(cache: &ScopeCache, config: &ScopeConfig, options: &Options) -> Result<(), Error> {
+    if let (Some(traits), Some(directory)) = (&options.traits, &options.snapshot) {
+        return snapshot(cache, config, traits, directory, options);
+    }
+
+    if let Some(traits) = &options.traits {
+        let output = options.output.unwrap_or(Output::Json);
+
+        return preview(cache, config, traits, &options.scopes, output);
+    }
+
+    let output = options.output.unwrap_or(Output::Table);
+
+    write(&render(config, output)?)
+}
+
+pub(crate) async fn run(schema: String, options: Options, config: Config) -> Result<(), Error> {
+    let kratos = connect(&config)?;
+
+    let mut problems = vec![];
+    let (cache, config) = fetch(&kratos, &config.parse, &schema, &mut problems).await?;
+
+    report(problems, &options)?;
+
+    print(&cache, &config, &options)
+}
+
+pub(crate) fn run_file(
+    path: &Path,
+    options: &Options,
+    parse_options: &ParseOptions,
+) -> Result<(), Error> {
+    let schema = read_traits(path)?;
+
+    let mut problems = vec![];
+    let (cache, config) = parse(parse_options, None, &schema, &mut problems);
+
+    report(problems, options)?;
+
+    print(&cache, &config, options)
+}
+
+/// Outcome of validating one of the identity schemas known to Kratos.
+#[derive(Serialize)]
+struct Summary {
+    schema: String,
+    scopes: usize,
+    warnings: usize,
+    errors: usize,
+}
+
+pub(crate) async fn list_schemas(kratos: &Kratos) -> Result<Vec<IdentitySchemaContainer>, Error> {
+    const PER_PAGE: i64 = 250;
+
+    let mut schemas = vec![];
+
+    for page in 1.. {
+        let batch = kratos
+            .scoped()
+            .call(Error::Kratos, |config| {
+                ory_kratos_client::apis::identity_api::list_identity_schemas(
+                    config,
+                    Some(PER_PAGE),
+                    Some(page),
+                )
+            })
+            .await?;
+
+        let last = i64::try_from(batch.len()).map_or(true, |len| len < PER_PAGE);
+        schemas.extend(batch);
+
+        if last {
+            break;
+        }
+    }
+
+    Ok(schemas)
+}
+
+fn summarize(
+    container: IdentitySchemaContainer,
+    options: &ParseOptions,
+    problems: &mut Vec<Problem>,
+) -> Summary {
+    let schema = container.id.unwrap_or_default();
+
+    let traits = container
+        .schema
+        .ok_or_else(|| Report::new(Error::IdentitySchemaMalformed))
+        .and_then(|identity_schema| traits_of(&identity_schema));
+
+    let scopes = match traits {
+        Ok(traits) => parse(options, Some(&schema), &traits, problems)
+            .1
+            .scopes
+            .len(),
+        Err(report) => {
+            problems.push(Problem::error(
+                &jsonptr::Pointer::root(),
+                format!("{report:#}"),
+            ));
+
+            0
+        }
+    };
+
+    let warnings = problems
+        .iter()
+        .filter(|problem| !problem.is_error())
+        .count();
+
+    Summary {
+        schema,
+        scopes,
+        warnings,
+        errors: problems.len() - warnings,
+    }
+}
+
+/// Validate every identity schema in Kratos and print a summary per schema.
+pub(crate) async fn run_all(options: Options, config: Config) -> Result<(), Error> {
+    let kratos = connect(&config)?;
+
+    let mut summaries = vec![];
+    let mut errors = vec![];
+
+    for container in list_schemas(&kratos).await? {
+        let mut problems = vec![];
+        let summary = summarize(container, &config.parse, &mut problems);
+
+        errors.extend(
+            problems
+                .into_iter()
+                .filter(Problem::is_error)
+                .map(|problem| format!("schema `{}`: {problem}", summary.schema)),
+        );
+
+        summaries.push(summary);
+    }
+
+    // a row per schema reads better than the generic nested table
+    let output = match options.output.unwrap_or(Output::Table) {
+        Output::Table => {
+            let mut builder = Builder::default();
+            builder.set_header(["schema", "scopes", "warnings", "errors"]);
+
+            for summary in &summaries {
+                builder.push_record([
+                    summary.schema.clone(),
+                    summary.scopes.to_string(),
+                    summary.warnings.to_string(),
+                    summary.errors.to_string(),
+                ]);
+            }
+
+            builder.build().with(Style::rounded()).to_string()
+        }
+        output => render(&summaries, output)?,
+    };
+
+    write(&output)?;
+
+    check(errors, &options)
+}
+
+/// Print the JSON Schema of the keyword, for editor completion and validation of identity schemas.
+pub(crate) fn export_config_schema(keywords: &[String]) -> Result<(), Error> {
+    let keyword = keywords.first().map_or("", String::as_str);
+
+    write(&render(&schema::config_schema(keyword), Output::Json)?)
+}
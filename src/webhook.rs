@@ -0,0 +1,97 @@
+use alloc::sync::Arc;
+use std::path::PathBuf;
+
+use clap::ValueHint;
+use error_stack::{Result, ResultExt};
+use reqwest::header::USER_AGENT;
+use serde::Serialize;
+use serde_json::Value;
+use thiserror::Error;
+use url::Url;
+
+use crate::{
+    secret::Secret,
+    source::{self, ClaimSource, Request},
+    upstream::Webhook,
+};
+
+#[derive(Debug, Copy, Clone, Error)]
+#[error("unable to call the enrichment webhook")]
+pub(crate) struct Error;
+
+#[derive(Debug, Clone, clap::Args)]
+pub(crate) struct WebhookConfig {
+    /// URL the subject, client and requested scopes are posted to during consent, the JSON
+    /// response is exposed to mappings with the `webhook` source
+    #[clap(long, env, value_hint = ValueHint::Url)]
+    pub(crate) webhook_url: Option<Url>,
+
+    /// Token sent as bearer token to the webhook
+    #[clap(long, env, hide_env_values = true, requires = "webhook_url")]
+    pub(crate) webhook_token: Option<Secret>,
+
+    /// File containing the webhook token
+    #[clap(
+        long,
+        env,
+        requires = "webhook_url",
+        conflicts_with = "webhook_token",
+        value_hint = ValueHint::FilePath
+    )]
+    pub(crate) webhook_token_file: Option<PathBuf>,
+}
+
+/// Body posted to the webhook.
+#[derive(Debug, Serialize)]
+struct Payload<'a> {
+    subject: &'a str,
+    client_id: Option<&'a str>,
+    requested_scope: &'a [String],
+}
+
+/// Post the consent request to the webhook and return its response, e.g.
+/// `{"entitlements": ["billing"]}`.
+async fn enrich(webhook: &Webhook, payload: &Payload<'_>) -> Result<Value, Error> {
+    webhook
+        .scoped()
+        .call(Error, |endpoint| async move {
+            let mut request = endpoint.client.post(endpoint.url.clone()).json(payload);
+
+            if let Some(user_agent) = &endpoint.user_agent {
+                request = request.header(USER_AGENT, user_agent);
+            }
+
+            request
+                .send()
+                .await?
+                .error_for_status()?
+                .json::<Value>()
+                .await
+        })
+        .await
+}
+
+/// Response of the enrichment webhook, the document of the `webhook` source.
+#[derive(Debug)]
+pub(crate) struct WebhookSource {
+    pub(crate) webhook: Arc<Webhook>,
+}
+
+#[axum::async_trait]
+impl ClaimSource for WebhookSource {
+    fn name(&self) -> &str {
+        source::WEBHOOK
+    }
+
+    async fn fetch(&self, request: &Request<'_>) -> Result<Value, source::Error> {
+        let payload = Payload {
+            subject: request.subject,
+            client_id: request.client_id,
+            requested_scope: request.requested_scope,
+        };
+
+        enrich(&self.webhook, &payload)
+            .await
+            .change_context(source::Error)
+    }
+}